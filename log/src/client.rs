@@ -0,0 +1,1459 @@
+use std::collections::{BTreeMap, HashMap};
+use std::{fs, path};
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+use std::os::unix::fs::PermissionsExt;
+use sha2::{Sha256, Digest};
+use serde_json::{Map, Value};
+
+use hyper::Client;
+use hyper::{Body, Method, Request, StatusCode};
+use hyper::body::{Bytes, HttpBody};
+
+use tokio::runtime::Runtime;
+
+use utils::utils;
+
+use crate::error::{ErrorKind, LogError, Result};
+use crate::snapshot::{self, Snapshot, ENCRYPTED_ARCHIVE, HASH_TRUNCATE_LENGTH};
+use crate::crypt;
+use crate::signing;
+use crate::merge;
+use crate::codec::{self, Codec};
+use crate::progress::Progress;
+use crate::tls;
+use crate::hooks::{self, Hook};
+use crate::export;
+
+pub const LOG_OPTIONS: [&str; 3] = ["Username", "Server", "tracked_files"];
+
+// config options that can be left out
+pub const OPTIONAL_LOG_OPTIONS: [&str; 12] = [
+  "collection", // collection for directories without a REV file when no '--coll' is given
+  "hooks", // directory the hooks are run from instead of the nearest .log/hooks, or off
+  "inline_limit", // files bigger than this many bytes are stored by reference on the server
+  "codec", // gzip (default), zstd or none
+  "compression_level", // 0-9 for gzip, 1-22 for zstd. Defaults to 1 and 3
+  "encrypt_collections", // comma separated collections whose uploads are encrypted before they leave this machine
+  "encryption_key", // file the encryption key is derived from. A passphrase is asked for if this isn't set
+  "signing_key", // where the key uploads are signed with is kept, ~/.log/signing_key by default
+  "ca_cert", // pem file with the CA (or self-signed certificate) of the server, on top of the system ones
+  "pinned_fingerprint", // sha256 fingerprint of the server certificate. Only that certificate is accepted if set
+  "client_cert", // pem certificate to log in with instead of an API key, for servers with client_ca_path set
+  "client_key" // private key of client_cert
+];
+
+// options a project's .log/config can set over ~/.log/config
+pub const PROJECT_LOG_OPTIONS: [&str; 7] = ["tracked_files", "collection", "Server", "inline_limit", "codec", "compression_level", "hooks"];
+
+// Lists possible endpoints on server
+struct Endpoint{}
+impl<'a> Endpoint {
+  const REGISTER: &'a str = "/register";
+  const UPLOAD: &'a str = "/upload";
+  const CLEANUP: &'a str = "/cleanup";
+  const ID_CHECK: &'a str = "/check";
+  const UPDATE: &'a str = "/update";
+  const CHECKOUT: &'a str = "/checkout";
+  const HISTORY: &'a str = "/history";
+  const VERIFY: &'a str = "/verify";
+  const INTEGRITY: &'a str = "/integrity";
+  const FILE: &'a str = "/file";
+  const SEARCH: &'a str = "/search";
+  const EXPORT: &'a str = "/export";
+  const ROTATE: &'a str = "/rotate";
+  const LOGOUT: &'a str = "/logout";
+  const USERS: &'a str = "/users";
+  const REVOKE: &'a str = "/revoke";
+  const ADD_USER: &'a str = "/add_user";
+  const ADD_SIGNING_KEY: &'a str = "/add_signing_key";
+  const REPLACE_SIGNING_KEY: &'a str = "/replace_signing_key";
+  const REMOVE_SIGNING_KEY: &'a str = "/remove_signing_key";
+  const LIST: &'a str = "/list";
+}
+
+// Contents of ~/.log/config, with the .log/config of the project on top if there is one
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+  pub server: String, // host:port of log_server
+  pub username: String,
+  pub tracked_files: String, // comma separated patterns of files to hash
+  pub inline_limit: Option<u64>,
+  pub codec: Codec, // what upload archives are compressed with
+  pub compression_level: Option<i32>, // None is the codec's default
+  pub encrypt_collections: Vec<String>,
+  pub encryption_key: Option<PathBuf>,
+  pub signing_key: PathBuf, // every upload is signed, the key is made here the first time
+  pub ca_cert: Option<PathBuf>,
+  pub pinned_fingerprint: Option<String>,
+  pub client_cert: Option<PathBuf>,
+  pub client_key: Option<PathBuf>,
+  pub collection: Option<String>, // default collection
+  pub hooks: Option<PathBuf>, // None looks for .log/hooks above the directory
+  pub hooks_off: bool,
+  pub sources: HashMap<String, PathBuf> // config file each option was set in, options left out are defaults
+}
+
+impl ClientConfig {
+
+  pub fn default_path() -> PathBuf {
+    let mut path = home::home_dir().unwrap_or_default();
+    path.push(".log/config");
+    path
+  }
+
+  // .log/config in dir or the closest directory above it, other than ~/.log/config itself
+  pub fn project_path(dir: &Path) -> Option<PathBuf> {
+    let global = Self::default_path();
+    let global = global.canonicalize().unwrap_or(global);
+    dir.ancestors().map(|x| x.join(".log/config")).filter(|x| x.is_file()).find(|x| x.canonicalize().map(|x| x != global).unwrap_or(true))
+  }
+
+  // ~/.log/config with the project config of dir layered over it
+  pub fn load(dir: &Path) -> Result<ClientConfig> {
+    Self::from_files(&Self::default_path(), Self::project_path(dir).as_deref())
+  }
+
+  pub fn from_file(path: &Path) -> Result<ClientConfig> {
+    Self::from_files(path, None)
+  }
+
+  fn from_files(path: &Path, project: Option<&Path>) -> Result<ClientConfig> {
+
+    if !path.exists() {
+      let mut message = format!("Credentials not set up. Cannot log data before setup.\nPlease create a file at {} with the connection details like so:", path.display());
+      for s in LOG_OPTIONS {
+        message.push_str(&format!("\n{} <value>", s));
+      }
+      return Err(LogError::new(ErrorKind::Usage, &message))
+    }
+
+    let all_options: Vec<&str> = LOG_OPTIONS.iter().chain(OPTIONAL_LOG_OPTIONS.iter()).copied().collect();
+    let mut table = HashMap::new();
+    utils::read_file_into_hash(path.to_str().unwrap(), Some(&all_options), &mut table).map_err(|e| LogError::other(&format!("Could not read {}: {}", path.display(), e)))?;
+    let mut sources: HashMap<String, PathBuf> = table.keys().map(|x| (x.to_string(), path.to_path_buf())).collect();
+
+    // project values replace the ones from the home directory
+    if let Some(project) = project {
+      let mut project_table = HashMap::new();
+      utils::read_file_into_hash(project.to_str().unwrap(), Some(&PROJECT_LOG_OPTIONS), &mut project_table).map_err(|e| LogError::other(&format!("Could not read {}: {}", project.display(), e)))?;
+      for (option, value) in project_table {
+        sources.insert(option.to_string(), project.to_path_buf());
+        table.insert(option, value);
+      }
+    }
+    let source = |option: &str| sources.get(option).map(|x| x.display().to_string()).unwrap_or_default();
+
+    let inline_limit = match table.remove("inline_limit") {
+      Some(l) => Some(l.parse::<u64>().map_err(|_| LogError::new(ErrorKind::Usage, &format!("inline_limit in {} should be a number of bytes", source("inline_limit"))))?),
+      None => None
+    };
+
+    let codec = match table.remove("codec") {
+      Some(c) => Codec::from_name(&c).ok_or_else(|| LogError::new(ErrorKind::Usage, &format!("codec in {} should be gzip, zstd or none, not {}", source("codec"), c)))?,
+      None => Codec::default()
+    };
+    let compression_level = match table.remove("compression_level") {
+      Some(l) => {
+        let (min, max, _) = codec.levels();
+        match l.parse::<i32>() {
+          Ok(level) if (min..=max).contains(&level) => Some(level),
+          _ => return Err(LogError::new(ErrorKind::Usage, &format!("compression_level in {} should be between {} and {} for {}", source("compression_level"), min, max, codec.name())))
+        }
+      },
+      None => None
+    };
+
+    let encrypt_collections = match table.remove("encrypt_collections") {
+      Some(c) => c.split(',').map(|x| x.trim().to_string()).filter(|x| !x.is_empty()).collect(),
+      None => Vec::new()
+    };
+    let encryption_key = table.remove("encryption_key").map(PathBuf::from);
+    let signing_key = table.remove("signing_key").map(PathBuf::from).unwrap_or(signing::default_key_path());
+    let ca_cert = table.remove("ca_cert").map(PathBuf::from);
+    let pinned_fingerprint = table.remove("pinned_fingerprint");
+    let client_cert = table.remove("client_cert").map(PathBuf::from);
+    let client_key = table.remove("client_key").map(PathBuf::from);
+    let collection = table.remove("collection").filter(|x| !x.is_empty());
+
+    // relative to the directory the .log directory holding the config is in
+    let hooks_off = table.get("hooks").map(|x| x == "off").unwrap_or(false);
+    let hooks = match (table.remove("hooks"), sources.get("hooks")) {
+      (Some(h), Some(config)) if !hooks_off => Some(config.parent().and_then(|x| x.parent()).unwrap_or(Path::new("")).join(h)),
+      _ => None
+    };
+
+    let mut get = |option: &str| -> Result<String> {
+      table.remove(option).ok_or_else(|| LogError::new(ErrorKind::Usage, &format!("'{}' is missing from {}", option, path.display())))
+    };
+    let server = get("Server")?;
+    let username = get("Username")?;
+    let tracked_files = get("tracked_files")?;
+
+    Ok(ClientConfig {
+      server,
+      username,
+      tracked_files,
+      inline_limit,
+      codec,
+      compression_level,
+      encrypt_collections,
+      encryption_key,
+      signing_key,
+      ca_cert,
+      pinned_fingerprint,
+      client_cert,
+      client_key,
+      collection,
+      hooks,
+      hooks_off,
+      sources
+    })
+  }
+
+  // Every option with its effective value and the file it came from, "default" if it wasn't set
+  pub fn show(&self) -> Vec<(&'static str, String, String)> {
+
+    let path = |p: &Option<PathBuf>| p.as_ref().map(|x| x.display().to_string()).unwrap_or_default();
+    let hooks = match (&self.hooks, self.hooks_off) {
+      (_, true) => "off".to_string(),
+      (Some(h), _) => h.display().to_string(),
+      (None, _) => "nearest .log/hooks".to_string()
+    };
+    let values = vec![
+      ("Username", self.username.to_string()),
+      ("Server", self.server.to_string()),
+      ("tracked_files", self.tracked_files.to_string()),
+      ("collection", self.collection.clone().unwrap_or_default()),
+      ("hooks", hooks),
+      ("inline_limit", self.inline_limit.map(|x| x.to_string()).unwrap_or_default()),
+      ("codec", self.codec.name().to_string()),
+      ("compression_level", self.compression_level.unwrap_or(self.codec.levels().2).to_string()),
+      ("encrypt_collections", self.encrypt_collections.join(",")),
+      ("encryption_key", path(&self.encryption_key)),
+      ("signing_key", self.signing_key.display().to_string()),
+      ("ca_cert", path(&self.ca_cert)),
+      ("pinned_fingerprint", self.pinned_fingerprint.clone().unwrap_or_default()),
+      ("client_cert", path(&self.client_cert)),
+      ("client_key", path(&self.client_key))
+    ];
+
+    values.into_iter().map(|(option, value)| {
+      let source = self.sources.get(option).map(|x| x.display().to_string()).unwrap_or("default".to_string());
+      (option, value, source)
+    }).collect()
+  }
+
+  // hooks directory for dir, None if hooks are turned off or there isn't one
+  pub fn hooks_dir(&self, dir: &Path) -> Option<PathBuf> {
+    match (&self.hooks, self.hooks_off) {
+      (_, true) => None,
+      (Some(h), _) => Some(h.to_path_buf()),
+      (None, _) => hooks::hooks_dir(dir)
+    }
+  }
+
+  pub fn encrypts(&self, collection: &str) -> bool {
+    self.encrypt_collections.iter().any(|x| x == collection)
+  }
+
+  // hostname without port number, used for storing different keys
+  pub fn host(&self) -> &str {
+    match self.server.split_once(':') {
+      Some((host, _)) => host,
+      None => &self.server
+    }
+  }
+}
+
+// Finds the key for a server in a credential file made up of "host : key" lines
+pub fn read_key(key_file: &Path, host: &str) -> Result<Option<String>> {
+
+  if !key_file.exists() {
+    return Ok(None)
+  }
+
+  let mut key_file_hash = HashMap::new();
+  utils::read_file_into_hash(key_file.to_str().unwrap(), None, &mut key_file_hash).map_err(|e| LogError::other(&format!("Could not read {}: {}", key_file.display(), e)))?;
+  Ok(key_file_hash.remove(host))
+}
+
+// Where a directory stands compared to its REV file and the server
+#[derive(Debug, Clone)]
+pub struct Status {
+  pub dir: PathBuf,
+  pub collection: String,
+  pub id: String, // id of the directory as it is now
+  pub record_id: Option<String>, // id in REV, None if the directory was never logged
+  pub parent_id: Option<String>,
+  pub upload_name: Option<String>, // name the REV record is stored under, None if it is not on the server
+  pub needs_update: bool,
+  pub changed_files: Vec<String>
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct UploadOptions {
+  pub collection: Option<String>, // defaults to the collection in REV
+  pub name: Option<String>, // defaults to the directory name
+  pub force: bool, // upload even if the parent is not on the server. May cause a break in the chain of origin
+  pub metadata: Map<String, Value>, // extra information stored with the upload, like sweep axes
+  pub hooks: bool, // run the hooks in .log/hooks, see the hooks module
+  pub command: Option<Vec<String>>, // program and arguments that produced the directory, for `log reproduce`
+  pub parent_id: Option<String>, // parent to use instead of the REV record, "*" starts a new chain. Makes `log reproduce` runs siblings of the original
+  pub fork: bool // the parent is meant to have several children, like the runs of a sweep, so the server doesn't warn about it
+}
+
+#[derive(Debug, Clone)]
+pub struct UploadResult {
+  pub id: String,
+  pub parent_id: String,
+  pub upload_name: String, // can differ from the name asked for if it was already taken
+  pub changed_files: Vec<String>,
+  pub server_message: String,
+  pub missing_inputs: Vec<String>, // files the inputs reference that could not be found, so aren't part of the upload
+  pub branch_label: String, // main, or something like main.1 for the second child of a parent
+  pub fork_warning: Option<String> // set when the parent already had a child from somewhere else
+}
+
+// One upload of a chain checked by `verify`. Problems is empty if everything checked out.
+#[derive(Debug, Clone)]
+pub struct VerifiedEntry {
+  pub id: String,
+  pub upload_name: String,
+  pub signed_by: Option<String>,
+  pub key_fingerprint: Option<String>,
+  pub problems: Vec<String>
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct UpdateOptions {
+  pub dry_run: bool, // only work out what would change
+  pub merge: bool, // merge local changes with the latest upload instead of refusing
+  pub exact: bool, // delete tracked files that aren't in the upload
+  pub remove_untracked: bool // with exact, delete untracked files that aren't in the upload too
+}
+
+// What an update did, or would do with dry_run
+#[derive(Debug, Clone, Default)]
+pub struct UpdatePlan {
+  pub from_id: Option<String>, // id in REV before the update
+  pub target_id: String, // the newest upload, or the one asked for with sync_to
+  pub base_id: Option<String>, // revision both sides started from, only used when there are local changes
+  pub added: Vec<String>,
+  pub changed: Vec<String>,
+  pub deleted: Vec<String>,
+  pub removed: Vec<String>, // not in the target any more but kept, since only --exact deletes
+  pub merged: Vec<String>, // changed on both sides and merged cleanly
+  pub conflicts: Vec<String> // changed on both sides and left with conflict markers, or with the latest version next to it as <file>.latest
+}
+
+enum UpdateAction {
+  Write(Vec<u8>, u32),
+  Delete,
+  WriteLatest(Vec<u8>)
+}
+
+// Hashes of one file according to each place that has a copy of it. None where there is no copy.
+#[derive(Debug, Clone)]
+pub struct FileCheck {
+  pub file: String,
+  pub rev: Option<String>, // what REV says, always None for REV itself
+  pub disk: Option<String>,
+  pub server_archive: Option<String>, // the archive stored on the server
+  pub server_files: Option<String> // the copy in the database, only some files have one
+}
+
+impl FileCheck {
+
+  // Describes what doesn't match, None if everything does.
+  // Encrypted uploads only have REV in plaintext so the rest can't be missing there.
+  pub fn mismatch(&self, encrypted: bool) -> Option<String> {
+
+    let expected = match self.file == "REV" {
+      true => &self.disk,
+      false => &self.rev
+    };
+
+    let bad = expected != &self.disk
+      || ((self.server_archive.is_some() || !encrypted) && &self.server_archive != expected)
+      || (self.server_files.is_some() && self.server_files != self.server_archive);
+
+    if !bad {
+      return None
+    }
+
+    let show = |x: &Option<String>| x.clone().unwrap_or("nothing".to_string());
+    let mut message = match self.file == "REV" {
+      true => format!("{}: disk has {}, server has {}", self.file, show(&self.disk), show(&self.server_archive)),
+      false => format!("{}: REV says {}, disk has {}, server has {}", self.file, show(&self.rev), show(&self.disk), show(&self.server_archive))
+    };
+    if self.server_files.is_some() && self.server_files != self.server_archive {
+      message.push_str(&format!(", database has {}", show(&self.server_files)));
+    }
+    Some(message)
+  }
+}
+
+#[derive(Debug, Clone)]
+pub struct Integrity {
+  pub id: String, // id in REV, which is what the server was asked about
+  pub disk_id: String, // id the directory hashes to now
+  pub encrypted: bool,
+  pub files: Vec<FileCheck>
+}
+
+impl Integrity {
+  pub fn mismatches(&self) -> Vec<String> {
+    let mut mismatches: Vec<String> = Vec::new();
+    if self.id != self.disk_id {
+      mismatches.push(format!("id: REV says {}, disk has {}", self.id, self.disk_id));
+    }
+    mismatches.extend(self.files.iter().filter_map(|x| x.mismatch(self.encrypted)));
+    mismatches
+  }
+}
+
+#[derive(Debug, Clone)]
+pub struct HistoryEntry {
+  pub id: String,
+  pub parent_id: String,
+  pub upload_name: String,
+  pub upload_time: String,
+  pub uploaded_by: String, // empty for uploads from before the server recorded it
+  pub branch_label: String
+}
+
+// An upload in a collection, from `log ls <collection>`
+#[derive(Debug, Clone)]
+pub struct UploadEntry {
+  pub id: String,
+  pub parent_id: String,
+  pub upload_name: String,
+  pub upload_time: String,
+  pub uploaded_by: String,
+  pub branch: String, // id of the first upload in its chain
+  pub branch_label: String // which fork of the chain it is on, see the server's fork_policy
+}
+
+// A file in an upload, from `log ls <id>`
+#[derive(Debug, Clone)]
+pub struct FileEntry {
+  pub path: String,
+  pub size: u64,
+  pub stored: String // inline or reference in the database, archive if only in the stored archive, encrypted if the server can't see it
+}
+
+// A user registered on the server, from `log key list`
+#[derive(Debug, Clone)]
+pub struct ServerUser {
+  pub user: String,
+  pub roles: Vec<String>,
+  pub signing_keys: Vec<String> // fingerprints of the keys their uploads can be signed with
+}
+
+// Everything `log` does with the server, without any of the command line handling.
+// Calls block, each one runs on its own small tokio runtime.
+pub struct LoggerClient {
+  config: ClientConfig,
+  key: String,
+  passphrase: Option<String> // used for encrypted collections when there is no encryption_key file
+}
+
+impl LoggerClient {
+
+  pub fn new(config: ClientConfig, key: &str) -> LoggerClient {
+    LoggerClient { config, key: key.to_string(), passphrase: None }
+  }
+
+  pub fn config(&self) -> &ClientConfig {
+    &self.config
+  }
+
+  pub fn set_passphrase(&mut self, passphrase: &str) {
+    self.passphrase = Some(passphrase.to_string());
+  }
+
+  // true if encrypting or decrypting would have to ask for a passphrase
+  pub fn needs_passphrase(&self) -> bool {
+    self.config.encryption_key.is_none() && self.passphrase.is_none()
+  }
+
+  // what the encryption key is derived from, the key file wins over a passphrase
+  fn encryption_secret(&self) -> Result<Vec<u8>> {
+    if let Some(key_file) = &self.config.encryption_key {
+      let secret = fs::read(key_file).map_err(|e| LogError::new(ErrorKind::Usage, &format!("Could not read encryption key {}: {}", key_file.display(), e)))?;
+      return Ok(secret.trim_ascii().to_vec())
+    }
+
+    match &self.passphrase {
+      Some(passphrase) => Ok(passphrase.as_bytes().to_vec()),
+      None => Err(LogError::new(ErrorKind::Usage, "This upload is encrypted but there is no encryption_key in the config and no passphrase was given"))
+    }
+  }
+
+  // Decompresses a downloaded upload into a plain tar, decrypting it if it is encrypted
+  fn open_archive(&self, data: &[u8]) -> Result<Vec<u8>> {
+
+    let uncompressed = codec::decompress(data)?;
+
+    let mut archive = tar::Archive::new(uncompressed.as_slice());
+    for file in archive.entries()? {
+      let mut file = file?;
+      if file.path()?.to_str() == Some(ENCRYPTED_ARCHIVE) {
+        let mut blob: Vec<u8> = Vec::new();
+        file.read_to_end(&mut blob)?;
+
+        let inner = crypt::decrypt(&self.encryption_secret()?, &blob)?;
+        return Ok(codec::decompress(&inner)?)
+      }
+    }
+
+    Ok(uncompressed)
+  }
+
+  fn unpack(&self, data: &[u8], dir: &Path) -> Result<()> {
+    let uncompressed = self.open_archive(data)?;
+    let mut archive = tar::Archive::new(uncompressed.as_slice());
+
+    let mut progress = Progress::new("Unpacking", Some(uncompressed.len() as u64));
+    for file in archive.entries()? {
+      let mut file = file?;
+      let size = file.size();
+
+      // hooks and config under .log only ever come from this machine
+      if file.path()?.components().any(|x| x.as_os_str() == ".log") {
+        continue;
+      }
+      file.unpack_in(dir)?;
+      progress.add(size, 1);
+    }
+    progress.finish();
+    Ok(())
+  }
+
+  // Creates a new database user with the administrator password and returns its key
+  pub fn register(config: &ClientConfig, admin_password: &str) -> Result<String> {
+    let (headers, _) = send_request(config, Endpoint::REGISTER, admin_password, Vec::new(), Vec::new())?;
+    key_header(&headers)
+  }
+
+  // Replaces this client's key with a new one and switches to it.
+  // The old key stops working as soon as this returns, so the new one has to be saved right away
+  pub fn rotate_key(&mut self) -> Result<String> {
+    let (headers, _) = self.send(Endpoint::ROTATE, Vec::new(), Vec::new())?;
+    self.key = key_header(&headers)?;
+    Ok(self.key.clone())
+  }
+
+  // Gives user a new key with the administrator password, for users registered before they
+  // could rotate their own
+  pub fn rotate_user_key(config: &ClientConfig, admin_password: &str, user: &str) -> Result<String> {
+    let (headers, _) = send_request(config, Endpoint::ROTATE, admin_password, vec![("target", user.to_string())], Vec::new())?;
+    key_header(&headers)
+  }
+
+  // Makes the server forget this client's key
+  pub fn logout(&mut self) -> Result<()> {
+    self.send(Endpoint::LOGOUT, Vec::new(), Vec::new())?;
+    self.key.clear();
+    Ok(())
+  }
+
+  pub fn list_users(config: &ClientConfig, admin_password: &str) -> Result<Vec<ServerUser>> {
+
+    let (_, body) = send_request(config, Endpoint::USERS, admin_password, Vec::new(), Vec::new())?;
+    let results: Value = serde_json::from_slice(&body)?;
+    let results = results.as_array().ok_or_else(|| LogError::new(ErrorKind::ServerRejected, "Server returned something that was not a list of users"))?;
+
+    Ok(results.iter().map(|r| ServerUser {
+      user: crate::table::cell_string(r.get("user")),
+      roles: r.get("roles").and_then(|x| x.as_array()).map(|x| x.iter().filter_map(|x| x.as_str().map(|x| x.to_string())).collect()).unwrap_or_default(),
+      signing_keys: r.get("signing_keys").and_then(|x| x.as_array()).map(|x| x.iter().filter_map(|x| x.as_str().map(|x| x.to_string())).collect()).unwrap_or_default()
+    }).collect())
+  }
+
+  // Adds a user that logs in with a client certificate whose common name is user.
+  // The server keeps their key, so nothing comes back
+  pub fn add_cert_user(config: &ClientConfig, admin_password: &str, user: &str) -> Result<()> {
+    send_request(config, Endpoint::ADD_USER, admin_password, vec![("target", user.to_string())], Vec::new())?;
+    Ok(())
+  }
+
+  // Deletes user on the server so their key stops working. Their uploads are kept
+  pub fn revoke(config: &ClientConfig, admin_password: &str, user: &str) -> Result<()> {
+    send_request(config, Endpoint::REVOKE, admin_password, vec![("target", user.to_string())], Vec::new())?;
+    Ok(())
+  }
+
+  // Adds new_key, hex encoded, or the key of this client as a signing key of the user. The key
+  // here has to be registered to them already, or be the one added if they have none. Returns its fingerprint
+  pub fn add_signing_key(&self, new_key: Option<&str>) -> Result<String> {
+    let key = signing::load_or_create(&self.config.signing_key).map_err(|e| e.context("Could not load signing key"))?;
+    let new_key = new_key.map(|x| x.to_string()).unwrap_or(hex::encode(key.verifying_key().to_bytes()));
+    let (signature, public_key) = signing::sign_claim(&key, &self.config.username, &new_key);
+    let (_, body) = self.send(Endpoint::ADD_SIGNING_KEY, vec![("signature", signature), ("public_key", public_key)], new_key.into_bytes())?;
+    Ok(String::from_utf8_lossy(&body).to_string())
+  }
+
+  // Makes public_key, hex encoded, the only signing key of user. Their other keys are removed
+  pub fn replace_signing_key(config: &ClientConfig, admin_password: &str, user: &str, public_key: &str) -> Result<()> {
+    send_request(config, Endpoint::REPLACE_SIGNING_KEY, admin_password, vec![("target", user.to_string()), ("public_key", public_key.to_string())], Vec::new())?;
+    Ok(())
+  }
+
+  // Removes the signing key with fingerprint from user, or all of their keys. Returns the fingerprints removed
+  pub fn remove_signing_key(config: &ClientConfig, admin_password: &str, user: &str, fingerprint: Option<&str>) -> Result<Vec<String>> {
+    let mut headers = vec![("target", user.to_string())];
+    if let Some(fingerprint) = fingerprint {
+      headers.push(("fingerprint", fingerprint.to_string()));
+    }
+    let (_, body) = send_request(config, Endpoint::REMOVE_SIGNING_KEY, admin_password, headers, Vec::new())?;
+    Ok(serde_json::from_slice(&body)?)
+  }
+
+  // Removes files left on the server's disk after their records were deleted
+  pub fn clean_up(config: &ClientConfig, admin_password: &str) -> Result<String> {
+    let (_, body) = send_request(config, Endpoint::CLEANUP, admin_password, Vec::new(), Vec::new())?;
+    Ok(String::from_utf8_lossy(&body).to_string())
+  }
+
+  fn send(&self, endpoint: &str, headers: Vec<(&str, String)>, body: Vec<u8>) -> Result<(hyper::HeaderMap<hyper::header::HeaderValue>, hyper::body::Bytes)> {
+    send_request(&self.config, endpoint, &self.key, headers, body)
+  }
+
+  // Asks the server for the name stored under this id, None if there is no such upload
+  fn check_id(&self, collection: &str, id: &str) -> Result<Option<String>> {
+    // can just reuse the filehash header for this
+    let (headers, _) = self.send(Endpoint::ID_CHECK, vec![("collection", collection.to_string()), ("filehash", id.to_string())], Vec::new())?;
+    match headers.get("upload_name").map(|x| x.to_str().unwrap_or("DNE")) {
+      Some("DNE") | None => Ok(None),
+      Some(name) => Ok(Some(name.to_string()))
+    }
+  }
+
+  fn snapshot_status(&self, snapshot: &Snapshot) -> Result<Status> {
+
+    let upload_name = match snapshot.record_id() {
+      Some(id) => self.check_id(&snapshot.collection, id).map_err(|e| e.context("Error while checking for previous record"))?,
+      None => None
+    };
+
+    Ok(Status {
+      dir: snapshot.dir.to_owned(),
+      collection: snapshot.collection.to_string(),
+      id: snapshot.id().to_string(),
+      record_id: snapshot.record_id().map(|x| x.to_string()),
+      parent_id: snapshot.parent_id().map(|x| x.to_string()),
+      upload_name,
+      needs_update: snapshot.needs_update(),
+      changed_files: snapshot.changed_files()
+    })
+  }
+
+  // collection given, otherwise the one in dir's REV file, otherwise the configured default
+  pub fn collection_for(&self, dir: &Path, collection: Option<&str>) -> Option<String> {
+    collection.filter(|x| !x.is_empty()).map(|x| x.to_string())
+      .or_else(|| Snapshot::rev_collection(dir))
+      .or_else(|| self.config.collection.clone())
+  }
+
+  // Compares dir against its REV file and checks whether that revision is on the server
+  pub fn status(&self, dir: &Path, collection: Option<&str>) -> Result<Status> {
+    let snapshot = Snapshot::take(dir, &self.config.tracked_files, self.collection_for(dir, collection).as_deref())?;
+    self.snapshot_status(&snapshot)
+  }
+
+  // Uploads dir if anything changed since its REV file was written, then updates REV
+  pub fn upload(&self, dir: &Path, options: &UploadOptions) -> Result<UploadResult> {
+
+    let collection = self.collection_for(dir, options.collection.as_deref());
+    let hooks_dir = self.config.hooks_dir(dir).filter(|_| options.hooks);
+
+    if let Some(hooks_dir) = &hooks_dir {
+      let mut context = Map::new();
+      context.insert("collection".to_string(), Value::from(collection.clone()));
+      context.insert("name".to_string(), Value::from(options.name.clone()));
+      hooks::run(Hook::PreHash, hooks_dir, dir, context)?;
+    }
+
+    let snapshot = Snapshot::take(dir, &self.config.tracked_files, collection.as_deref())?;
+    let status = self.snapshot_status(&snapshot)?;
+
+    // working out which REV goes into the upload
+    let (rev, id, parent_id) = match (&status.record_id, &status.upload_name) {
+
+      // new directory, this starts a new chain
+      (None, _) => (Some(snapshot.new_rev(None)), status.id.to_string(), "*".to_string()),
+
+      // current id becomes the new parent id, unless asked for another one
+      (Some(record_id), Some(_)) => {
+        if !status.needs_update {
+          return Err(LogError::new(ErrorKind::NoChanges, "Record is already up to date, nothing to upload"))
+        }
+        let parent_id = options.parent_id.as_deref().unwrap_or(record_id);
+        (Some(snapshot.new_rev(Some(parent_id).filter(|x| *x != "*"))), status.id.to_string(), parent_id.to_string())
+      },
+
+      // if parent id is * then it's a new branch and there is no problem
+      (Some(record_id), None) => {
+        let parent_id = status.parent_id.clone().unwrap_or("*".to_string());
+        if parent_id != "*" && !options.force {
+          return Err(LogError::new(ErrorKind::ParentMissing, "Previous record not found in database, revert changes or delete REV file to create a new branch\nOr run again with '--force'"))
+        }
+        (None, record_id.to_string(), parent_id)
+      }
+    };
+
+    // a brand new record is all new files
+    let changed_files = match parent_id == "*" && status.upload_name.is_none() {
+      true => {
+        let mut files: Vec<String> = snapshot.current.keys().filter(|x| *x != "id").map(|x| x.to_string()).collect();
+        files.sort();
+        files
+      },
+      false => status.changed_files.to_owned()
+    };
+
+    // what the hooks get told about this upload
+    let mut context = Map::new();
+    context.insert("collection".to_string(), Value::from(snapshot.collection.to_string()));
+    context.insert("name".to_string(), Value::from(options.name.clone()));
+    context.insert("id".to_string(), Value::from(id.to_string()));
+    context.insert("parent_id".to_string(), Value::from(parent_id.to_string()));
+    context.insert("changed_files".to_string(), Value::from(changed_files.clone()));
+    if let Some(hooks_dir) = &hooks_dir {
+      hooks::run(Hook::PreUpload, hooks_dir, &snapshot.dir, context.clone())?;
+    }
+
+    let compression = (self.config.codec, self.config.compression_level);
+    let compressed_dir = snapshot.archive(rev.as_deref(), compression)?;
+
+    // the REV that is inside the archive, which is the one on disk when forcing
+    let rev_bytes = match &rev {
+      Some(rev) => rev.to_owned(),
+      None => fs::read(snapshot.dir.join("REV"))?
+    };
+
+    // the server only gets what it needs to index the upload, the rest is encrypted
+    let (compressed_dir, rev_bytes) = match self.config.encrypts(&snapshot.collection) {
+      true => (snapshot.index_archive(&rev_bytes, compression, &crypt::encrypt(&self.encryption_secret()?, &compressed_dir)?)?, snapshot::index_rev(&rev_bytes)),
+      false => (compressed_dir, rev_bytes)
+    };
+
+    // if no name given will default to directory name
+    let filename = match &options.name {
+      Some(name) if !name.is_empty() => name.to_string(),
+      _ => snapshot.dir.file_name().unwrap().to_str().unwrap().to_string()
+    };
+
+    let mut headers = vec![
+      ("collection", snapshot.collection.to_string()),
+      ("filename", filename.to_string()),
+      ("filehash", snapshot.id().to_string()),
+      ("codec", self.config.codec.name().to_string())
+    ];
+
+    // signing the REV the server gets
+    let key = signing::load_or_create(&self.config.signing_key).map_err(|e| e.context("Could not load signing key"))?;
+    let (signature, public_key) = signing::sign(&key, &rev_bytes, &compressed_dir);
+    headers.push(("signature", signature));
+    headers.push(("public_key", public_key));
+    if !options.metadata.is_empty() {
+      headers.push(("metadata", hex::encode(Value::Object(options.metadata.clone()).to_string())));
+    }
+    if let Some(limit) = self.config.inline_limit {
+      headers.push(("inline_limit", limit.to_string()));
+    }
+    // a json list of the arguments, hex encoded since they can have anything in them
+    if let Some(command) = &options.command {
+      headers.push(("command", hex::encode(Value::from(command.clone()).to_string())));
+    }
+
+    // stored with the upload so it can be told apart from the same user's uploads on other machines.
+    // Hex encoded like the metadata and command, which can have anything in them
+    let mut hostname = [0u8; 256];
+    if let Ok(hostname) = nix::unistd::gethostname(&mut hostname) {
+      headers.push(("hostname", hex::encode(hostname.to_string_lossy().as_bytes())));
+    }
+    headers.push(("client_version", env!("CARGO_PKG_VERSION").to_string()));
+    if options.fork {
+      headers.push(("fork", "intended".to_string()));
+    }
+
+    let (response_headers, body) = self.send(Endpoint::UPLOAD, headers, compressed_dir).map_err(|e| e.context("Error sending data file, cannot update REV"))?;
+
+    if let Some(rev) = rev {
+      snapshot.write_rev(&rev)?;
+    }
+
+    // server changes duplicate names
+    let upload_name = match response_headers.get("upload_name").and_then(|x| x.to_str().ok()) {
+      Some(name) => name.to_string(),
+      None => filename
+    };
+
+    let server_message = String::from_utf8_lossy(&body).to_string();
+    let header = |name: &str| response_headers.get(name).and_then(|x| x.to_str().ok()).map(|x| x.to_string());
+    let branch_label = header("branch_label").unwrap_or("main".to_string());
+    let fork_warning = header("fork_warning");
+
+    if let Some(hooks_dir) = &hooks_dir {
+      context.insert("upload_name".to_string(), Value::from(upload_name.to_string()));
+      context.insert("server_message".to_string(), Value::from(server_message.to_string()));
+      hooks::run(Hook::PostUpload, hooks_dir, &snapshot.dir, context).map_err(|e| e.context(&format!("Uploaded as {}, but", id)))?;
+    }
+
+    Ok(UploadResult {
+      id,
+      parent_id,
+      upload_name,
+      changed_files,
+      server_message,
+      missing_inputs: snapshot.external.iter().filter(|x| x.path.is_none()).map(|x| format!("{} (from {})", x.reference, x.input)).collect(),
+      branch_label,
+      fork_warning
+    })
+  }
+
+  // Downloads one upload by id and unpacks it into dir, which is created if needed.
+  // Returns the name the upload is stored under.
+  pub fn checkout(&self, id: &str, dir: &Path) -> Result<String> {
+
+    let collection = id.split(':').next().unwrap().to_string();
+    let (headers, body) = self.send(Endpoint::CHECKOUT, vec![("collection", collection), ("filehash", id.to_string())], Vec::new())?;
+
+    fs::create_dir_all(dir)?;
+    self.unpack(&body, dir)?;
+
+    Ok(headers.get("upload_name").and_then(|x| x.to_str().ok()).unwrap_or(id).to_string())
+  }
+
+  // Brings dir up to the newest upload of a collection, only touching files that differ.
+  // Local changes stop the update unless they are merged, or it's a dry run.
+  pub fn update(&self, dir: &Path, collection: Option<&str>, options: &UpdateOptions) -> Result<UpdatePlan> {
+    self.sync(dir, collection, None, options)
+  }
+
+  // Same as update but brings dir to one specific upload. dir is created if it doesn't exist.
+  pub fn sync_to(&self, id: &str, dir: &Path, options: &UpdateOptions) -> Result<UpdatePlan> {
+    fs::create_dir_all(dir)?;
+    self.sync(dir, id.split(':').next(), Some(id), options)
+  }
+
+  fn sync(&self, dir: &Path, collection: Option<&str>, target_id: Option<&str>, options: &UpdateOptions) -> Result<UpdatePlan> {
+
+    if options.exact && options.merge {
+      return Err(LogError::new(ErrorKind::Usage, "'--exact' deletes files and '--merge' keeps local changes, they can't be used together"))
+    }
+
+    let snapshot = Snapshot::take(dir, &self.config.tracked_files, self.collection_for(dir, collection).as_deref())?;
+    let collection = snapshot.collection.as_str();
+    let local_changes = snapshot.record_id().is_some() && snapshot.needs_update();
+
+    if local_changes && !options.merge && !options.dry_run {
+      return Err(LogError::new(ErrorKind::Usage, "Current directory has changed. Pulling updates will overwrite your changes. Update stopped\nRun again with '--merge' to merge them, or '--dry-run' to see what would change"))
+    }
+
+    let (_, body) = match target_id {
+      Some(id) => self.send(Endpoint::CHECKOUT, vec![("collection", collection.to_string()), ("filehash", id.to_string())], Vec::new())?,
+      None => self.send(Endpoint::UPDATE, vec![("collection", collection.to_string())], Vec::new())?
+    };
+    let target = archive_files(&self.open_archive(&body)?)?;
+
+    let target_rev = target.get("REV").map(|x| String::from_utf8_lossy(&x.0).to_string()).ok_or_else(|| LogError::new(ErrorKind::ServerRejected, "Upload has no REV file"))?;
+    let target_rev = parse_rev(&target_rev);
+    let target_id = target_rev.get("id").ok_or_else(|| LogError::new(ErrorKind::ServerRejected, "Upload has a REV file without an id"))?.to_string();
+
+    // local changes are compared against the revision both sides started from
+    let base = match local_changes {
+      true => {
+        let base_id = self.common_ancestor(snapshot.record_id().unwrap(), &target_id)?;
+        let (_, body) = self.send(Endpoint::CHECKOUT, vec![("collection", collection.to_string()), ("filehash", base_id.to_string())], Vec::new())?;
+        Some((base_id, archive_files(&self.open_archive(&body)?)?))
+      },
+      false => None
+    };
+
+    // files that can be deleted or merged. Without a base, tracked files still in REV can be deleted too.
+    let mut paths: Vec<String> = target.keys().cloned().collect();
+    match &base {
+      Some((_, base_files)) => paths.extend(base_files.keys().cloned()),
+      None => paths.extend(snapshot.record.keys().filter(|x| *x != "id" && *x != "parent_id").cloned())
+    };
+    paths.sort();
+    paths.dedup();
+    let latest_copies = snapshot::latest_copies(&snapshot.dir);
+    paths.retain(|x| x != "REV" && !snapshot::own_file(x, &latest_copies));
+
+    let tracked = |path: &str| target_rev.contains_key(path) || snapshot.current.contains_key(path);
+
+    let mut plan = UpdatePlan {
+      from_id: snapshot.record_id().map(|x| x.to_string()),
+      target_id: target_id.to_string(),
+      base_id: base.as_ref().map(|x| x.0.to_string()),
+      ..UpdatePlan::default()
+    };
+    let mut actions: Vec<(String, UpdateAction)> = Vec::new();
+
+    for path in paths {
+
+      let local = fs::read(snapshot.dir.join(&path)).ok();
+      let theirs = target.get(&path);
+      let original = match &base {
+        Some((_, base_files)) => base_files.get(&path).map(|x| x.0.to_owned()),
+        None => local.to_owned() // no local changes, so the directory is the starting point
+      };
+
+      if local.as_ref() == theirs.map(|x| &x.0) || theirs.map(|x| &x.0) == original.as_ref() {
+        continue;
+      }
+
+      // only changed upstream
+      if local == original {
+        match (&local, theirs) {
+          (_, None) if options.exact => {
+            plan.deleted.push(path.to_string());
+            actions.push((path, UpdateAction::Delete));
+          },
+          (_, None) => plan.removed.push(path.to_string()),
+          (None, Some((contents, mode))) => {
+            plan.added.push(path.to_string());
+            actions.push((path, UpdateAction::Write(contents.to_owned(), *mode)));
+          },
+          (Some(_), Some((contents, mode))) => {
+            plan.changed.push(path.to_string());
+            actions.push((path, UpdateAction::Write(contents.to_owned(), *mode)));
+          }
+        };
+        continue;
+      }
+
+      // changed on both sides. Tracked text files get merged, anything else keeps the local version
+      let text = |x: &Option<Vec<u8>>| x.as_ref().map(|x| std::str::from_utf8(x).ok().map(|x| x.to_string()));
+      match (tracked(&path), text(&original).unwrap_or(Some(String::new())), text(&local), theirs.map(|x| (std::str::from_utf8(&x.0).ok(), x.1))) {
+        (true, Some(original), Some(Some(local)), Some((Some(theirs), mode))) => {
+          let (merged, conflicts) = merge::merge3(&original, &local, theirs, "local", &target_id);
+          match conflicts {
+            0 => plan.merged.push(path.to_string()),
+            _ => plan.conflicts.push(path.to_string())
+          };
+          actions.push((path, UpdateAction::Write(merged.into_bytes(), mode)));
+        },
+        _ => {
+          plan.conflicts.push(path.to_string());
+          if let Some((contents, _)) = theirs {
+            actions.push((path, UpdateAction::WriteLatest(contents.to_owned())));
+          }
+        }
+      };
+    }
+
+    // tracked files the target doesn't have, plus untracked ones if asked
+    if options.exact {
+      let mut on_disk: Vec<PathBuf> = Vec::new();
+      Snapshot::find_all_files(&snapshot.dir, &mut on_disk)?;
+
+      for f in on_disk {
+        let path = f.strip_prefix(&snapshot.dir).unwrap().to_string_lossy().to_string();
+        if path == "REV" || snapshot::own_file(&path, &latest_copies) || target.contains_key(&path) || actions.iter().any(|(x, _)| *x == path) {
+          continue;
+        }
+        if snapshot.current.contains_key(&path) || options.remove_untracked {
+          plan.deleted.push(path.to_string());
+          actions.push((path, UpdateAction::Delete));
+        }
+      }
+      plan.deleted.sort();
+    }
+
+    if options.dry_run {
+      return Ok(plan)
+    }
+
+    let mut written_copies = Vec::new();
+    for (path, action) in actions {
+      match action {
+        UpdateAction::Write(contents, mode) => {
+          let file_path = local_path(&snapshot.dir, &path, true)?;
+          fs::write(&file_path, contents)?;
+          fs::set_permissions(&file_path, fs::Permissions::from_mode(mode))?;
+        },
+        UpdateAction::Delete => {
+          let file_path = local_path(&snapshot.dir, &path, false)?;
+          fs::remove_file(&file_path)?;
+
+          // directories that only held deleted files go too
+          let mut parent = file_path.parent();
+          while let Some(p) = parent.filter(|x| *x != snapshot.dir) {
+            if fs::remove_dir(p).is_err() {
+              break;
+            }
+            parent = p.parent();
+          }
+        },
+        UpdateAction::WriteLatest(contents) => {
+          fs::write(local_path(&snapshot.dir, &format!("{}.latest", path), true)?, contents)?;
+          written_copies.push(format!("{}.latest", path));
+        }
+      };
+    }
+
+    // so a later --exact update knows which .latest files are log's and not the user's
+    if !written_copies.is_empty() {
+      snapshot::record_latest_copies(&snapshot.dir, &written_copies)?;
+    }
+
+    // REV last, so an interrupted update never claims to be at the new revision
+    snapshot.write_rev(&target.get("REV").unwrap().0)?;
+
+    Ok(plan)
+  }
+
+  // Newest upload that is an ancestor of both ids
+  fn common_ancestor(&self, local_id: &str, latest_id: &str) -> Result<String> {
+
+    let latest_chain: Vec<String> = self.history(latest_id)?.into_iter().map(|x| x.id).collect();
+    if latest_chain.iter().any(|x| x == local_id) {
+      return Ok(local_id.to_string())
+    }
+
+    for entry in self.history(local_id)? {
+      if latest_chain.contains(&entry.id) {
+        return Ok(entry.id)
+      }
+    }
+
+    Err(LogError::new(ErrorKind::ParentMissing, &format!("{} and {} have no common ancestor to merge from", local_id, latest_id)))
+  }
+
+  // One file out of an upload, by its path inside the upload.
+  // This is how files stored by reference are read, but it works for any file.
+  pub fn fetch(&self, id: &str, path: &str) -> Result<Vec<u8>> {
+
+    let collection = id.split(':').next().unwrap().to_string();
+
+    // the server can't read files out of an encrypted upload, so the whole thing comes down
+    if self.config.encrypts(&collection) {
+      let (_, body) = self.send(Endpoint::CHECKOUT, vec![("collection", collection), ("filehash", id.to_string())], Vec::new())?;
+      let uncompressed = self.open_archive(&body)?;
+
+      let mut archive = tar::Archive::new(uncompressed.as_slice());
+      for file in archive.entries()? {
+        let mut file = file?;
+        if file.path()?.to_str() == Some(path) {
+          let mut contents: Vec<u8> = Vec::new();
+          file.read_to_end(&mut contents)?;
+          return Ok(contents)
+        }
+      }
+      return Err(LogError::new(ErrorKind::Usage, &format!("{} is not in {}", path, id)))
+    }
+
+    let (_, body) = self.send(Endpoint::FILE, vec![("collection", collection), ("filehash", id.to_string()), ("path", path.to_string())], Vec::new())?;
+    Ok(body.to_vec())
+  }
+
+  // Names of the collections on the server
+  pub fn collections(&self) -> Result<Vec<String>> {
+    let results = self.list(Vec::new())?;
+    Ok(results.iter().filter_map(|x| x.as_str().map(|x| x.to_string())).collect())
+  }
+
+  // Every upload in a collection, oldest first
+  // Uploads in a collection, only the ones made by user if it is given
+  pub fn uploads(&self, collection: &str, user: Option<&str>) -> Result<Vec<UploadEntry>> {
+
+    let mut headers = vec![("collection", collection.to_string())];
+    if let Some(user) = user {
+      headers.push(("uploaded_by", user.to_string()));
+    }
+    let results = self.list(headers)?;
+    let field = |r: &Value, name: &str| crate::table::cell_string(r.get(name));
+
+    Ok(results.iter().map(|r| UploadEntry {
+      id: field(r, "id"),
+      parent_id: field(r, "parent_id"),
+      upload_name: field(r, "upload_name"),
+      upload_time: field(r, "upload_time"),
+      uploaded_by: field(r, "uploaded_by"),
+      branch: field(r, "branch"),
+      branch_label: field(r, "branch_label")
+    }).collect())
+  }
+
+  // Files in an upload. Encrypted uploads are downloaded and listed here since the server can't see inside of them
+  pub fn files(&self, id: &str) -> Result<Vec<FileEntry>> {
+
+    let collection = id.split(':').next().unwrap().to_string();
+
+    if self.config.encrypts(&collection) {
+      let (_, body) = self.send(Endpoint::CHECKOUT, vec![("collection", collection), ("filehash", id.to_string())], Vec::new())?;
+      let uncompressed = self.open_archive(&body)?;
+
+      let mut files: Vec<FileEntry> = Vec::new();
+      let mut archive = tar::Archive::new(uncompressed.as_slice());
+      for file in archive.entries()? {
+        let file = file?;
+        if file.header().entry_type().is_file() {
+          files.push(FileEntry { path: file.path()?.to_str().unwrap_or("").to_string(), size: file.size(), stored: "encrypted".to_string() });
+        }
+      }
+      return Ok(files)
+    }
+
+    let results = self.list(vec![("filehash", id.to_string())])?;
+    Ok(results.iter().map(|r| FileEntry {
+      path: crate::table::cell_string(r.get("path")),
+      size: r.get("size").and_then(|x| x.as_u64()).unwrap_or(0),
+      stored: crate::table::cell_string(r.get("stored"))
+    }).collect())
+  }
+
+  fn list(&self, headers: Vec<(&str, String)>) -> Result<Vec<Value>> {
+    let (_, body) = self.send(Endpoint::LIST, headers, Vec::new())?;
+    match serde_json::from_slice(&body)? {
+      Value::Array(results) => Ok(results),
+      _ => Err(LogError::new(ErrorKind::ServerRejected, "Server returned something that was not a list"))
+    }
+  }
+
+  // The chain of parents of an upload, starting with the upload itself
+  pub fn history(&self, id: &str) -> Result<Vec<HistoryEntry>> {
+
+    let collection = id.split(':').next().unwrap().to_string();
+    let (_, body) = self.send(Endpoint::HISTORY, vec![("collection", collection), ("filehash", id.to_string())], Vec::new())?;
+
+    let results: Value = serde_json::from_slice(&body)?;
+    let results = results.as_array().ok_or_else(|| LogError::new(ErrorKind::ServerRejected, "Server returned something that was not a list of uploads"))?;
+
+    let field = |r: &Value, name: &str| crate::table::cell_string(r.get(name));
+
+    Ok(results.iter().map(|r| HistoryEntry {
+      id: field(r, "id"),
+      parent_id: field(r, "parent_id"),
+      upload_name: field(r, "upload_name"),
+      upload_time: field(r, "upload_time"),
+      uploaded_by: field(r, "uploaded_by"),
+      branch_label: field(r, "branch_label")
+    }).collect())
+  }
+
+  // Compares the files in dir with its REV file and with what the server has stored for that REV
+  pub fn check_integrity(&self, dir: &Path) -> Result<Integrity> {
+
+    let snapshot = Snapshot::take(dir, &self.config.tracked_files, None)?;
+    let id = snapshot.record_id().ok_or_else(|| LogError::new(ErrorKind::Usage, "No REV file in this directory, nothing to check against"))?.to_string();
+
+    let (_, body) = self.send(Endpoint::INTEGRITY, vec![("collection", snapshot.collection.to_string()), ("filehash", id.to_string())], Vec::new())?;
+    let server: Value = serde_json::from_slice(&body)?;
+
+    // the client only keeps the start of each hash
+    let server_hash = |section: &str, file: &str| server.get(section).and_then(|x| x.get(file)).and_then(|x| x.as_str()).map(|x| x.chars().take(HASH_TRUNCATE_LENGTH).collect::<String>());
+
+    let mut names: Vec<&String> = snapshot.record.keys().chain(snapshot.current.keys())
+      .filter(|x| *x != "id" && *x != "parent_id")
+      .collect();
+    names.sort();
+    names.dedup();
+
+    let mut files: Vec<FileCheck> = names.into_iter().map(|name| FileCheck {
+      file: name.to_string(),
+      rev: snapshot.record.get(name).cloned(),
+      disk: snapshot.current.get(name).cloned(),
+      server_archive: server_hash("archive", name),
+      server_files: server_hash("files", name)
+    }).collect();
+
+    // REV itself can be left half written by an interrupted update. Encrypted uploads only show the server its ids
+    let encrypted = server.get("encrypted").and_then(|x| x.as_bool()).unwrap_or(false);
+    let rev_disk = fs::read(snapshot.dir.join("REV")).ok()
+      .map(|x| if encrypted { snapshot::index_rev(&x) } else { x })
+      .map(|x| hex::encode(Sha256::digest(x))[..HASH_TRUNCATE_LENGTH].to_string());
+    files.push(FileCheck {
+      file: "REV".to_string(),
+      rev: None,
+      disk: rev_disk,
+      server_archive: server_hash("archive", "REV"),
+      server_files: server_hash("files", "REV")
+    });
+
+    Ok(Integrity {
+      id,
+      disk_id: snapshot.id().to_string(),
+      encrypted,
+      files
+    })
+  }
+
+  // Checks the signature of an upload and all of its parents, and that they link up
+  pub fn verify(&self, id: &str) -> Result<Vec<VerifiedEntry>> {
+
+    let collection = id.split(':').next().unwrap().to_string();
+    let (_, body) = self.send(Endpoint::VERIFY, vec![("collection", collection), ("filehash", id.to_string())], Vec::new())?;
+
+    let records: Value = serde_json::from_slice(&body)?;
+    let records = records.as_array().ok_or_else(|| LogError::new(ErrorKind::ServerRejected, "Server returned something that was not a list of uploads"))?;
+
+    let field = |r: &Value, name: &str| r.get(name).and_then(|x| x.as_str()).map(|x| x.to_string());
+
+    let mut entries: Vec<VerifiedEntry> = Vec::new();
+    for (i, r) in records.iter().enumerate() {
+
+      let id = field(r, "id").unwrap_or_default();
+      let parent_id = field(r, "parent_id").unwrap_or_default();
+      let rev = field(r, "rev").unwrap_or_default();
+      let mut problems: Vec<String> = Vec::new();
+
+      // REV has to agree with the record it is stored in
+      let rev_hash = parse_rev(&rev);
+      if rev_hash.get("id") != Some(&id) || rev_hash.get("parent_id") != Some(&parent_id) {
+        problems.push("REV does not match the record".to_string());
+      }
+
+      // the last one in the chain should be the root
+      if i == records.len() - 1 && parent_id != "*" {
+        problems.push(format!("parent {} is missing, the chain is broken", parent_id));
+      }
+
+      let (signed_by, key_fingerprint) = match r.get("provenance") {
+        None | Some(Value::Null) => {
+          problems.push("not signed".to_string());
+          (None, None)
+        },
+        Some(p) => {
+          let public_key = field(p, "public_key").unwrap_or_default();
+          let fingerprint = signing::fingerprint(&hex::decode(&public_key).unwrap_or_default());
+          let message = signing::signed_message(rev.as_bytes(), &field(p, "archive_hash").unwrap_or_default());
+
+          if !signing::verify(&public_key, &field(p, "signature").unwrap_or_default(), &message) {
+            problems.push("signature does not match".to_string());
+          }
+          if field(p, "key_fingerprint").as_deref() != Some(&fingerprint) {
+            problems.push("stored fingerprint is not the one of the public key".to_string());
+          }
+          let registered: Vec<String> = r.get("registered_fingerprints").and_then(|x| x.as_array()).map(|x| x.iter().filter_map(|x| x.as_str().map(|x| x.to_string())).collect()).unwrap_or_default();
+          if registered.is_empty() {
+            problems.push("signer had no registered key".to_string());
+          } else if !registered.contains(&fingerprint) {
+            problems.push(format!("signer's registered keys were {}", registered.join(", ")));
+          }
+
+          (field(p, "signed_by"), Some(fingerprint))
+        }
+      };
+
+      entries.push(VerifiedEntry {
+        id,
+        upload_name: field(r, "upload_name").unwrap_or_default(),
+        signed_by,
+        key_fingerprint,
+        problems
+      });
+    }
+
+    Ok(entries)
+  }
+
+  // Runs a search like "watch.timestep < 0.002 and keywords has sim" against a collection.
+  // Every result has id, upload_name and upload_time plus the comma separated fields asked for.
+  pub fn query(&self, collection: &str, query: &str, fields: &str) -> Result<Vec<Value>> {
+
+    let headers = vec![("collection", collection.to_string()), ("fields", fields.to_string())];
+    let (_, body) = self.send(Endpoint::SEARCH, headers, query.as_bytes().to_vec())?;
+
+    match serde_json::from_slice(&body)? {
+      Value::Array(results) => Ok(results),
+      _ => Err(LogError::new(ErrorKind::ServerRejected, "Server returned something that was not a list of results"))
+    }
+  }
+
+  // Watch values and thermo data as rows, one per upload or one per thermo step
+  pub fn export_rows(&self, collection: &str, query: &str, fields: &str) -> Result<Vec<Map<String, Value>>> {
+
+    let headers = vec![("collection", collection.to_string()), ("fields", fields.to_string())];
+    let (_, body) = self.send(Endpoint::EXPORT, headers, query.as_bytes().to_vec())?;
+
+    let mut rows: Vec<Map<String, Value>> = Vec::new();
+    for line in std::str::from_utf8(&body)?.lines().filter(|x| !x.is_empty()) {
+      match serde_json::from_str(line)? {
+        Value::Object(row) => rows.push(row),
+        _ => return Err(LogError::new(ErrorKind::ServerRejected, "Server sent a row that was not a json object"))
+      };
+    }
+
+    Ok(rows)
+  }
+
+  // Same as export_rows but writes them out as csv, parquet or jsonl. Returns the number of rows.
+  pub fn export(&self, collection: &str, query: &str, fields: &str, format: &str, output: &path::Path) -> Result<usize> {
+
+    if !export::EXPORT_FORMATS.contains(&format) {
+      return Err(LogError::new(ErrorKind::Usage, &format!("Unknown export format '{}'. Valid formats are {:?}", format, export::EXPORT_FORMATS)))
+    }
+
+    let rows = self.export_rows(collection, query, fields)?;
+    if rows.is_empty() {
+      return Err(LogError::other("Nothing matched, no file written"))
+    }
+
+    export::write_rows(&rows, format, output).map_err(|e| LogError::other(&format!("Could not write {}: {}", output.display(), e)))?;
+
+    Ok(rows.len())
+  }
+}
+
+// Every regular file in a tar with its contents and mode
+fn archive_files(uncompressed: &[u8]) -> Result<BTreeMap<String, (Vec<u8>, u32)>> {
+
+  let mut files = BTreeMap::new();
+  let mut archive = tar::Archive::new(uncompressed);
+  for file in archive.entries()? {
+    let mut file = file?;
+    if !file.header().entry_type().is_file() {
+      continue;
+    }
+
+    let name = file.path()?.to_string_lossy().to_string();
+    if !plain_relative(&name) {
+      return Err(LogError::new(ErrorKind::ServerRejected, &format!("Upload contains {}, which points outside of its directory", name)))
+    }
+
+    // no setuid or setgid bits from somebody else's upload
+    let mode = file.header().mode().unwrap_or(0o644) & 0o777;
+    let mut contents: Vec<u8> = Vec::new();
+    file.read_to_end(&mut contents)?;
+    files.insert(name, (contents, mode));
+  }
+
+  Ok(files)
+}
+
+// only names like "a/b.txt", no "..", "." or leading "/"
+fn plain_relative(path: &str) -> bool {
+  let path = Path::new(path);
+  path.components().next().is_some() && path.components().all(|x| matches!(x, Component::Normal(_)))
+}
+
+// Where a path from an archive goes inside dir. Symlinked directories on the way are refused so
+// nothing is written or deleted outside of dir, and missing ones are created if create_parents is set
+fn local_path(dir: &Path, path: &str, create_parents: bool) -> Result<PathBuf> {
+
+  let outside = || LogError::new(ErrorKind::ServerRejected, &format!("Not touching {}, it would be outside of {}", path, dir.display()));
+  if !plain_relative(path) {
+    return Err(outside())
+  }
+
+  let file_path = dir.join(path);
+  let parent = file_path.parent().unwrap();
+  let mut current = dir.to_path_buf();
+  for part in parent.strip_prefix(dir).unwrap().components() {
+    current.push(part);
+    match fs::symlink_metadata(&current) {
+      Ok(m) if m.file_type().is_symlink() => return Err(outside()),
+      Ok(_) => {},
+      Err(_) if create_parents => fs::create_dir(&current)?,
+      Err(_) => return Ok(file_path) // nothing there to delete
+    }
+  }
+
+  let dir = dir.canonicalize()?;
+  if !parent.canonicalize()?.starts_with(&dir) {
+    return Err(outside())
+  }
+  if create_parents && fs::symlink_metadata(&file_path).map(|x| x.file_type().is_symlink()).unwrap_or(false) {
+    return Err(outside())
+  }
+  Ok(file_path)
+}
+
+fn parse_rev(rev: &str) -> HashMap<String, String> {
+  rev.lines().filter_map(|l| l.split_once(" : ")).map(|(k, v)| (k.trim().to_string(), v.trim().to_string())).collect()
+}
+
+// request bodies are handed to hyper in pieces this big, so the upload progress can be shown
+const SEND_CHUNK_SIZE: usize = 256 * 1024;
+
+fn key_header(headers: &hyper::HeaderMap<hyper::header::HeaderValue>) -> Result<String> {
+  let key = headers.get("key").ok_or_else(|| LogError::new(ErrorKind::ServerRejected, "Server did not send back a key"))?;
+  Ok(key.to_str().map_err(|_| LogError::new(ErrorKind::ServerRejected, "Server sent back an unreadable key"))?.to_string())
+}
+
+fn send_request(config: &ClientConfig, endpoint: &str, password: &str, headers: Vec<(&str, String)>, body: Vec<u8>) -> Result<(hyper::HeaderMap<hyper::header::HeaderValue>, hyper::body::Bytes)> {
+
+  let server = format!("https://{}{}", config.server, endpoint);
+
+  let mut req = Request::builder()
+    .method(Method::POST)
+    .uri(server)
+    .header("password", password)
+    .header("username", &config.username)
+    .header(hyper::header::CONTENT_LENGTH, body.len());
+
+  for (k, v) in headers {
+    req = req.header(k, v);
+  }
+
+  let (mut sender, request_body) = Body::channel();
+  let req = req.body(request_body).map_err(|e| LogError::new(ErrorKind::Usage, &format!("Could not build request: {}", e)))?;
+
+  let (tls, refused) = tls::client_tls(config)?;
+  let https = hyper_rustls::HttpsConnectorBuilder::new().with_tls_config(tls).https_only().enable_http1().build();
+  let client = Client::builder().build::<_, hyper::Body>(https);
+
+  // No need to make entire program asynchronous so just defining runtime here to keep it isolated.
+  // Runtime creation takes only 1 or 2 milliseconds
+  let rt = Runtime::new()?;
+  rt.block_on(async move {
+
+    let body = Bytes::from(body);
+    let send_body = async move {
+      let mut progress = Progress::new("Uploading", Some(body.len() as u64));
+      for start in (0..body.len()).step_by(SEND_CHUNK_SIZE) {
+        let chunk = body.slice(start..body.len().min(start + SEND_CHUNK_SIZE));
+        let size = chunk.len() as u64;
+        // the server hung up, the request itself reports why
+        if sender.send_data(chunk).await.is_err() {
+          return
+        }
+        progress.add(size, 0);
+      }
+      progress.finish();
+    };
+
+    let (resp, _) = tokio::join!(client.request(req), send_body);
+
+    // rustls only says the certificate was bad, not what was wrong with it
+    let resp = match (resp, refused.lock().unwrap().take()) {
+      (Err(_), Some(problem)) => return Err(LogError::new(ErrorKind::Network, &problem)),
+      (resp, _) => resp?
+    };
+    let status = resp.status();
+
+    let headers = resp.headers().to_owned();
+    let total = headers.get(hyper::header::CONTENT_LENGTH).and_then(|x| x.to_str().ok()).and_then(|x| x.parse::<u64>().ok());
+
+    let mut progress = Progress::new("Downloading", total);
+    let mut resp_body = resp.into_body();
+    let mut body_bytes: Vec<u8> = Vec::with_capacity(total.unwrap_or(0) as usize);
+    while let Some(chunk) = resp_body.data().await {
+      let chunk = chunk.map_err(|e| LogError::new(ErrorKind::Network, &format!("Connection to the server broke: {}", e)))?;
+      body_bytes.extend_from_slice(&chunk);
+      progress.add(chunk.len() as u64, 0);
+    }
+    progress.finish();
+    let body_bytes = Bytes::from(body_bytes);
+
+    if status != StatusCode::OK {
+      let body_string = String::from_utf8_lossy(&body_bytes);
+
+      // server sends back the database error when the key is wrong
+      let kind = match body_string.contains("Authentication failed") {
+        true => ErrorKind::Auth,
+        false => ErrorKind::ServerRejected
+      };
+      return Err(LogError::new(kind, &format!("Error: {}", body_string)))
+    }
+
+    Ok((headers, body_bytes))
+  })
+}
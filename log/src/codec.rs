@@ -0,0 +1,120 @@
+use std::io::{self, Read, Write};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+use crate::progress::Progress;
+
+// How upload archives are compressed, set with `codec` and `compression_level` in the config.
+// gzip stays the default so older servers keep working. Downloads are decoded by looking at
+// the first bytes, so uploads made with any codec can be read back whatever the config says now.
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+// data goes through the encoders and decoders in pieces this big so progress can be shown
+const CHUNK_SIZE: usize = 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Codec {
+  #[default]
+  Gzip,
+  Zstd,
+  None
+}
+
+impl Codec {
+
+  pub fn from_name(name: &str) -> Option<Codec> {
+    match name {
+      "gzip" | "gz" => Some(Codec::Gzip),
+      "zstd" | "zst" => Some(Codec::Zstd),
+      "none" => Some(Codec::None),
+      _ => None
+    }
+  }
+
+  // what gets sent in the codec header
+  pub fn name(&self) -> &'static str {
+    match self {
+      Codec::Gzip => "gzip",
+      Codec::Zstd => "zstd",
+      Codec::None => "none"
+    }
+  }
+
+  // levels the codec accepts. gzip defaults to its fastest level, which is what was always used
+  pub fn levels(&self) -> (i32, i32, i32) {
+    match self {
+      Codec::Gzip => (0, 9, 1),
+      Codec::Zstd => (1, 22, 3),
+      Codec::None => (0, 0, 0)
+    }
+  }
+
+  pub fn detect(data: &[u8]) -> Codec {
+    if data.starts_with(GZIP_MAGIC) {
+      Codec::Gzip
+    } else if data.starts_with(ZSTD_MAGIC) {
+      Codec::Zstd
+    } else {
+      Codec::None
+    }
+  }
+}
+
+fn write_chunks<W: Write>(encoder: &mut W, data: &[u8]) -> io::Result<()> {
+  let mut progress = Progress::new("Compressing", Some(data.len() as u64));
+  for chunk in data.chunks(CHUNK_SIZE) {
+    encoder.write_all(chunk)?;
+    progress.add(chunk.len() as u64, 0);
+  }
+  progress.finish();
+  Ok(())
+}
+
+fn read_chunks<R: Read>(mut decoder: R, compressed_size: usize) -> io::Result<Vec<u8>> {
+
+  // total is only known for the compressed side, so this counts uncompressed bytes without an ETA
+  let mut progress = Progress::new("Decompressing", None);
+  let mut uncompressed: Vec<u8> = Vec::with_capacity(compressed_size);
+  let mut chunk = vec![0u8; CHUNK_SIZE];
+  loop {
+    let read = decoder.read(&mut chunk)?;
+    if read == 0 {
+      break;
+    }
+    uncompressed.extend_from_slice(&chunk[..read]);
+    progress.add(read as u64, 0);
+  }
+  progress.finish();
+  Ok(uncompressed)
+}
+
+pub fn compress(codec: Codec, level: Option<i32>, data: &[u8]) -> io::Result<Vec<u8>> {
+
+  let level = level.unwrap_or(codec.levels().2);
+
+  match codec {
+    Codec::Gzip => {
+      let mut encoder = GzEncoder::new(Vec::new(), Compression::new(level as u32));
+      write_chunks(&mut encoder, data)?;
+      encoder.finish()
+    },
+    Codec::Zstd => {
+      let mut encoder = zstd::Encoder::new(Vec::new(), level)?;
+      write_chunks(&mut encoder, data)?;
+      encoder.finish()
+    },
+    Codec::None => Ok(data.to_vec())
+  }
+}
+
+// Gives back the plain tar whichever codec it was compressed with
+pub fn decompress(data: &[u8]) -> io::Result<Vec<u8>> {
+  match Codec::detect(data) {
+    Codec::Gzip => read_chunks(GzDecoder::new(data), data.len()),
+    Codec::Zstd => read_chunks(zstd::Decoder::new(data)?, data.len()),
+    Codec::None => Ok(data.to_vec())
+  }
+}
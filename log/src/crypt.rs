@@ -0,0 +1,53 @@
+use argon2::Argon2;
+use chacha20poly1305::{ChaCha20Poly1305, Key, KeyInit, Nonce};
+use chacha20poly1305::aead::{Aead, AeadCore, OsRng, rand_core::RngCore};
+
+use crate::error::{ErrorKind, LogError, Result};
+
+// Encryption of uploads for collections listed in encrypt_collections.
+// The key is derived from a passphrase or the contents of a key file with argon2, so the
+// server never sees anything it could decrypt with.
+// Blob layout: MAGIC | salt | nonce | ciphertext
+
+const MAGIC: &[u8] = b"RLENC1";
+const SALT_LENGTH: usize = 16;
+const NONCE_LENGTH: usize = 12;
+
+fn derive_key(secret: &[u8], salt: &[u8]) -> Result<Key> {
+  let mut key = Key::default();
+  Argon2::default().hash_password_into(secret, salt, &mut key).map_err(|e| LogError::other(&format!("Could not derive encryption key: {}", e)))?;
+  Ok(key)
+}
+
+pub fn encrypt(secret: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+
+  let mut salt = [0u8; SALT_LENGTH];
+  OsRng.fill_bytes(&mut salt);
+  let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+
+  let cipher = ChaCha20Poly1305::new(&derive_key(secret, &salt)?);
+  let ciphertext = cipher.encrypt(&nonce, data).map_err(|_| LogError::other("Could not encrypt archive"))?;
+
+  let mut blob = Vec::with_capacity(MAGIC.len() + SALT_LENGTH + NONCE_LENGTH + ciphertext.len());
+  blob.extend_from_slice(MAGIC);
+  blob.extend_from_slice(&salt);
+  blob.extend_from_slice(&nonce);
+  blob.extend_from_slice(&ciphertext);
+  Ok(blob)
+}
+
+pub fn decrypt(secret: &[u8], blob: &[u8]) -> Result<Vec<u8>> {
+
+  let header_length = MAGIC.len() + SALT_LENGTH + NONCE_LENGTH;
+  if blob.len() < header_length || !blob.starts_with(MAGIC) {
+    return Err(LogError::other("Encrypted archive is damaged or in an unknown format"))
+  }
+
+  let salt = &blob[MAGIC.len()..MAGIC.len() + SALT_LENGTH];
+  let mut nonce = Nonce::default();
+  nonce.copy_from_slice(&blob[MAGIC.len() + SALT_LENGTH..header_length]);
+
+  let cipher = ChaCha20Poly1305::new(&derive_key(secret, salt)?);
+  cipher.decrypt(&nonce, &blob[header_length..])
+    .map_err(|_| LogError::new(ErrorKind::Usage, "Could not decrypt archive, wrong passphrase or key file"))
+}
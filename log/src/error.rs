@@ -0,0 +1,126 @@
+use std::fmt;
+
+// Every failure from the client library is a LogError, so callers can tell
+// a missing parent apart from a dropped connection without parsing messages.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorKind {
+  Other,
+  Usage,
+  NoChanges,
+  ParentMissing,
+  Auth,
+  Network,
+  ServerRejected,
+  Unverified,
+  HookFailed,
+  Mismatch
+}
+
+impl ErrorKind {
+
+  pub fn exit_code(&self) -> i32 {
+    match self {
+      ErrorKind::Other => 1,
+      ErrorKind::Usage => 2,
+      ErrorKind::NoChanges => 3,
+      ErrorKind::ParentMissing => 4,
+      ErrorKind::Auth => 5,
+      ErrorKind::Network => 6,
+      ErrorKind::ServerRejected => 7,
+      ErrorKind::Unverified => 8,
+      ErrorKind::HookFailed => 9,
+      ErrorKind::Mismatch => 10
+    }
+  }
+
+  pub fn name(&self) -> &'static str {
+    match self {
+      ErrorKind::Other => "other",
+      ErrorKind::Usage => "usage",
+      ErrorKind::NoChanges => "no_changes",
+      ErrorKind::ParentMissing => "parent_missing",
+      ErrorKind::Auth => "auth",
+      ErrorKind::Network => "network",
+      ErrorKind::ServerRejected => "server_rejected",
+      ErrorKind::Unverified => "unverified",
+      ErrorKind::HookFailed => "hook_failed",
+      ErrorKind::Mismatch => "mismatch"
+    }
+  }
+}
+
+#[derive(Debug)]
+pub struct LogError {
+  pub kind: ErrorKind,
+  pub message: String
+}
+
+pub type Result<T> = std::result::Result<T, LogError>;
+
+impl LogError {
+  pub fn new(kind: ErrorKind, message: &str) -> LogError {
+    LogError { kind, message: message.to_string() }
+  }
+
+  pub fn other(message: &str) -> LogError {
+    LogError::new(ErrorKind::Other, message)
+  }
+
+  // Same kind, with some context in front of the message
+  pub fn context(self, context: &str) -> LogError {
+    LogError { kind: self.kind, message: format!("{}: {}", context, self.message) }
+  }
+}
+
+impl fmt::Display for LogError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{}", self.message)
+  }
+}
+
+impl std::error::Error for LogError {}
+
+impl From<std::io::Error> for LogError {
+  fn from(err: std::io::Error) -> LogError {
+    LogError::other(&err.to_string())
+  }
+}
+
+impl From<serde_json::Error> for LogError {
+  fn from(err: serde_json::Error) -> LogError {
+    LogError::other(&format!("Could not read json: {}", err))
+  }
+}
+
+impl From<std::str::Utf8Error> for LogError {
+  fn from(err: std::str::Utf8Error) -> LogError {
+    LogError::other(&err.to_string())
+  }
+}
+
+impl From<hyper::Error> for LogError {
+  fn from(err: hyper::Error) -> LogError {
+    LogError::new(ErrorKind::Network, &format!("Could not reach the server: {}", err))
+  }
+}
+
+impl From<&str> for LogError {
+  fn from(message: &str) -> LogError {
+    LogError::other(message)
+  }
+}
+
+impl From<String> for LogError {
+  fn from(message: String) -> LogError {
+    LogError::other(&message)
+  }
+}
+
+// Errors that didn't come from us are just "other"
+pub fn error_kind(err: &(dyn std::error::Error + 'static)) -> ErrorKind {
+  match err.downcast_ref::<LogError>() {
+    Some(e) => e.kind,
+    None => ErrorKind::Other
+  }
+}
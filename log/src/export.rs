@@ -0,0 +1,110 @@
+use std::{fs, io::Write, path::Path, sync::Arc};
+use serde_json::{Map, Value};
+
+use arrow_array::{ArrayRef, Float64Array, RecordBatch, StringArray};
+use arrow_schema::{DataType, Field, Schema};
+use parquet::arrow::ArrowWriter;
+
+use crate::table;
+
+// Writes the json rows returned by the server's /export endpoint into a file
+// that pandas or polars can load directly.
+
+pub const EXPORT_FORMATS: [&str; 3] = ["csv", "parquet", "jsonl"];
+
+
+// Columns in the order they first show up. Every row has the same base columns so this
+// keeps id, upload_name and upload_time at the front.
+fn column_names(rows: &[Map<String, Value>]) -> Vec<String> {
+  let mut columns: Vec<String> = Vec::new();
+  for row in rows {
+    for k in row.keys() {
+      if !columns.contains(k) {
+        columns.push(k.to_string());
+      }
+    }
+  }
+  columns
+}
+
+pub fn write_rows(rows: &[Map<String, Value>], format: &str, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+  match format {
+    "csv" => write_csv(rows, path),
+    "parquet" => write_parquet(rows, path),
+    "jsonl" => write_jsonl(rows, path),
+    _ => Err(format!("Unknown export format '{}'. Valid formats are {:?}", format, EXPORT_FORMATS).into())
+  }
+}
+
+fn write_jsonl(rows: &[Map<String, Value>], path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+  let mut file = fs::File::create(path)?;
+  for row in rows {
+    file.write_all(Value::Object(row.clone()).to_string().as_bytes())?;
+    file.write_all(b"\n")?;
+  }
+  file.flush()?;
+  Ok(())
+}
+
+fn csv_escape(cell: &str) -> String {
+  if cell.contains(',') || cell.contains('"') || cell.contains('\n') {
+    format!("\"{}\"", cell.replace('"', "\"\""))
+  } else {
+    cell.to_string()
+  }
+}
+
+fn write_csv(rows: &[Map<String, Value>], path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+
+  let columns = column_names(rows);
+  let mut file = fs::File::create(path)?;
+
+  let header: Vec<String> = columns.iter().map(|x| csv_escape(x)).collect();
+  writeln!(file, "{}", header.join(","))?;
+
+  for row in rows {
+    let cells: Vec<String> = columns.iter().map(|c| {
+      match row.get(c) {
+        None | Some(Value::Null) => String::new(),
+        v => csv_escape(&table::cell_string(v))
+      }
+    }).collect();
+    writeln!(file, "{}", cells.join(","))?;
+  }
+
+  file.flush()?;
+  Ok(())
+}
+
+fn write_parquet(rows: &[Map<String, Value>], path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+
+  let columns = column_names(rows);
+  let mut fields: Vec<Field> = Vec::new();
+  let mut arrays: Vec<ArrayRef> = Vec::new();
+
+  // a column is numeric if every value in it is a number, anything else is stored as text
+  for c in &columns {
+    let values: Vec<Option<&Value>> = rows.iter().map(|r| r.get(c).filter(|v| !v.is_null())).collect();
+    let numeric = values.iter().all(|v| v.is_none_or(|x| x.is_number()));
+
+    if numeric {
+      let array: Float64Array = values.iter().map(|v| v.and_then(|x| x.as_f64())).collect();
+      fields.push(Field::new(c, DataType::Float64, true));
+      arrays.push(Arc::new(array));
+    } else {
+      let array: StringArray = values.iter().map(|v| v.map(|x| table::cell_string(Some(x)))).collect();
+      fields.push(Field::new(c, DataType::Utf8, true));
+      arrays.push(Arc::new(array));
+    }
+  }
+
+  let schema = Arc::new(Schema::new(fields));
+  let batch = RecordBatch::try_new(schema.clone(), arrays)?;
+
+  let file = fs::File::create(path)?;
+  let mut writer = ArrowWriter::try_new(file, schema, None)?;
+  writer.write(&batch)?;
+  writer.close()?;
+
+  Ok(())
+}
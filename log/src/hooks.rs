@@ -0,0 +1,80 @@
+use std::io::Write;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use serde_json::{Map, Value};
+
+use crate::error::{ErrorKind, LogError, Result};
+
+// Executables in a project's .log/hooks directory that run around an upload, for checks and
+// side jobs like refusing to log a run with errors in it or posting a notification afterwards.
+// The hooks directory is looked for in the logged directory and then every directory above it up
+// to the project root, the one holding .log/config. The first one found is used, unless the
+// config points somewhere else with `hooks`. Without a project config only the logged directory counts.
+// .log is never uploaded or written by an update, so hooks can't come from somebody else's upload. Hooks are named after when they run, get a json object describing
+// the upload on stdin and run from inside the logged directory. A hook that exits with anything
+// but 0 stops the upload. Files without the executable bit are ignored, like git does.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Hook {
+  PreHash, // before the directory is hashed
+  PreUpload, // once the id and changed files are known, before anything is sent
+  PostUpload // after the server took the upload, too late to stop it
+}
+
+impl Hook {
+  pub fn name(&self) -> &'static str {
+    match self {
+      Hook::PreHash => "pre-hash",
+      Hook::PreUpload => "pre-upload",
+      Hook::PostUpload => "post-upload"
+    }
+  }
+}
+
+pub fn hooks_dir(dir: &Path) -> Option<PathBuf> {
+  let root = dir.ancestors().find(|x| x.join(".log/config").is_file()).unwrap_or(dir);
+  for d in dir.ancestors() {
+    let hooks = d.join(".log/hooks");
+    if hooks.is_dir() {
+      return Some(hooks)
+    }
+    if d == root {
+      break;
+    }
+  }
+  None
+}
+
+// Runs hook from hooks_dir for dir if there is one. context gets the name of the hook and dir added to it
+pub fn run(hook: Hook, hooks_dir: &Path, dir: &Path, mut context: Map<String, Value>) -> Result<()> {
+
+  let path = hooks_dir.join(hook.name());
+  if !path.is_file() {
+    return Ok(())
+  }
+  if path.metadata().map(|x| x.permissions().mode() & 0o111 == 0).unwrap_or(true) {
+    return Ok(())
+  }
+
+  context.insert("hook".to_string(), Value::from(hook.name()));
+  context.insert("dir".to_string(), Value::from(dir.display().to_string()));
+
+  let mut child = Command::new(&path)
+    .current_dir(dir)
+    .stdin(Stdio::piped())
+    .spawn()
+    .map_err(|e| LogError::new(ErrorKind::HookFailed, &format!("Could not run {} hook {}: {}", hook.name(), path.display(), e)))?;
+
+  // a hook that doesn't read its input closes the pipe early, which is fine
+  if let Some(mut stdin) = child.stdin.take() {
+    let _ = stdin.write_all(Value::Object(context).to_string().as_bytes());
+  }
+
+  let status = child.wait()?;
+  match status.success() {
+    true => Ok(()),
+    false => Err(LogError::new(ErrorKind::HookFailed, &format!("{} hook {} failed ({})", hook.name(), path.display(), status)))
+  }
+}
@@ -0,0 +1,149 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+// Files that the LAMMPS inputs of a directory read from somewhere outside of it, like
+// `pair_coeff * * ../../potentials/Cu.eam` or `read_data /scratch/shared/data.crack`.
+// These go into the id like tracked files and are uploaded under EXTERNAL_DIR, so a revision
+// records the exact potentials and data it was run with. Paths are taken relative to the
+// directory being logged, which is where LAMMPS runs. Anything with a variable in it (v_name,
+// ${name} or $(expression)) is skipped since it can't be known without running the input.
+
+pub const EXTERNAL_DIR: &str = ".log_external";
+
+#[derive(Debug, Clone)]
+pub struct ExternalFile {
+  pub reference: String, // the path as it is written in the input
+  pub input: String, // input file it was found in
+  pub name: String, // where it goes in REV and the archive, under EXTERNAL_DIR
+  pub path: Option<PathBuf> // where it is read from, None if it is missing
+}
+
+// commands with a file as one of their arguments, and which argument it is
+const FILE_COMMANDS: [(&str, usize); 5] = [("include", 1), ("read_data", 1), ("read_restart", 1), ("read_dump", 1), ("molecule", 2)];
+
+pub fn external_files(dir: &Path) -> std::io::Result<Vec<ExternalFile>> {
+
+  let mut inputs: Vec<String> = fs::read_dir(dir)?
+    .filter_map(|x| x.ok())
+    .map(|x| x.file_name().to_string_lossy().to_string())
+    .filter(|x| x.starts_with("in."))
+    .collect();
+  inputs.sort();
+
+  let mut external: Vec<ExternalFile> = Vec::new();
+  let mut parsed: HashSet<PathBuf> = HashSet::new();
+  for input in inputs {
+    find_references(dir, &dir.join(&input), &input, &mut parsed, &mut external);
+  }
+
+  external.sort_by(|a, b| a.name.cmp(&b.name));
+  external.dedup_by(|a, b| a.name == b.name);
+  Ok(external)
+}
+
+// Adds the external files referenced in input, following include into other files
+fn find_references(dir: &Path, input: &Path, input_name: &str, parsed: &mut HashSet<PathBuf>, external: &mut Vec<ExternalFile>) {
+
+  if !parsed.insert(input.to_path_buf()) {
+    return
+  }
+  let contents = match fs::read_to_string(input) {
+    Ok(c) => c,
+    Err(_) => return
+  };
+
+  for line in logical_lines(&contents) {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let command = match words.first() {
+      Some(c) => *c,
+      None => continue
+    };
+
+    let references: Vec<&str> = match FILE_COMMANDS.iter().find(|(c, _)| *c == command) {
+      Some((_, arg)) => words.get(*arg).into_iter().copied().collect(),
+
+      // potential files are mixed in with element names and numbers, and only ones with a path can be outside of dir
+      None if command == "pair_coeff" => words.iter().skip(3).copied().filter(|x| x.contains('/')).collect(),
+      None => continue
+    };
+
+    for reference in references {
+      let reference = reference.trim_matches(|c| c == '"' || c == '\'');
+      if reference.starts_with("v_") || reference.contains("${") || reference.contains("$(") {
+        continue;
+      }
+
+      let path = match Path::new(reference).is_absolute() {
+        true => PathBuf::from(reference),
+        false => dir.join(reference)
+      };
+
+      if command == "include" {
+        find_references(dir, &path, input_name, parsed, external);
+      }
+
+      let name = match external_name(dir, reference) {
+        Some(name) => name,
+        None => continue // inside of the directory already
+      };
+
+      // a checked out revision only has the copy that was uploaded with it
+      let stored = dir.join(&name);
+      let path = match (path.is_file(), stored.is_file()) {
+        (true, _) => Some(path),
+        (false, true) => Some(stored),
+        (false, false) => None
+      };
+
+      external.push(ExternalFile { reference: reference.to_string(), input: input_name.to_string(), name, path });
+    }
+  }
+}
+
+// Joins lines ending in & and drops comments
+fn logical_lines(contents: &str) -> Vec<String> {
+
+  let mut lines: Vec<String> = Vec::new();
+  let mut current = String::new();
+  for line in contents.lines() {
+    let line = line.split('#').next().unwrap().trim_end();
+    match line.strip_suffix('&') {
+      Some(start) => current.push_str(start),
+      None => {
+        current.push_str(line);
+        lines.push(std::mem::take(&mut current));
+      }
+    }
+  }
+  lines.push(current);
+  lines
+}
+
+// Name under EXTERNAL_DIR for a path outside of dir, None if the path is inside of it.
+// ".." becomes "_parent" and absolute paths start with "_root", so nothing unpacks outside the directory.
+fn external_name(dir: &Path, reference: &str) -> Option<String> {
+
+  let reference = Path::new(reference);
+  if reference.is_absolute() && reference.starts_with(dir) {
+    return None
+  }
+
+  let mut parts: Vec<String> = Vec::new();
+  for component in reference.components() {
+    match component {
+      Component::RootDir => parts.push("_root".to_string()),
+      Component::ParentDir => match parts.last().map(|x| x.as_str()) {
+        Some("_root") | Some("_parent") | None => parts.push("_parent".to_string()),
+        Some(_) => { parts.pop(); }
+      },
+      Component::Normal(x) => parts.push(x.to_string_lossy().to_string()),
+      Component::CurDir | Component::Prefix(_) => {}
+    }
+  }
+
+  match parts.first().map(|x| x.as_str()) {
+    Some("_root") | Some("_parent") => Some(format!("{}/{}", EXTERNAL_DIR, parts.join("/"))),
+    _ => None
+  }
+}
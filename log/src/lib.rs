@@ -1 +1,23 @@
-extern crate utils;
\ No newline at end of file
+extern crate utils;
+
+// Client library behind the `log` binary. LoggerClient does everything `log` does with the
+// server, so other Rust programs can log directories without going through the command line.
+
+pub mod error;
+pub mod snapshot;
+pub mod client;
+pub mod table;
+pub mod export;
+pub mod crypt;
+pub mod signing;
+pub mod merge;
+pub mod codec;
+pub mod progress;
+pub mod tls;
+pub mod hooks;
+pub mod inputs;
+pub mod watch;
+
+pub use error::{ErrorKind, LogError};
+pub use codec::Codec;
+pub use client::{ClientConfig, LoggerClient, Status, UploadOptions, UpdateOptions, UpdatePlan, UploadResult, HistoryEntry, UploadEntry, FileEntry, ServerUser, VerifiedEntry, Integrity, FileCheck};
@@ -1,705 +1,949 @@
+#[macro_use]
+mod report;
+mod sweep;
+mod scheduler;
+mod reproduce;
 
-use std::collections::HashMap;
-use std::{env, io, path, process, fs};
-use std::io::{Write, Read};
-use sha2::{Sha256, Digest};
-use hex;
-use tar::Builder;
-use flate2::{write::GzEncoder, read::GzDecoder};
-use flate2::Compression;
-
-use utils::utils;
-
-use std::path::PathBuf;
-use std::os::unix::fs::PermissionsExt;
+use std::{env, path, fs};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
 
 use nix::unistd;
-use home;
-use rpassword;
-
-use hyper::Client;
-use hyper::{Body, Method, Request,StatusCode};
-use hyper_tls::HttpsConnector;
 
-use tokio::runtime::Runtime;
+use log::{ClientConfig, LoggerClient, UploadOptions, UpdateOptions, UpdatePlan};
+use log::client::read_key;
+use log::error::{ErrorKind, LogError, error_kind};
+use log::snapshot::Snapshot;
+use log::{signing, table};
 
-const HASH_TRUNCATE_LENGTH: usize = 16;
+use report::Report;
 
 const KEY_FILE: &str = "/etc/.Rust_Logger_Credentials";
 
-const LOG_OPTIONS: [&str; 3] = ["Username", "Server", "tracked_files"];
-
-
+// Command line side of log. Everything that talks to the server lives in the library,
+// this only handles arguments, printing and the suid credential file.
 struct User {
   user_id: u32,
-  logger_config_path: PathBuf,
-  db_table: HashMap<String, String>,
-  admin_password: String,
-  key: String,
-
-  cmd_string: String, // full command for Lammps
-  input_file_path: path::PathBuf, // location of lammps input file or directory
-  curr_file_hashes: HashMap<String,String>, // stores hashes of files  currently in directory
-  record_file_hashes: HashMap<String,String>, // stores hashes of files found in REV file
-  needs_update: bool, // whether or not the REV file needs to be updated
-  
-  filename: Option<String>, 
-  collection_name: String, // bottom directory name
-  compressed_dir: Option<Vec<u8>>,
-  record_file_hash: Option<String>,
-
-  potential_rev_file: Option<Vec<u8>>,
-
-  file_list: Vec<PathBuf>
-}
+  client: LoggerClient, // key is filled in by check_creds()
+
+  metadata: serde_json::Map<String, serde_json::Value>, // extra information stored with an upload, like sweep axes
+  fork: bool, // uploads are siblings on purpose, see UploadOptions
 
-// Lists possible endpoints on server
-struct Endpoint{}
-impl<'a> Endpoint {
-  const REGISTER: &'a str = "/register";
-  const UPLOAD: &'a str = "/upload";
-  const CLEANUP: &'a str = "/cleanup";
-  const ID_CHECK: &'a str = "/check";
-  const UPDATE: &'a str = "/update";
+  report: Report // result of the command, printed at the end with --json
 }
 
 impl User {
 
-  // When starting as root suid, effective id is root. Want to turn this off until needed
-  pub fn user() -> User {
+  // When starting as root suid, effective id is root. Want to turn this off until needed.
+  // The config is ~/.log/config with the project config of dir over it
+  pub fn user(dir: &Path) -> Result<User, Box<dyn std::error::Error>> {
     let raw_uid = unistd::Uid::current().as_raw();
-    unistd::seteuid(unistd::Uid::from_raw(raw_uid)).expect("Error setting initial user id");
+    unistd::seteuid(unistd::Uid::from_raw(raw_uid)).map_err(|e| format!("Error setting initial user id: {e:?}"))?;
 
-    let mut logger_config_path = home::home_dir().unwrap();
-    logger_config_path.push(".log/config");
+    let config = ClientConfig::load(dir)?;
 
-    let mut new_user = User {
+    let new_user = User {
       user_id: raw_uid,
-      logger_config_path: logger_config_path,
-      db_table: HashMap::new(),
-      admin_password: String::new(), // admin_password should be blank unless performing registration
-      key: String::new(), // key will be initialized at check_creds()
-
-      cmd_string: String::new(), // full command for Lammps
-      input_file_path: path::PathBuf::new(), // location of lammps input file or directory
-      curr_file_hashes: HashMap::new(), // stores hashes of files  currently in directory
-      record_file_hashes: HashMap::new(), // stores hashes of files found in REV file
-      needs_update: false, // whether or not the REV file needs to be updated
-
-      filename: None, 
-      collection_name: String::new(), // bottom directory name
-      compressed_dir: None,
-      record_file_hash: None,
-      potential_rev_file: None,
-      file_list: Vec::new()
+      client: LoggerClient::new(config, ""),
+      metadata: serde_json::Map::new(),
+      fork: false,
+      report: Report::default()
     };
 
-    new_user.read_config_file();
-
     // quick test to see if we can get root
-    new_user.get_root();
-    new_user.return_root();
+    new_user.get_root()?;
+    new_user.return_root()?;
 
-    new_user
+    Ok(new_user)
 
   }
 
-  fn get_root(&self) {
-    if let Err(e) = unistd::seteuid(unistd::Uid::from_raw(0)) {
-      println!("Error setting root id: {e:?}. Executable was probably not compiled as an SUID binary");
-      panic!();
-    }
+  fn get_root(&self) -> Result<(), Box<dyn std::error::Error>> {
+    unistd::seteuid(unistd::Uid::from_raw(0)).map_err(|e| format!("Error setting root id: {e:?}. Executable was probably not compiled as an SUID binary"))?;
+    Ok(())
   }
 
-  fn return_root(&self) {
-    if let Err(e) = unistd::seteuid(unistd::Uid::from_raw(self.user_id)) {
-      println!("Error setting original user id: {e:?}");
-      panic!();
-    }
+  fn return_root(&self) -> Result<(), Box<dyn std::error::Error>> {
+    unistd::seteuid(unistd::Uid::from_raw(self.user_id)).map_err(|e| format!("Error setting original user id: {e:?}"))?;
+    Ok(())
   }
 
-  pub fn send_output(&self) -> Result<hyper::HeaderMap<hyper::header::HeaderValue>, Box<dyn std::error::Error> >  {
-    Ok(self.send_data(Endpoint::UPLOAD)?.0)
-  }
+  // Stores the key for this server in the root owned credential file, replacing any it had before.
+  // None removes the server's line. The file is rewritten next to itself and renamed over the old
+  // one, so an interrupted write can't lose the keys of other servers.
+  fn save_key(&self, new_key: Option<&str>) -> Result<(), Box<dyn std::error::Error>> {
 
-  pub fn check_id(&self) -> Result<String, Box<dyn std::error::Error>> {
-    let result = self.send_data(Endpoint::ID_CHECK)?;
-    Ok(result.0.get("upload_name").unwrap().to_str().unwrap().to_string())
+    let host = self.client.config().host();
+
+    self.get_root()?;
+    let result = (|| -> std::io::Result<()> {
+
+      // using hostname without port number for storing different keys
+      let mut contents = String::new();
+      if path::Path::new(KEY_FILE).exists() {
+        for line in fs::read_to_string(KEY_FILE)?.lines() {
+          if line.split_once(':').map(|(h, _)| h.trim() == host).unwrap_or(false) {
+            continue;
+          }
+          contents.push_str(line);
+          contents.push('\n');
+        }
+      }
+      if let Some(key) = new_key {
+        contents.push_str(&format!("{} : {}\n", host, key));
+      }
+
+      let tmp_path = format!("{}.tmp", KEY_FILE);
+      let mut file = fs::OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(&tmp_path)?;
+      file.set_permissions(fs::Permissions::from_mode(0o600))?;
+      file.write_all(contents.as_bytes())?;
+      file.sync_all()?;
+      fs::rename(&tmp_path, KEY_FILE)
+    })();
+    self.return_root()?;
+
+    result.map_err(|e| format!("Could not save key to {}: {}", KEY_FILE, e).into())
   }
 
-  // Will download and unpack everything into the working directory.
-  // Maybe should try and enumerate the files that are changed.
-  pub fn get_latest_version(&self) -> Result<(), Box<dyn std::error::Error>> {
+  // log key rotate [--admin]
+  pub fn rotate_key(&mut self, as_admin: bool) -> Result<(), Box<dyn std::error::Error>> {
+
+    let new_key = match as_admin {
+      true => {
+        say!("\nPlease enter the administrator password: ");
+        let admin_password = rpassword::read_password()?;
+        let config = self.client.config().clone();
+        let new_key = LoggerClient::rotate_user_key(&config, &admin_password, &config.username)?;
+        self.client = LoggerClient::new(config, &new_key);
+        new_key
+      },
+      false => self.client.rotate_key()?
+    };
 
-    let result = self.send_data(Endpoint::UPDATE)?.1.to_vec();
-    let mut unzipper = GzDecoder::new(&result[..]);
-    let mut uncompressed: Vec<u8> = Vec::new();
-    unzipper.read_to_end(&mut uncompressed)?;
-    let mut archive = tar::Archive::new(uncompressed.as_slice());
-    archive.unpack(env::current_dir().unwrap())?;
+    // the old key is already dead on the server, so the new one can't be lost
+    self.save_key(Some(&new_key)).map_err(|e| format!("{}\nThe server switched to the new key, add this line to {} by hand:\n{} : {}", e, KEY_FILE, self.client.config().host(), new_key))?;
 
+    say!("Key rotated, the old key no longer works");
+    self.report.server_message = Some("Key rotated".to_string());
     Ok(())
   }
- 
 
-  fn send_data(&self, endpoint: &str) -> Result<(hyper::HeaderMap<hyper::header::HeaderValue>, hyper::body::Bytes), Box<dyn std::error::Error> > {
-    let mut server: String = self.db_table.get("Server").unwrap().to_string(); 
-    server.insert_str(0, "https://");
-    server.push_str(endpoint);
-
-    let pword = match endpoint {
-      Endpoint::REGISTER => &self.admin_password,
-      Endpoint::CLEANUP => &self.admin_password,
-      Endpoint::UPLOAD => &self.key,
-      Endpoint::ID_CHECK => &self.key,
-      Endpoint::UPDATE => &self.key,
-      _ => ""
-    };
-    
-
-    let req = Request::builder()
-    .method(Method::POST)
-    .uri(server)
-    .header("password", pword)
-    .header("username", self.db_table.get("Username").unwrap().to_string());
-    
-    let req = match endpoint {
-      Endpoint::REGISTER | Endpoint::CLEANUP => req.body(Body::from("")).unwrap(),
-      _ => {
-
-        let req = req.header("collection", &self.collection_name);
-
-        match endpoint {
-          
-          // can just reuse the filehash header for this
-          Endpoint::ID_CHECK => {
-            let req = req.header("filehash", self.record_file_hash.as_ref().unwrap());
-            req.body(Body::from("")).unwrap()
-          },
-
-          Endpoint::UPDATE => {
-            req.body(Body::from("")).unwrap()
-          },
-
-          _ => {
-            let req = req.header("filename", self.filename.as_ref().unwrap());
-            let req = req.header("filehash", self.curr_file_hashes.get("id").unwrap());
-            
-            // using to_owned() here seems like it would be bad for large files since you might be copying MBs or GBs in memory
-            // However when testing with a 8 MB pdf, I saw no performance difference between this and a method which used the original compressed
-            // dir vec.
-            req.body(Body::from(self.compressed_dir.to_owned().unwrap())).unwrap() 
-          }
-        }
-      }
-    };
+  // log logout
+  pub fn logout(&mut self) -> Result<(), Box<dyn std::error::Error>> {
 
-    let https = HttpsConnector::new();
-    let client = Client::builder().build::<_, hyper::Body>(https);
-
-    // No need to make entire program asynchronous so just defining runtime here to keep it isolated.
-    // Runtime creation takes only 1 or 2 milliseconds
-    let rt = Runtime::new().unwrap();
-    let resp = rt.block_on(async move {
-
-      let resp = client.request(req).await?;
-      let status = resp.status();
-      
-
-      let headers = resp.headers().to_owned();
-      let body_bytes = hyper::body::to_bytes(resp.into_body()).await.unwrap();
-      
-      // Don't try to convert body to string if we are downloading an update
-      let body_string = match endpoint{
-        Endpoint::UPDATE => "",
-        _ => std::str::from_utf8(&body_bytes).unwrap(),
-      };
+    self.client.logout()?;
+    self.save_key(None)?;
 
-      if status != StatusCode::OK {
-        println!("Error: {}", body_string);
-        let err: Box<dyn std::error::Error> = String::from(body_string).into();
-        Err(err)
-      } else {
-        println!("{}", body_string);
-        Ok((headers, body_bytes))
-      }
-      
-    });
-    
-    resp
+    say!("Logged out of {}. The next log command registers this machine again", self.client.config().host());
+    self.report.server_message = Some("Logged out".to_string());
+    Ok(())
   }
 
-   fn register(&self) -> std::result::Result<(), Box<dyn std::error::Error>> {
+  // log key list
+  pub fn list_users(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+
+    say!("\nPlease enter the administrator password: ");
+    let admin_password = rpassword::read_password()?;
+    let users = LoggerClient::list_users(self.client.config(), &admin_password)?;
 
-    let headers = self.send_data(Endpoint::REGISTER)?;
-    let new_key = headers.0.get("key").unwrap().as_bytes();
-    println!("Registration with server successful\n");
+    let headers: Vec<String> = vec!["user".to_string(), "roles".to_string(), "signing_keys".to_string()];
+    let rows: Vec<Vec<String>> = users.iter().map(|u| vec![u.user.to_string(), u.roles.join(","), u.signing_keys.join(",")]).collect();
 
-    // create new file, overwriting the old. Set permissions
-    self.get_root();
-    let mut file: fs::File;
-    if !path::Path::new(KEY_FILE).exists() {
-      file = fs::File::create(KEY_FILE).expect("error creating new credential file");
-      file.set_permissions(fs::Permissions::from_mode(0o600)).expect("Permission set failure");
+    if report::json_mode() {
+      self.report.results = Some(serde_json::Value::Array(users.iter().map(|u| serde_json::json!({
+        "user": u.user,
+        "roles": u.roles,
+        "signing_keys": u.signing_keys
+      })).collect()));
     } else {
-      file = fs::OpenOptions::new().append(true).open(KEY_FILE).unwrap();
+      say!("{}", table::format_table(&headers, &rows));
     }
-    
-    // using hostname without port number for storing different keys
-    file.write_all(self.db_table.get("Server").unwrap().split_once(":").unwrap().0.as_bytes()).unwrap();
-    file.write_all(b" : ").unwrap();
-    file.write_all(new_key).unwrap();
-    file.write_all(b"\n").unwrap();
-    file.flush().unwrap();
-    self.return_root();
+    say!("\n{} users", rows.len());
 
     Ok(())
   }
 
-  pub fn clean_up(&mut self) -> Result<(), Box<dyn std::error::Error> > {
-    println!("\nPlease enter the administrator password: ");
-    self.admin_password.push_str(&rpassword::read_password().unwrap());
-    self.send_data(Endpoint::CLEANUP)?;
+  // log key add <user>
+  pub fn add_cert_user(&mut self, target: &str) -> Result<(), Box<dyn std::error::Error>> {
+
+    say!("\nPlease enter the administrator password: ");
+    let admin_password = rpassword::read_password()?;
+    LoggerClient::add_cert_user(self.client.config(), &admin_password, target)?;
+
+    say!("Added {}. Clients with a certificate for {} can log in now", target, target);
+    self.report.server_message = Some(format!("Added {}", target));
     Ok(())
   }
 
-  pub fn check_creds(&mut self) -> Result<(), Box<dyn std::error::Error> > {
+  // log key revoke <user>
+  pub fn revoke(&mut self, target: &str) -> Result<(), Box<dyn std::error::Error>> {
 
-    let mut need_to_register = false;
+    say!("\nPlease enter the administrator password: ");
+    let admin_password = rpassword::read_password()?;
+    LoggerClient::revoke(self.client.config(), &admin_password, target)?;
 
-    if !path::Path::new(KEY_FILE).exists() {
+    // our own key is useless now
+    if target == self.client.config().username {
+      self.save_key(None)?;
+    }
 
-      need_to_register = true;
+    say!("Revoked {}", target);
+    self.report.server_message = Some(format!("Revoked {}", target));
+    Ok(())
+  }
 
-    } else { // if key file does exist need to check if we have a key for this current site
+  // log key add-signing [<public key>]
+  pub fn add_signing_key(&mut self, public_key: Option<&str>) -> Result<(), Box<dyn std::error::Error>> {
 
-      self.get_root();
-      let mut key_file_hash = HashMap::new();
-      utils::read_file_into_hash(KEY_FILE, None, &mut key_file_hash)?;
-      let key_check = key_file_hash.get(self.db_table.get("Server").unwrap().split_once(":").unwrap().0);
-      if key_check.is_none() {
-        need_to_register = true;
-      }
-      self.return_root();
+    let fingerprint = self.client.add_signing_key(public_key)?;
 
-    }
- 
+    say!("Added signing key {} for {}. Uploads signed with it are accepted now", fingerprint, self.client.config().username);
+    self.report.server_message = Some(format!("Added signing key {}", fingerprint));
+    Ok(())
+  }
 
-    if need_to_register {
+  // log key show-signing
+  // Prints the public half of this machine's signing key, to add from another machine or for an administrator
+  pub fn show_signing_key(&mut self) -> Result<(), Box<dyn std::error::Error>> {
 
-      println!("No credential file found. Starting registration process.\nPlease enter the administrator password: ");
-      self.admin_password.push_str(&rpassword::read_password().unwrap());
-      self.register()?;
+    let key = signing::load_or_create(&self.client.config().signing_key)?;
+    let public_key = key.verifying_key().to_bytes();
 
+    if report::json_mode() {
+      self.report.results = Some(serde_json::json!({
+        "public_key": hex::encode(public_key),
+        "fingerprint": signing::fingerprint(&public_key)
+      }));
+    } else {
+      say!("public key  {}\nfingerprint {}", hex::encode(public_key), signing::fingerprint(&public_key));
     }
+    Ok(())
+  }
 
-    self.get_root();
-    let mut key_file_hash = HashMap::new();
-    utils::read_file_into_hash(KEY_FILE, None, &mut key_file_hash)?;
-    self.key = key_file_hash.get(self.db_table.get("Server").unwrap().split_once(":").unwrap().0).unwrap().to_string();
-    self.return_root();
+  // log key replace-signing <user> <public key>
+  pub fn replace_signing_key(&mut self, target: &str, public_key: &str) -> Result<(), Box<dyn std::error::Error>> {
 
-    println!("Key found on local system");
+    say!("\nPlease enter the administrator password: ");
+    let admin_password = rpassword::read_password()?;
+    LoggerClient::replace_signing_key(self.client.config(), &admin_password, target, public_key)?;
+
+    say!("Replaced the signing keys of {}, uploads from {} have to be signed with the new key now", target, target);
+    self.report.server_message = Some(format!("Replaced the signing keys of {}", target));
     Ok(())
   }
 
-  fn read_config_file(&mut self) {
+  // log key remove-signing <user> [<fingerprint>]
+  pub fn remove_signing_key(&mut self, target: &str, fingerprint: Option<&str>) -> Result<(), Box<dyn std::error::Error>> {
 
-    // checking if credentials file exists
-    if !path::Path::new(&self.logger_config_path).exists() {
-      println!("Error: credentials not set up. Cannot log data before setup.");
-      println!("Please create a file at ~/.log/config with the connection details like so:");
-      for s in LOG_OPTIONS {
-        println!("{} <value>", s);
-      }
-      println!("");
-      panic!();
-    }
+    say!("\nPlease enter the administrator password: ");
+    let admin_password = rpassword::read_password()?;
+    let removed = LoggerClient::remove_signing_key(self.client.config(), &admin_password, target, fingerprint)?;
 
-    utils::read_file_into_hash(self.logger_config_path.to_str().unwrap(), Some(&LOG_OPTIONS), &mut self.db_table).unwrap();
-    
-    // for (k,v) in &self.db_table {
-    //   println!("{} {}", k,v);
-    // }
+    say!("Removed signing keys {} from {}", removed.join(", "), target);
+    self.report.server_message = Some(format!("Removed signing keys {} from {}", removed.join(", "), target));
+    Ok(())
+  }
 
+  pub fn clean_up(&mut self) -> Result<(), Box<dyn std::error::Error> > {
+    say!("\nPlease enter the administrator password: ");
+    let admin_password = rpassword::read_password()?;
+    let message = LoggerClient::clean_up(self.client.config(), &admin_password)?;
+    say!("{}", message);
+    self.report.server_message = Some(message);
+    Ok(())
   }
 
-  pub fn command(&mut self, cmd_string: Vec<String>, c_name: String) {
+  // asks for the encryption passphrase if collection is encrypted and there is no key file
+  pub fn unlock(&mut self, collection: &str) -> Result<(), Box<dyn std::error::Error>> {
 
-    // get full input file path
-    let mut input_file_path = String::new();
-    for i in 1..cmd_string.len() {
-      if cmd_string[i-1] == "-in" || cmd_string[i-1] == "-c" {
-        input_file_path.insert_str(0, &cmd_string[i]);
-      }
+    if !self.client.config().encrypts(collection) || !self.client.needs_passphrase() {
+      return Ok(())
     }
 
-    // will just upload current working directory if no other directory given
-    if input_file_path.is_empty() {
-      input_file_path.insert(0, '.');
+    if !unistd::isatty(0).unwrap_or(false) {
+      return Err(LogError::new(ErrorKind::Usage, &format!("{} is encrypted and there is no terminal to ask for the passphrase.\nSet encryption_key in the config instead.", collection)).into())
     }
 
-    if input_file_path.len() == 0 {
-      panic!("Improperly formatted command string. No input file found")
-    }
+    say!("Encryption passphrase for {}: ", collection);
+    let passphrase = rpassword::read_password()?;
+    self.client.set_passphrase(&passphrase);
+    Ok(())
+  }
 
-    // Get input file directory path and moving to it to ensure logs are stored there
-    let input_file_path = path::Path::new(&input_file_path).to_path_buf().canonicalize().unwrap();
+  pub fn check_creds(&mut self) -> Result<(), Box<dyn std::error::Error> > {
 
-    // Setting our current working directoy to the location of the input lammps file.
-    // if input_file_path is a directory do not move into parent
-    let change_dir = match input_file_path.is_dir() {
-      true => &input_file_path,
-      false => input_file_path.parent().unwrap()
-    };
+    // if key file does exist need to check if we have a key for this current site
+    self.get_root()?;
+    let key = read_key(Path::new(KEY_FILE), self.client.config().host());
+    self.return_root()?;
 
-    match env::set_current_dir(change_dir) {
-      Ok(()) => (),
-      Err(error) => match error.kind() {
-        io::ErrorKind::NotFound => {
-          panic!("Directory not found. Try switching your current directory or providing the full absolute path.");
-        },
-        other_error => panic!("Bruh... {:?}", other_error)
-      }
-    };
-    
-    println!("Moved to {}", env::current_dir().unwrap().display());
+    let key = match key? {
+      Some(key) => key,
 
-    let mut real_string = String::new();
-    for s in cmd_string {
-        real_string.push_str(&s);
-        real_string.push_str(" ");
-    }
+      // the server knows who we are from the certificate
+      None if self.client.config().client_cert.is_some() => {
+        say!("Using client certificate {}", self.client.config().client_cert.as_ref().unwrap().display());
+        return Ok(())
+      },
+
+      None => {
 
-    // reads all file names into vec and sorts so the final hash will be deterministic
-    println!("Finding all files...");
-    self.find_all_files(PathBuf::from("./")).unwrap();
-    self.file_list.sort();
+        // batch jobs have no terminal to type a password into
+        if !unistd::isatty(0).unwrap_or(false) {
+          return Err(LogError::new(ErrorKind::Auth, "No credentials found for this server and no terminal to ask for the administrator password.\nRun log once from a terminal to register this machine.").into())
+        }
+
+        say!("No credential file found. Starting registration process.\nPlease enter the administrator password: ");
+        let admin_password = rpassword::read_password()?;
+        let new_key = LoggerClient::register(self.client.config(), &admin_password)?;
+        say!("Registration with server successful\n");
+        self.save_key(Some(&new_key))?;
+        new_key
+
+      }
+    };
 
-    self.cmd_string = real_string;
-    self.input_file_path = input_file_path;
-    self.curr_file_hashes = HashMap::new();
-    self.record_file_hashes = HashMap::new();
-    self.needs_update = false;
-    self.collection_name = c_name;
-    
+    self.client = LoggerClient::new(self.client.config().clone(), &key);
 
+    say!("Key found on local system");
+    Ok(())
   }
 
-  pub fn execute(&mut self) -> io::Result<()> {
+  // Runs a search against the server and prints the matches as a table
+  pub fn query(&mut self, collection: String, query_string: String, fields: String) -> Result<(), Box<dyn std::error::Error>> {
 
-    // Executing lammps command by calling lmp directly through shell command
-    let mut cmd = process::Command::new("sh");
-    cmd.arg("-c");
-    cmd.arg(&self.cmd_string);
-    cmd.stdout(process::Stdio::inherit()); // Allows process to write to parent stdout
+    let results = self.client.query(&collection, &query_string, &fields)?;
 
-    println!("\nExecuting {:?}\n", cmd);
+    let mut headers: Vec<String> = vec!["id".to_string(), "upload_name".to_string(), "upload_time".to_string()];
+    for f in fields.split(',').map(|x| x.trim()).filter(|x| !x.is_empty()) {
+      // the keywords shortcut is stored inside of watch
+      match f {
+        "keywords" => headers.push("watch.keywords".to_string()),
+        _ => headers.push(f.to_string())
+      };
+    }
 
-    println!("Start of command output:\n");
+    let rows: Vec<Vec<String>> = results.iter().map(|r| {
+      headers.iter().map(|h| table::cell_string(table::lookup(r, h))).collect()
+    }).collect();
 
-    match cmd.output() {
-      Ok(output) => {
-        if output.status.success() {
-          println!("\nCommand executed successfully. Control returned to log.");
-        }
-      },
-      Err(err) => panic!("Problem running command {:?}", err)
-    };
+    if report::json_mode() {
+      self.report.results = Some(serde_json::Value::Array(results));
+    } else {
+      say!("{}", table::format_table(&headers, &rows));
+    }
+    say!("\n{} matching uploads", rows.len());
 
-    Ok(self.compress_and_hash().unwrap())
+    Ok(())
   }
 
-  // Gets hashes for current files in working directory
-  fn get_current_filehashes(&mut self) -> io::Result<()> {
+  // Downloads watch values and thermo data as rows and writes them out as csv, parquet or jsonl
+  pub fn export(&mut self, collection: String, query_string: String, fields: String, format: &str, output: &path::Path) -> Result<(), Box<dyn std::error::Error>> {
+
+    let num_rows = self.client.export(&collection, &query_string, &fields, format, output)?;
+    say!("Wrote {} rows to {}", num_rows, output.display());
 
-    let mut final_hasher = Sha256::new();
+    let mut results = serde_json::Map::new();
+    results.insert("rows".to_string(), serde_json::Value::from(num_rows));
+    results.insert("file".to_string(), serde_json::Value::from(output.display().to_string()));
+    self.report.results = Some(serde_json::Value::Object(results));
 
-    // gets hash of every file that should be tracked 
-    for f in &self.file_list {
+    Ok(())
+  }
 
-      if PathBuf::from(&f).is_dir() { continue; } // skipping directories
+  // Prints the chain of uploads that led to id
+  pub fn history(&mut self, id: &str) -> Result<(), Box<dyn std::error::Error>> {
 
-      let f = f.to_str().unwrap();
+    let entries = self.client.history(id)?;
 
-      for s in self.db_table.get("tracked_files").unwrap().split(",").map(|x| x.trim()) {
-        if f.contains(s) {
-          
-          let mut file = fs::File::open(&f)?;
-          let mut file_data: Vec<u8> = Vec::new();
-          file.read_to_end(&mut file_data)?;
-          let hash = Sha256::digest(&file_data);
-          final_hasher.update(hash);
-          // splitting at 2 here to remove the "./"
-          self.curr_file_hashes.insert(f.split_at(2).1.to_string(), hex::encode(hash)[..HASH_TRUNCATE_LENGTH].to_string());
-          break;
+    let headers: Vec<String> = vec!["id".to_string(), "parent_id".to_string(), "upload_name".to_string(), "upload_time".to_string(), "uploaded_by".to_string(), "branch".to_string()];
+    let rows: Vec<Vec<String>> = entries.iter().map(|e| vec![e.id.to_string(), e.parent_id.to_string(), e.upload_name.to_string(), e.upload_time.to_string(), e.uploaded_by.to_string(), e.branch_label.to_string()]).collect();
 
+    if report::json_mode() {
+      self.report.results = Some(serde_json::Value::Array(rows.iter().map(|r| {
+        let mut obj = serde_json::Map::new();
+        for (h, v) in headers.iter().zip(r) {
+          obj.insert(h.to_string(), serde_json::Value::from(v.to_string()));
         }
+        serde_json::Value::Object(obj)
+      }).collect()));
+    } else {
+      say!("{}", table::format_table(&headers, &rows));
+    }
+
+    // the chain stops early if a parent was deleted from the database
+    if let Some(last) = entries.last() {
+      if last.parent_id != "*" {
+        say!("\nParent {} is no longer in the database", last.parent_id);
       }
     }
-    let final_hash = final_hasher.finalize();
 
-    // combining hash with collection name to give full id. 
-    let mut total_id = String::new();
-    total_id.push_str(&self.collection_name);
-    total_id.push_str(":");
-    total_id.push_str(&hex::encode(final_hash)[..HASH_TRUNCATE_LENGTH].to_string());
+    Ok(())
+  }
+
+  // Compares a directory with its REV file and the server's copy, printing every file that differs
+  pub fn check_integrity(&mut self, dir: &Path) -> Result<(), Box<dyn std::error::Error>> {
+
+    let integrity = self.client.check_integrity(dir)?;
+    let mismatches = integrity.mismatches();
+
+    if report::json_mode() {
+      self.report.results = Some(serde_json::json!({
+        "disk_id": integrity.disk_id,
+        "encrypted": integrity.encrypted,
+        "files": integrity.files.iter().map(|f| serde_json::json!({
+          "file": f.file,
+          "rev": f.rev,
+          "disk": f.disk,
+          "server_archive": f.server_archive,
+          "server_files": f.server_files
+        })).collect::<Vec<serde_json::Value>>(),
+        "mismatches": mismatches
+      }));
+    }
+
+    self.report.id = Some(integrity.id.to_string());
+    self.report.changed_files = integrity.files.iter().filter(|f| f.mismatch(integrity.encrypted).is_some()).map(|f| f.file.to_string()).collect();
 
-    self.curr_file_hashes.insert("id".to_string(), total_id);
+    if integrity.encrypted {
+      say!("{} is encrypted, the server can only check the ids in REV", integrity.id);
+    }
+
+    if !mismatches.is_empty() {
+      for m in &mismatches {
+        say!("{}", m);
+      }
+      return Err(LogError::new(ErrorKind::Mismatch, &format!("{} does not match {}", dir.display(), integrity.id)).into())
+    }
 
+    say!("{} matches {} ({} files)", dir.display(), integrity.id, integrity.files.len());
     Ok(())
   }
 
+  // Checks signatures along the chain of an upload, fails if any of them is missing or wrong
+  pub fn verify(&mut self, id: &str) -> Result<(), Box<dyn std::error::Error>> {
 
-  fn get_record_filehashes(&mut self)  {
-    self.record_file_hashes.clear();
-    utils::read_file_into_hash("REV", None, &mut self.record_file_hashes).unwrap();
-  }
+    let entries = self.client.verify(id)?;
 
-  pub fn update_record(&mut self) {
+    let status = |problems: &Vec<String>| match problems.is_empty() {
+      true => "ok".to_string(),
+      false => problems.join("; ")
+    };
 
-    // current id becomes the new parent id
-    let parent_id = self.record_file_hashes.get("id").unwrap().to_owned();
-    self.make_new_rev(Some(parent_id)).unwrap();
+    if report::json_mode() {
+      self.report.results = Some(serde_json::Value::Array(entries.iter().map(|e| serde_json::json!({
+        "id": e.id,
+        "upload_name": e.upload_name,
+        "signed_by": e.signed_by,
+        "key_fingerprint": e.key_fingerprint,
+        "problems": e.problems
+      })).collect()));
+    } else {
+      let headers: Vec<String> = vec!["id".to_string(), "upload_name".to_string(), "signed_by".to_string(), "key".to_string(), "status".to_string()];
+      let rows: Vec<Vec<String>> = entries.iter().map(|e| vec![
+        e.id.to_string(),
+        e.upload_name.to_string(),
+        e.signed_by.clone().unwrap_or("-".to_string()),
+        e.key_fingerprint.clone().unwrap_or("-".to_string()),
+        status(&e.problems)
+      ]).collect();
+      say!("{}", table::format_table(&headers, &rows));
+    }
 
-    // update record filehashes
-    self.get_record_filehashes();
+    self.report.id = Some(id.to_string());
+
+    let failed = entries.iter().filter(|e| !e.problems.is_empty()).count();
+    if failed > 0 {
+      return Err(LogError::new(ErrorKind::Unverified, &format!("{} of {} uploads failed verification", failed, entries.len())).into())
+    }
+
+    say!("\nAll {} uploads verified", entries.len());
+    Ok(())
   }
 
-  fn make_new_rev(&mut self, parent_id: Option<String>) -> io::Result<()> {
+  // Prints what an update or exact checkout did, or would do on a dry run
+  fn show_plan(&mut self, plan: &UpdatePlan, dry_run: bool) {
 
-    // Puts all hashes into text file along with one "master" hash that sums up the whole directory
-    let mut filenames: Vec<&String> = self.curr_file_hashes.keys().collect();
-    filenames.sort(); // sorting these too so that the REV file is always the same
+    let verb = match dry_run {
+      true => "would be",
+      false => "was"
+    };
+    say!("{} -> {}", plan.from_id.as_deref().unwrap_or("(nothing)"), plan.target_id);
+    if let Some(base_id) = &plan.base_id {
+      say!("Merging local changes made since {}", base_id);
+    }
+    for (label, files) in [("added", &plan.added), ("changed", &plan.changed), ("deleted", &plan.deleted), ("merged", &plan.merged), ("conflict", &plan.conflicts)] {
+      for f in files {
+        say!("  {}: {}", label, f);
+      }
+    }
+    if plan.added.len() + plan.changed.len() + plan.deleted.len() + plan.merged.len() + plan.conflicts.len() == 0 {
+      say!("Nothing {} changed", verb);
+    }
 
-    let mut new_rev = String::new();
+    // only --exact deletes, so files the target dropped are just pointed out
+    if !plan.removed.is_empty() {
+      say!("\nNot in {}, kept (use '--exact' to delete them):", plan.target_id);
+      for f in &plan.removed {
+        say!("  {}", f);
+      }
+    }
 
-    // want ids at top of file
-    new_rev.push_str("id : ");
-    new_rev.push_str(self.curr_file_hashes.get("id").unwrap());
-    println!("New id: {}", self.curr_file_hashes.get("id").unwrap());
-    new_rev.push_str("\n");
+    let mut changed_files: Vec<String> = plan.added.iter().chain(&plan.changed).chain(&plan.deleted).chain(&plan.merged).chain(&plan.conflicts).cloned().collect();
+    changed_files.sort();
+    self.report.id = Some(plan.target_id.to_string());
+    self.report.parent_id = plan.from_id.to_owned();
+    self.report.changed_files = changed_files;
+    self.report.results = Some(serde_json::json!({
+      "dry_run": dry_run,
+      "base_id": plan.base_id,
+      "added": plan.added,
+      "changed": plan.changed,
+      "deleted": plan.deleted,
+      "removed": plan.removed,
+      "merged": plan.merged,
+      "conflicts": plan.conflicts
+    }));
+
+    // conflicts are written out, but the directory still needs fixing up by hand
+    if !dry_run && !plan.conflicts.is_empty() {
+      say!("\n{} files have conflicts. Fix the <<<<<<< markers (or compare with the .latest copies) before logging again", plan.conflicts.len());
+    }
+  }
 
-    new_rev.push_str("parent_id : ");
-    match parent_id {
-      Some(s) => new_rev.push_str(&s),
-      None => new_rev.push_str("*")
-    };
+  // Unpacks an upload into a directory, which must not have anything in it yet.
+  // With sync options the directory can have something in it, and is brought in line with the upload instead.
+  pub fn checkout(&mut self, id: &str, dir: &Path, sync: Option<UpdateOptions>) -> Result<(), Box<dyn std::error::Error>> {
 
-    new_rev.push_str("\n");
+    if let Some(options) = sync {
+      self.unlock(id.split(':').next().unwrap())?;
+      say!("Syncing {} to {}", dir.display(), id);
+      let plan = self.client.sync_to(id, dir, &options)?;
+      self.show_plan(&plan, options.dry_run);
+      return Ok(())
+    }
 
-    for f in filenames {
-      if f == "id" { continue; } // don't need to write id twice
-      new_rev.push_str(f);
-      new_rev.push_str(" : ");
-      new_rev.push_str(self.curr_file_hashes.get(f).unwrap());
-      new_rev.push_str("\n");
+    if dir.exists() && fs::read_dir(dir)?.next().is_some() {
+      return Err(LogError::new(ErrorKind::Usage, &format!("{} is not empty, not checking out into it. Use '--exact' to sync it to {} instead", dir.display(), id)).into())
     }
 
-    self.potential_rev_file = Some(new_rev.into_bytes());    
+    self.unlock(id.split(':').next().unwrap())?;
+    say!("Checking out {} into {}", id, dir.display());
+    let upload_name = self.client.checkout(id, dir)?;
+    say!("Checked out {}", upload_name);
+
+    self.report.id = Some(id.to_string());
+    self.report.upload_name = Some(upload_name);
     Ok(())
   }
 
-  pub fn update_rev_file (&mut self) {
+  // writes a single file from an upload to out, including files the server keeps by reference
+  pub fn fetch(&mut self, id: &str, path: &str, out: &Path) -> Result<(), Box<dyn std::error::Error>> {
 
-    if self.potential_rev_file.is_some() {
-      let mut new_rev = fs::File::create("REV").unwrap();
-      new_rev.write_all(self.potential_rev_file.as_ref().unwrap()).unwrap();
-      new_rev.flush().unwrap();
-    }  
-    
+    self.unlock(id.split(':').next().unwrap())?;
+    let contents = self.client.fetch(id, path)?;
+    fs::write(out, &contents)?;
+    say!("Wrote {} ({} bytes) to {}", path, contents.len(), out.display());
+
+    self.report.id = Some(id.to_string());
+    self.report.changed_files = vec![path.to_string()];
+    Ok(())
   }
 
-  pub fn track_files(&mut self) -> std::result::Result<(), Box<dyn std::error::Error> > {
+  // Same as fetch but the file goes to stdout
+  pub fn cat(&mut self, id: &str, path: &str) -> Result<(), Box<dyn std::error::Error>> {
 
-    if path::Path::new("REV").exists() {
-      self.get_record_filehashes();
+    self.unlock(id.split(':').next().unwrap())?;
+    let contents = self.client.fetch(id, path)?;
 
-      // allows user to change collection if they want
-      if self.collection_name.is_empty() {
-        self.collection_name = self.record_file_hashes.get("id").unwrap().split(":").next().unwrap().to_string();  
+    self.report.id = Some(id.to_string());
+    match report::json_mode() {
+      true => self.report.results = Some(serde_json::Value::from(String::from_utf8_lossy(&contents).to_string())),
+      false => {
+        let mut stdout = std::io::stdout();
+        stdout.write_all(&contents)?;
+        stdout.flush()?;
       }
-      
-    } else if self.collection_name.is_empty(){
-      let my_err: Box<dyn std::error::Error> = String::from("No collection name specified. Don't know where to put this.\nPlease specify a collection name with the '--coll' option.").into();
-      return Err(my_err)
+    }
+    Ok(())
+  }
+
+  // log ls: collections, log ls <collection>: uploads in it, log ls <id>: files in the upload.
+  // user narrows the uploads of a collection down to the ones they made
+  pub fn ls(&mut self, target: Option<&str>, user: Option<&str>) -> Result<(), Box<dyn std::error::Error>> {
+
+    if user.is_some() && target.map(|x| x.contains(':')).unwrap_or(true) {
+      return Err(LogError::new(ErrorKind::Usage, "'--user' only works when listing the uploads of a collection: log ls <collection> --user <name>").into())
     }
 
+    let (headers, rows, results) = match target {
+      None => {
+        let collections = self.client.collections()?;
+        let rows: Vec<Vec<String>> = collections.iter().map(|x| vec![x.to_string()]).collect();
+        (vec!["collection"], rows, serde_json::Value::from(collections))
+      },
+      Some(id) if id.contains(':') => {
+        self.unlock(id.split(':').next().unwrap())?;
+        let files = self.client.files(id)?;
+        let rows: Vec<Vec<String>> = files.iter().map(|f| vec![f.path.to_string(), log::progress::format_bytes(f.size), f.stored.to_string()]).collect();
+        let results = files.iter().map(|f| serde_json::json!({"path": f.path, "size": f.size, "stored": f.stored})).collect();
+        (vec!["path", "size", "stored"], rows, serde_json::Value::Array(results))
+      },
+      Some(collection) => {
+        let uploads = self.client.uploads(collection, user)?;
+        let rows: Vec<Vec<String>> = uploads.iter().map(|u| vec![u.id.to_string(), u.upload_name.to_string(), u.upload_time.to_string(), u.uploaded_by.to_string(), u.branch.to_string(), u.branch_label.to_string()]).collect();
+        let results = uploads.iter().map(|u| serde_json::json!({"id": u.id, "parent_id": u.parent_id, "upload_name": u.upload_name, "upload_time": u.upload_time, "uploaded_by": u.uploaded_by, "branch": u.branch, "branch_label": u.branch_label})).collect();
+        (vec!["id", "upload_name", "upload_time", "uploaded_by", "branch", "label"], rows, serde_json::Value::Array(results))
+      }
+    };
 
+    if report::json_mode() {
+      self.report.results = Some(results);
+    } else {
+      let headers: Vec<String> = headers.iter().map(|x| x.to_string()).collect();
+      say!("{}", table::format_table(&headers, &rows));
+    }
+    say!("\n{} entries", rows.len());
 
-    self.get_current_filehashes().unwrap();
+    Ok(())
+  }
 
-    // if REV file exists, get those recorded hashes, otherwise need to create it
-    // will return immediately after creating new REV file
-    if path::Path::new("REV").exists() {
+  // log config --show: every option, its value and the config file it came from
+  pub fn show_config(&mut self) -> Result<(), Box<dyn std::error::Error>> {
 
-      // can now check if there are any discrepencies between recorded and current filehashes
-      self.check_hashes();
-      if self.needs_update {
-        println!("\nDiscrepency found, need to update record\n");
-      } else {
-        println!("\nNo changes detected in tracked files\n");
-      }
+    let options = self.client.config().show();
 
+    if report::json_mode() {
+      let results = options.iter().map(|(option, value, source)| ((*option).to_string(), serde_json::json!({"value": value, "source": source}))).collect();
+      self.report.results = Some(serde_json::Value::Object(results));
     } else {
-      
-      println!("No REV file found, creating a new one");
-      self.make_new_rev(None).unwrap();
-      self.update_rev_file();
-      self.get_record_filehashes();
+      let headers: Vec<String> = vec!["option".to_string(), "value".to_string(), "source".to_string()];
+      let rows: Vec<Vec<String>> = options.into_iter().map(|(option, value, source)| vec![option.to_string(), value, source]).collect();
+      say!("{}", table::format_table(&headers, &rows));
+    }
 
+    Ok(())
+  }
+
+}
+
+// Works out which directory to log from the command.
+// Uses the directory of the "-in" or "-c" argument, or the current directory if there isn't one.
+fn input_dir(args: &[String]) -> Result<PathBuf, Box<dyn std::error::Error>> {
+
+  let mut input_file_path = String::from(".");
+  for i in 1..args.len() {
+    if args[i-1] == "-in" || args[i-1] == "-c" {
+      input_file_path = args[i].to_string();
     }
+  }
 
-    self.filename= None;
-    self.compressed_dir = None;
-    self.record_file_hash = Some(self.record_file_hashes.get("id").unwrap().to_string());
+  let input_file_path = path::Path::new(&input_file_path).canonicalize()
+    .map_err(|_| LogError::new(ErrorKind::Usage, "Directory not found. Try switching your current directory or providing the full absolute path."))?;
 
-    Ok(())
+  // if input_file_path is a directory do not move into parent
+  match input_file_path.is_dir() {
+    true => Ok(input_file_path),
+    false => Ok(input_file_path.parent().unwrap().to_path_buf())
+  }
+}
 
-   }
+fn main() {
 
-  fn check_hashes(&mut self) {
+  let mut args: Vec<String> = env::args().collect();
 
-    for (k,v) in &self.curr_file_hashes {
-      
-      // if record doesn't exist or is different, need to update record
-      let record_hash = match self.record_file_hashes.get(k) {
-        Some(record) => record,
-        None => "None"
-      };
+  // if additional args provided, remove command from args list
+  if args.len() > 1 {
+    args.remove(0);
+  }
 
-      if record_hash != v {
-        self.needs_update = true;
-        break;
-      }
+  if let Some(v) = args.iter().position(|x| x == "--json") {
+    args.remove(v);
+    report::set_json_mode(true);
+  }
+
+  // the file printed by `log cat` is the only thing on stdout
+  if args.first().map(|x| x == "cat").unwrap_or(false) {
+    report::reserve_stdout();
+  }
+
+  // progress of long hashes and transfers goes to stderr, as a bar on a terminal
+  match args.iter().position(|x| x == "--no-progress") {
+    Some(v) => {
+      args.remove(v);
+    },
+    None => log::progress::set_enabled(true)
+  };
+
+  log::tls::set_prompt(trust_server);
+
+  say!();
+
+  let command = args.first().map(|x| x.to_string()).unwrap_or_default();
 
+  // project config is looked for from the directory being logged
+  let dir = input_dir(&args).ok().or_else(|| env::current_dir().ok()).unwrap_or_default();
+
+  let (result, mut report) = match User::user(&dir) {
+    Ok(mut user) => {
+
+      // the config can be looked at without a key
+      let creds = match command.as_str() {
+        "config" => Ok(()),
+        _ => user.check_creds()
+      };
+      user.report.command = command;
+      let result = match creds {
+        Ok(_) => run(&mut user, args),
+        Err(err) => Err(LogError::new(error_kind(err.as_ref()), &format!("Error when registering: {}", err)).into())
+      };
+      (result, user.report)
+    },
+    Err(err) => {
+      let mut report = Report::default();
+      report.command = command;
+      (Err(err), report)
     }
+  };
 
+  if let Err(err) = result {
+    say!("\n{}", err);
+    report.set_error(err.as_ref());
   }
 
-  pub fn compress_and_hash(&mut self) -> io::Result<()> {
-    
-    // Compressing output directory
-    let mut output_filename = String::new();
-    output_filename.push_str(self.input_file_path.file_name().unwrap().to_str().unwrap());
-    output_filename.push_str(".tar.gz");
-
-    // Need to hash all files to get correct final hash.
-    // The hash of a tar.gz file changes based on the file modification time.
-    // I only care if the file contents themselves change, so i need to hash all individually.
-    let mut all_files: Vec<PathBuf> = fs::read_dir(env::current_dir()?)?.map(|x| x.unwrap().path()).collect();
-    all_files.sort();
+  if report::json_mode() {
+    println!("{}", report.to_json());
+  }
 
-    // Creating tar archive of directory, then compressing
-    println!("Compressing output data.");
-    let mut archive = Builder::new(Vec::new());
+  std::process::exit(report.exit_code());
 
-    let pot_rev_file_ptr = self.potential_rev_file.as_ref();
+}
 
-    for f in all_files {
+fn run(user: &mut User, mut args: Vec<String>) -> Result<(), Box<dyn std::error::Error>> {
 
-      let filename = f.file_name().unwrap();
-      
-      // appending rev file manually
-      // this allows us to change the local rev file only if we succeeded in uploading the data 
-      // to the server.
-      if filename == "REV" && pot_rev_file_ptr.is_some(){
+  if args.is_empty() {
+    return Err(LogError::new(ErrorKind::Usage, "Try entering a command like:\nlog mpirun -np 4 lmp -in in.crack\n\nOr to just compress and send a directory:\nlog -c -in <file or directory>").into())
+  }
 
-        let mut header = tar::Header::new_gnu();
-        header.set_size(pot_rev_file_ptr.unwrap().len().try_into().unwrap());
-        header.set_cksum();
-        header.set_entry_type(tar::EntryType::Regular);
-        header.set_uid(self.user_id.try_into().unwrap());
-        header.set_gid(self.user_id.try_into().unwrap());
-        header.set_mode(0o666);
-        let time = chrono::Utc::now();
-        header.set_mtime(time.timestamp().try_into().unwrap());
-        archive.append_data(&mut header, filename, pot_rev_file_ptr.unwrap().as_slice()).unwrap();
-        continue;
+  if args[0] == "clean" {
+    args.remove(0);
+    return user.clean_up();
+  }
 
-      } else if f.is_dir() { 
+  if args[0] == "logout" {
+    return user.logout();
+  }
 
-        archive.append_dir_all(filename, &f).unwrap();
+  // log config --show [-in <directory>]
+  if args[0] == "config" {
+    return match args.iter().any(|x| x == "--show") {
+      true => user.show_config(),
+      false => Err(LogError::new(ErrorKind::Usage, "Usage: log config --show [-in <directory>]").into())
+    };
+  }
 
-      } else {
+  // log key rotate [--admin] | log key list | log key add <user> | log key revoke <user>
+  // | log key add-signing [<public key>] | log key show-signing | log key replace-signing <user> <public key> | log key remove-signing <user> [<fingerprint>]
+  if args[0] == "key" {
+    args.remove(0);
+    return match args.first().map(|x| x.as_str()) {
+      Some("rotate") => user.rotate_key(args.iter().any(|x| x == "--admin")),
+      Some("list") => user.list_users(),
+      Some("add") if args.len() > 1 => user.add_cert_user(&args[1]),
+      Some("revoke") if args.len() > 1 => user.revoke(&args[1]),
+      Some("add-signing") => user.add_signing_key(args.get(1).map(|x| x.as_str())),
+      Some("show-signing") => user.show_signing_key(),
+      Some("replace-signing") if args.len() > 2 => user.replace_signing_key(&args[1], &args[2]),
+      Some("remove-signing") if args.len() > 1 => user.remove_signing_key(&args[1], args.get(2).map(|x| x.as_str())),
+      _ => Err(LogError::new(ErrorKind::Usage, "Usage: log key rotate [--admin] | log key list | log key add <user> | log key revoke <user> | log key add-signing [<public key>] | log key show-signing | log key replace-signing <user> <public key> | log key remove-signing <user> [<fingerprint>]").into())
+    };
+  }
 
-        archive.append_file(filename, &mut fs::File::open(&f).unwrap()).unwrap();
+  // log query --coll <collection> [--fields a,b] '<query>'
+  if args[0] == "query" {
+    args.remove(0);
 
-      }
+    let mut collection_name = String::new();
+    if let Some(v) = args.iter().position(|x| x == "--coll") {
+      collection_name = option_value(&args, v)?;
+      args.remove(v);
+      args.remove(v);
+    }
 
+    let mut fields = String::new();
+    if let Some(v) = args.iter().position(|x| x == "--fields") {
+      fields = option_value(&args, v)?;
+      args.remove(v);
+      args.remove(v);
     }
 
+    if collection_name.is_empty() {
+      return Err(LogError::new(ErrorKind::Usage, "No collection name specified. Please specify one with the '--coll' option.").into())
+    }
 
-    let archive_result = archive.into_inner().unwrap();
-    let mut encoder = GzEncoder::new(Vec::new(), Compression::fast());
-    encoder.write_all(&archive_result)?;
-    let compressed_data = encoder.finish()?;
+    return user.query(collection_name, args.join(" "), fields);
+  }
 
-    self.compressed_dir = Some(compressed_data);
+  // log export --coll <collection> [--fields a,b] [--format csv|parquet|jsonl] [--out file] ['<query>']
+  if args[0] == "export" {
+    args.remove(0);
 
-    Ok(())
-  
-  }
+    let mut collection_name = String::new();
+    if let Some(v) = args.iter().position(|x| x == "--coll") {
+      collection_name = option_value(&args, v)?;
+      args.remove(v);
+      args.remove(v);
+    }
 
-  fn find_all_files(&mut self, dir: PathBuf) -> Result<(), Box<dyn std::error::Error>> {
-    
-    let mut filenames: Vec<std::ffi::OsString> = fs::read_dir(&dir)?.map(|x| x.unwrap().file_name()).collect();
-    filenames.sort();
+    let mut fields = String::new();
+    if let Some(v) = args.iter().position(|x| x == "--fields") {
+      fields = option_value(&args, v)?;
+      args.remove(v);
+      args.remove(v);
+    }
 
-    for f in filenames {
+    let mut format = String::from("csv");
+    if let Some(v) = args.iter().position(|x| x == "--format") {
+      format = option_value(&args, v)?;
+      args.remove(v);
+      args.remove(v);
+    }
 
-      let mut sub_dir = PathBuf::from(&dir);
-      sub_dir.push(&f);
-      
-      if PathBuf::from(&sub_dir).is_dir() {
-        self.find_all_files(sub_dir)?;
-      } else {
-        self.file_list.push(sub_dir);
-      }
+    if collection_name.is_empty() {
+      return Err(LogError::new(ErrorKind::Usage, "No collection name specified. Please specify one with the '--coll' option.").into())
+    }
 
+    // defaults to <collection>.<format> in the current directory
+    let mut output = PathBuf::from(format!("{}.{}", collection_name, format));
+    if let Some(v) = args.iter().position(|x| x == "--out") {
+      output = PathBuf::from(option_value(&args, v)?);
+      args.remove(v);
+      args.remove(v);
     }
 
-    Ok(())
+    return user.export(collection_name, args.join(" "), fields, &format, &output);
+  }
+
+  // log checkout <id> [--dir <directory>] [--exact [--remove-untracked]] [--dry-run]
+  if args[0] == "checkout" {
+    args.remove(0);
 
+    // defaults to a new directory named after the id
+    let dir = take_option(&mut args, "--dir");
+    let options = take_update_options(&mut args);
+    let id = match args.first() {
+      Some(id) => id.to_string(),
+      None => return Err(LogError::new(ErrorKind::Usage, "No id given. Usage: log checkout <id> [--dir <directory>] [--exact [--remove-untracked]] [--dry-run]").into())
+    };
+    let dir = PathBuf::from(dir.unwrap_or(id.replace(':', "_")));
+
+    // anything besides a plain checkout into an empty directory
+    let sync = Some(options).filter(|x| x.exact || x.dry_run || x.merge);
+    return user.checkout(&id, &dir, sync);
   }
 
-}
+  // log fetch <id> <path> [--out <file>]
+  if args[0] == "fetch" {
+    args.remove(0);
 
+    // defaults to the file name in the current directory
+    let out = take_option(&mut args, "--out");
+    let (id, file_path) = match (args.first(), args.get(1)) {
+      (Some(id), Some(file_path)) => (id.to_string(), file_path.to_string()),
+      _ => return Err(LogError::new(ErrorKind::Usage, "Usage: log fetch <id> <path> [--out <file>]").into())
+    };
+    let out = match out {
+      Some(out) => PathBuf::from(out),
+      None => PathBuf::from(Path::new(&file_path).file_name().ok_or_else(|| LogError::new(ErrorKind::Usage, "path should name a file"))?)
+    };
 
-fn main() {
-  println!();
+    return user.fetch(&id, &file_path, &out);
+  }
 
-  let mut user = User::user();
-  if let Err(err) = user.check_creds() {
-    println!("Error when registering: {}", err);
-    return 
+  // log cat <id> <path>
+  if args[0] == "cat" {
+    return match (args.get(1), args.get(2)) {
+      (Some(id), Some(file_path)) => user.cat(id, file_path),
+      _ => Err(LogError::new(ErrorKind::Usage, "Usage: log cat <id> <path>").into())
+    };
   }
 
-  let mut args: Vec<String> = env::args().collect();
+  // log reproduce <id> [--dir <directory>]
+  if args[0] == "reproduce" {
+    args.remove(0);
+    let dir = take_option(&mut args, "--dir").map(PathBuf::from);
+    let yes = match args.iter().position(|x| x == "--yes") {
+      Some(v) => {
+        args.remove(v);
+        true
+      },
+      None => false
+    };
+    return match args.first() {
+      Some(id) => reproduce::run(user, &id.to_string(), dir, yes),
+      None => Err(LogError::new(ErrorKind::Usage, "No id given. Usage: log reproduce <id> [--dir <directory>] [--yes]").into())
+    };
+  }
+
+  // log ls [<collection> [--user <name>] | <id>]
+  if args[0] == "ls" {
+    args.remove(0);
+    let uploader = take_option(&mut args, "--user");
+    return user.ls(args.first().map(|x| x.as_str()), uploader.as_deref());
+  }
 
-  // if args.len() == 1 {
-  //   println!("\nTry entering a command like:");
-  //   println!("log mpirun -np 4 lmp -in in.crack\n");
-  //   println!("Or to just compress and send a directory:\nlog -c -in <file or directory>\n");
-  //   println!("To clean up dead files on the server:\nlog clean");
-  //   return;
-  // }
+  // log history [<id>]
+  if args[0] == "history" {
+    args.remove(0);
 
-  // if additional args provided, remove command from args list
-  if args.len() > 1 {
+    // defaults to the revision in the current directory
+    let id = match args.first() {
+      Some(id) => id.to_string(),
+      None => match Snapshot::take(&env::current_dir()?, &user.client.config().tracked_files, None).ok().and_then(|s| s.record_id().map(|x| x.to_string())) {
+        Some(id) => id,
+        None => return Err(LogError::new(ErrorKind::Usage, "No id given and no REV file in this directory. Usage: log history [<id>]").into())
+      }
+    };
+
+    return user.history(&id);
+  }
+
+  // log verify <id>
+  // log verify [--dir <directory>]
+  if args[0] == "verify" {
     args.remove(0);
+
+    // with an id the signatures are checked, without one a directory is checked against REV and the server
+    let dir = take_option(&mut args, "--dir");
+    return match args.first() {
+      Some(id) => user.verify(&id.to_string()),
+      None => user.check_integrity(&PathBuf::from(dir.unwrap_or(".".to_string())))
+    };
   }
 
-  if args[0] == "clean" {
+  // log submit [--coll <collection>] [--name <name>] [--scheduler slurm|pbs] <job script> [scheduler options]
+  // log submit --status
+  if args[0] == "submit" {
     args.remove(0);
-    if user.clean_up().is_err() {};
-    return;
+    return match args.iter().any(|x| x == "--status") {
+      true => scheduler::status(),
+      false => scheduler::submit(args)
+    };
   }
 
-  
-  // removing execution option
-  let compress_only = true;
+  // run by the wrapper script from `log submit` once the job is done
+  if args[0] == "job-finish" {
+    args.remove(0);
+    return scheduler::finish(user, args);
+  }
 
+  // log sweep --set name=v1,v2 [--set ...] [--jobs N] -- <command>
+  if args[0] == "sweep" {
+    args.remove(0);
+    let sweep = sweep::parse_args(args).map_err(|err| LogError::new(ErrorKind::Usage, &err.to_string()))?;
+    return sweep::run(user, sweep);
+  }
 
   let mut filename = String::new();
   if let Some(v) = args.iter().position(|x| x == "--name") {
@@ -719,96 +963,134 @@ fn main() {
   if let Some(v) = args.iter().position(|x| x == "--force") {
     args.remove(v);
     force_upload = true;
-    println!("\n[WARNING] : FORCING UPLOAD. MAY CAUSE BREAK IN CHAIN OF ORIGIN\n");
+    say!("\n[WARNING] : FORCING UPLOAD. MAY CAUSE BREAK IN CHAIN OF ORIGIN\n");
   }
 
-  let mut get_latest = false;
+  let mut update = None;
   if let Some(v) = args.iter().position(|x| x == "--update") {
     args.remove(v);
-    get_latest = true;
+    update = Some(take_update_options(&mut args));
   }
 
-  user.command(args, collection_name);
+  log_directory(user, args, collection_name, filename, force_upload, update)
 
-  // cannot continue if no collection name is specified
-  if let Err(err) = user.track_files() { 
-    println!("\n{}", err);
-    return;
-  };
+}
 
-  // if need to update record, should communicate with server to check if current record id exists
-  println!("Checking if previous version exists...");
-  let og_upload_name = match user.check_id() {
-    Ok(name) => name,
-    Err(_) => {
-      println!("Error while checking for previous record");
-      return
-    }
-  };
+// Asked the first time a server shows a certificate that no CA vouches for, like ssh does
+fn trust_server(server: &str, fingerprint: &str) -> bool {
+
+  if !unistd::isatty(0).unwrap_or(false) {
+    return false
+  }
 
-  println!("Version check done\n");
+  say!("The certificate of {} is not signed by a trusted CA.\nSHA256 fingerprint: {}\nCompare it with the one on the server (openssl x509 -in <cert> -noout -fingerprint -sha256).", server, fingerprint);
+  say!("Trust this server from now on? [y/N] ");
+  let mut answer = String::new();
+  if std::io::stdin().read_line(&mut answer).is_err() {
+    return false
+  }
+  matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
+}
 
-  // Need to check if there has been a change.
-  // If there is a local change cannot update.
-  if get_latest { 
-    
-    if user.needs_update {
+// Takes the flags shared by --update and checkout out of the argument list
+fn take_update_options(args: &mut Vec<String>) -> UpdateOptions {
 
-      println!("Current directory has changed. Pulling updates will overwrite your changes. Update stopped");
-      
-    } else {
+  let mut take_flag = |flag: &str| match args.iter().position(|x| x == flag) {
+    Some(v) => {
+      args.remove(v);
+      true
+    },
+    None => false
+  };
 
-      println!("Getting latest version of {}", &user.collection_name);
-      if let Err(err) = user.get_latest_version() {
-        println!("\nError during update: {}", err);
-      } else {
-        println!("Update successful");
-      }
-      
-    }
+  UpdateOptions {
+    dry_run: take_flag("--dry-run"),
+    merge: take_flag("--merge"),
+    exact: take_flag("--exact"),
+    remove_untracked: take_flag("--remove-untracked")
+  }
+}
+
+// The value after the option at args[v], a usage error if it was the last argument
+fn option_value(args: &[String], v: usize) -> Result<String, LogError> {
+  args.get(v + 1).cloned().ok_or_else(|| LogError::new(ErrorKind::Usage, &format!("{} needs a value", args[v])))
+}
 
-    return
-    
+// Takes "--option value" out of the argument list
+fn take_option(args: &mut Vec<String>, option: &str) -> Option<String> {
+  let v = args.iter().position(|x| x == option)?;
+  if v + 1 >= args.len() {
+    return None
   }
+  args.remove(v);
+  Some(args.remove(v))
+}
 
-  // Calculating new REV file if needed
-  if og_upload_name != "DNE" {
+// Checks the directory given by args against its REV file and uploads it if anything changed.
+// With update options it instead pulls the newest upload of the collection into the directory.
+fn log_directory(user: &mut User, args: Vec<String>, collection_name: String, filename: String, force_upload: bool, update: Option<UpdateOptions>) -> Result<(), Box<dyn std::error::Error>> {
 
-    if user.needs_update {
+  let dir = input_dir(&args)?;
+  say!("Logging {}", dir.display());
 
-      println!("Record exists, can update");
-      user.update_record()
+  let collection = Some(collection_name.as_str()).filter(|x| !x.is_empty());
 
-    }  
+  // Local changes stop the update, unless merging or just looking
+  if let Some(update) = update {
 
-  } else if user.record_file_hashes.get("parent_id").unwrap() != "*" && !force_upload { // if parent id is * then it's a new branch and there is no problem
+    if let Some(c) = user.client.collection_for(&dir, collection) {
+      user.unlock(&c)?;
+    }
+
+    say!("Getting latest version...");
+    let plan = user.client.update(&dir, collection, &update).map_err(|e| LogError::new(e.kind, &format!("Error during update: {}", e)))?;
 
-    println!("Error: Previous record not found in database, revert changes or delete REV file to create a new branch");
-    println!("Or run again with '--force'");
-    return
+    user.show_plan(&plan, update.dry_run);
+    if !update.dry_run {
+      say!("Update successful");
+    }
+    return Ok(())
 
   }
-    
-  // Running commands and compressing directory for upload
-  match compress_only {
-    false => user.execute().unwrap(),
-    true => user.compress_and_hash().unwrap()
+
+  let options = UploadOptions {
+    collection: collection.map(|x| x.to_string()),
+    name: Some(filename).filter(|x| !x.is_empty()),
+    force: force_upload,
+    metadata: user.metadata.clone(),
+    hooks: true,
+    command: Some(args.clone()).filter(|_| !args.is_empty() && args[0] != "-c"),
+    parent_id: None,
+    fork: user.fork
   };
 
-  // if no name given will default to directory name
-  if filename.is_empty() {
-    filename = env::current_dir().unwrap().file_name().unwrap().to_str().unwrap().to_string();
+  // collection comes from REV or the config if it wasn't given
+  if let Some(c) = user.client.collection_for(&dir, collection) {
+    user.unlock(&c)?;
   }
-  user.filename = Some(filename);
-    
-  
 
-  println!("Attempting upload...");
-  match user.send_output() {
-    Ok(_) => {
-      user.update_rev_file();
-    },
-    Err(_) => println!("Error sending data file, cannot update REV")
-  };
+  say!("Attempting upload...");
+  let result = user.client.upload(&dir, &options)?;
+
+  say!("New id: {}", result.id);
+  say!("{}", result.server_message);
+  if let Some(warning) = &result.fork_warning {
+    say!("\n[WARNING] : {}\nTwo machines probably uploaded from the same REV. Use 'log history' to see both branches", warning);
+    user.report.warnings.push(warning.to_string());
+  }
+  for f in &result.changed_files {
+    say!("  changed: {}", f);
+  }
+  for f in &result.missing_inputs {
+    say!("[WARNING] : {} does not exist, it was not hashed or uploaded", f);
+  }
+
+  user.report.id = Some(result.id);
+  user.report.parent_id = Some(result.parent_id);
+  user.report.upload_name = Some(result.upload_name);
+  user.report.changed_files = result.changed_files;
+  user.report.server_message = Some(result.server_message);
+
+  Ok(())
 
 }
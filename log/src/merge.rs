@@ -0,0 +1,105 @@
+use similar::{capture_diff_slices, Algorithm, DiffOp};
+
+// Line based three-way merge for `--update --merge`, done the same way as diff3.
+// Regions where neither side changed the base are kept, a region changed on one side only takes
+// that side, and a region changed differently on both sides becomes a conflict with markers.
+
+// (base_start, base_end, local_start, local_end, latest_start, latest_end) of lines unchanged on both sides
+type SyncRegion = (usize, usize, usize, usize, usize, usize);
+
+// (base index, other index, length) of every run of lines equal between base and other
+fn matching_blocks(base: &[&str], other: &[&str]) -> Vec<(usize, usize, usize)> {
+  capture_diff_slices(Algorithm::Myers, base, other).into_iter().filter_map(|op| match op {
+    DiffOp::Equal { old_index, new_index, len } => Some((old_index, new_index, len)),
+    _ => None
+  }).collect()
+}
+
+fn sync_regions(base: &[&str], local: &[&str], latest: &[&str]) -> Vec<SyncRegion> {
+
+  let local_matches = matching_blocks(base, local);
+  let latest_matches = matching_blocks(base, latest);
+
+  let mut regions: Vec<SyncRegion> = Vec::new();
+  let (mut il, mut it) = (0, 0);
+  while il < local_matches.len() && it < latest_matches.len() {
+
+    let (l_base, l_match, l_len) = local_matches[il];
+    let (t_base, t_match, t_len) = latest_matches[it];
+
+    // part of base that both sides kept
+    let start = l_base.max(t_base);
+    let end = (l_base + l_len).min(t_base + t_len);
+    if start < end {
+      let l_start = l_match + (start - l_base);
+      let t_start = t_match + (start - t_base);
+      regions.push((start, end, l_start, l_start + end - start, t_start, t_start + end - start));
+    }
+
+    if l_base + l_len < t_base + t_len {
+      il += 1;
+    } else {
+      it += 1;
+    }
+  }
+
+  // end marker so the changes after the last common region get handled too
+  regions.push((base.len(), base.len(), local.len(), local.len(), latest.len(), latest.len()));
+  regions
+}
+
+// Returns the merged text and the number of conflicts in it
+pub fn merge3(base: &str, local: &str, latest: &str, local_label: &str, latest_label: &str) -> (String, usize) {
+
+  let base: Vec<&str> = base.split_inclusive('\n').collect();
+  let local: Vec<&str> = local.split_inclusive('\n').collect();
+  let latest: Vec<&str> = latest.split_inclusive('\n').collect();
+
+  let mut merged = String::new();
+  let mut conflicts = 0;
+
+  // a conflict block has to start on its own line even if the text before didn't end in a newline
+  let push_lines = |merged: &mut String, lines: &[&str]| {
+    for l in lines {
+      merged.push_str(l);
+    }
+  };
+  let end_line = |merged: &mut String| {
+    if !merged.is_empty() && !merged.ends_with('\n') {
+      merged.push('\n');
+    }
+  };
+
+  let (mut ib, mut il, mut it) = (0, 0, 0);
+  for (b_start, b_end, l_start, l_end, t_start, t_end) in sync_regions(&base, &local, &latest) {
+
+    let base_chunk = &base[ib..b_start];
+    let local_chunk = &local[il..l_start];
+    let latest_chunk = &latest[it..t_start];
+
+    if !local_chunk.is_empty() || !latest_chunk.is_empty() {
+      if local_chunk == latest_chunk || latest_chunk == base_chunk {
+        push_lines(&mut merged, local_chunk);
+      } else if local_chunk == base_chunk {
+        push_lines(&mut merged, latest_chunk);
+      } else {
+        conflicts += 1;
+        end_line(&mut merged);
+        merged.push_str(&format!("<<<<<<< {}\n", local_label));
+        push_lines(&mut merged, local_chunk);
+        end_line(&mut merged);
+        merged.push_str("=======\n");
+        push_lines(&mut merged, latest_chunk);
+        end_line(&mut merged);
+        merged.push_str(&format!(">>>>>>> {}\n", latest_label));
+      }
+    }
+
+    push_lines(&mut merged, &base[b_start..b_end]);
+    ib = b_end;
+    il = l_end;
+    it = t_end;
+  }
+
+  (merged, conflicts)
+}
@@ -0,0 +1,138 @@
+use std::io::{IsTerminal, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+// Progress of the slow parts of logging a directory: hashing, building the tar, compressing,
+// the transfer both ways and unpacking. Nothing is shown unless a step takes longer than
+// SHOW_AFTER, so small directories look the same as always.
+// On a terminal it is a bar redrawn in place on stderr. Otherwise (batch jobs, redirected
+// output) a plain line is printed every LINE_INTERVAL instead.
+// Off unless turned on with set_enabled, so programs using the library stay quiet.
+
+const SHOW_AFTER: Duration = Duration::from_secs(1);
+const REDRAW_INTERVAL: Duration = Duration::from_millis(200);
+const LINE_INTERVAL: Duration = Duration::from_secs(10);
+const BAR_WIDTH: usize = 30;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+pub fn set_enabled(on: bool) {
+  ENABLED.store(on, Ordering::Relaxed);
+}
+
+pub struct Progress {
+  label: String,
+  total: Option<u64>, // bytes, None if not known up front
+  bytes: u64,
+  files: u64,
+  start: Instant,
+  last_draw: Option<Instant>,
+  tty: bool,
+  enabled: bool
+}
+
+impl Progress {
+
+  pub fn new(label: &str, total: Option<u64>) -> Progress {
+    Progress {
+      label: label.to_string(),
+      total,
+      bytes: 0,
+      files: 0,
+      start: Instant::now(),
+      last_draw: None,
+      tty: std::io::stderr().is_terminal(),
+      enabled: ENABLED.load(Ordering::Relaxed)
+    }
+  }
+
+  pub fn add(&mut self, bytes: u64, files: u64) {
+    self.bytes += bytes;
+    self.files += files;
+
+    if !self.enabled || self.start.elapsed() < SHOW_AFTER {
+      return
+    }
+
+    let interval = match self.tty {
+      true => REDRAW_INTERVAL,
+      false => LINE_INTERVAL
+    };
+    if self.last_draw.map(|x| x.elapsed() >= interval).unwrap_or(true) {
+      self.draw(false);
+      self.last_draw = Some(Instant::now());
+    }
+  }
+
+  // Prints the final state, but only if something was shown already
+  pub fn finish(mut self) {
+    if self.last_draw.is_some() {
+      self.draw(true);
+    }
+  }
+
+  fn draw(&mut self, done: bool) {
+
+    let elapsed = self.start.elapsed().as_secs_f64();
+    let rate = self.bytes as f64 / elapsed.max(0.001);
+
+    let mut amount = match self.total {
+      Some(total) => format!("{}/{}", format_bytes(self.bytes), format_bytes(total)),
+      None => format_bytes(self.bytes)
+    };
+    if self.files > 0 {
+      amount.push_str(&format!(", {} files", self.files));
+    }
+
+    let percent = self.total.filter(|x| *x > 0).map(|x| (self.bytes.min(x) as f64 / x as f64 * 100.0) as usize);
+    let eta = match (self.total, done) {
+      (Some(total), false) if rate > 0.0 => format!(", ETA {}", format_duration(total.saturating_sub(self.bytes) as f64 / rate)),
+      (_, true) => format!(" in {}", format_duration(elapsed)),
+      _ => String::new()
+    };
+
+    let mut stderr = std::io::stderr();
+    if self.tty {
+      let bar = match percent {
+        Some(p) => {
+          let filled = p * BAR_WIDTH / 100;
+          format!("[{}{}] {:>3}% ", "=".repeat(filled), " ".repeat(BAR_WIDTH - filled), p)
+        },
+        None => String::new()
+      };
+      let end = match done {
+        true => "\n",
+        false => ""
+      };
+      // \x1b[K clears whatever was left over from a longer line
+      let _ = write!(stderr, "\r{} {}{}, {}/s{}\x1b[K{}", self.label, bar, amount, format_bytes(rate as u64), eta, end);
+    } else {
+      let percent = percent.map(|x| format!("{}% ", x)).unwrap_or_default();
+      let _ = writeln!(stderr, "{}: {}{}, {}/s{}", self.label, percent, amount, format_bytes(rate as u64), eta);
+    }
+    let _ = stderr.flush();
+  }
+}
+
+pub fn format_bytes(bytes: u64) -> String {
+  let units = ["B", "KB", "MB", "GB", "TB"];
+  let mut value = bytes as f64;
+  let mut unit = 0;
+  while value >= 1024.0 && unit < units.len() - 1 {
+    value /= 1024.0;
+    unit += 1;
+  }
+  match unit {
+    0 => format!("{} B", bytes),
+    _ => format!("{:.1} {}", value, units[unit])
+  }
+}
+
+fn format_duration(seconds: f64) -> String {
+  let seconds = seconds.round() as u64;
+  match seconds {
+    s if s >= 3600 => format!("{}h{:02}m", s / 3600, s % 3600 / 60),
+    s if s >= 60 => format!("{}m{:02}s", s / 60, s % 60),
+    s => format!("{}s", s)
+  }
+}
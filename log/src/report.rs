@@ -0,0 +1,111 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use serde_json::{Map, Value};
+
+use log::error::{ErrorKind, error_kind};
+
+// Machine readable results for scripts that wrap log.
+// With --json every command prints exactly one json object to stdout and all of the usual
+// messages go to stderr instead. The process exit code tells what kind of failure happened:
+//
+//   0  success
+//   1  any other error
+//   2  bad command line arguments
+//   3  no changes to upload
+//   4  parent revision missing on the server
+//   5  authentication failure
+//   6  could not reach the server
+//   7  server rejected the request
+//   8  log verify found a missing or bad signature
+//   9  a hook failed and stopped the upload
+//  10  log verify found a directory that doesn't match its REV or the server
+
+static JSON_MODE: AtomicBool = AtomicBool::new(false);
+static STDOUT_RESERVED: AtomicBool = AtomicBool::new(false);
+
+pub fn set_json_mode(on: bool) {
+  JSON_MODE.store(on, Ordering::Relaxed);
+}
+
+pub fn json_mode() -> bool {
+  JSON_MODE.load(Ordering::Relaxed)
+}
+
+// Keeps stdout for what the command itself prints, like the file from `log cat`
+pub fn reserve_stdout() {
+  STDOUT_RESERVED.store(true, Ordering::Relaxed);
+}
+
+pub fn messages_to_stderr() -> bool {
+  json_mode() || STDOUT_RESERVED.load(Ordering::Relaxed)
+}
+
+// println! that moves out of the way of the json output
+macro_rules! say {
+  ($($arg:tt)*) => {
+    if crate::report::messages_to_stderr() {
+      eprintln!($($arg)*)
+    } else {
+      println!($($arg)*)
+    }
+  };
+}
+
+#[derive(Default)]
+pub struct Report {
+  pub command: String,
+  pub id: Option<String>,
+  pub parent_id: Option<String>,
+  pub upload_name: Option<String>,
+  pub changed_files: Vec<String>,
+  pub server_message: Option<String>,
+  pub results: Option<Value>,
+  pub warnings: Vec<String>,
+  error: Option<(ErrorKind, String)>
+}
+
+impl Report {
+
+  pub fn set_error(&mut self, err: &(dyn std::error::Error + 'static)) {
+    self.error = Some((error_kind(err), err.to_string()));
+  }
+
+  pub fn exit_code(&self) -> i32 {
+    match &self.error {
+      Some((kind, _)) => kind.exit_code(),
+      None => 0
+    }
+  }
+
+  pub fn to_json(&self) -> Value {
+
+    let opt = |x: &Option<String>| match x {
+      Some(s) => Value::from(s.to_string()),
+      None => Value::Null
+    };
+
+    let mut obj = Map::new();
+    obj.insert("command".to_string(), Value::from(self.command.to_string()));
+    obj.insert("success".to_string(), Value::from(self.error.is_none()));
+    obj.insert("id".to_string(), opt(&self.id));
+    obj.insert("parent_id".to_string(), opt(&self.parent_id));
+    obj.insert("upload_name".to_string(), opt(&self.upload_name));
+    obj.insert("changed_files".to_string(), Value::from(self.changed_files.to_owned()));
+    obj.insert("server_message".to_string(), opt(&self.server_message));
+    obj.insert("results".to_string(), self.results.to_owned().unwrap_or(Value::Null));
+    obj.insert("warnings".to_string(), Value::from(self.warnings.to_owned()));
+
+    match &self.error {
+      Some((kind, message)) => {
+        obj.insert("error_kind".to_string(), Value::from(kind.name()));
+        obj.insert("error".to_string(), Value::from(message.to_string()));
+      },
+      None => {
+        obj.insert("error_kind".to_string(), Value::Null);
+        obj.insert("error".to_string(), Value::Null);
+      }
+    };
+    obj.insert("exit_code".to_string(), Value::from(self.exit_code()));
+
+    Value::Object(obj)
+  }
+}
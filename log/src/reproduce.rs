@@ -0,0 +1,192 @@
+use std::path::{Path, PathBuf};
+use std::process;
+
+use nix::unistd;
+use serde_json::{Map, Value};
+
+use log::{ErrorKind, LogError, UploadOptions};
+use log::table;
+
+use crate::{User, report};
+use crate::scheduler::shell_quote;
+
+// `log reproduce <id> [--dir <directory>]` checks out an upload into a fresh directory, runs the
+// command that was stored with it again and logs the result next to the original, as another
+// child of the original's parent. Then the watch values of both runs are compared.
+// Whoever uploaded the original chose the command, so it is shown and has to be confirmed first,
+// or allowed up front with --yes.
+
+pub fn run(user: &mut User, id: &str, dir: Option<PathBuf>, yes: bool) -> Result<(), Box<dyn std::error::Error>> {
+
+  let collection = id.split(':').next().unwrap().to_string();
+  user.unlock(&collection)?;
+
+  let original = record(user, &collection, id)?.ok_or_else(|| LogError::new(ErrorKind::Usage, &format!("No upload with id {}", id)))?;
+  // stored as the list of arguments it was given, the first being the program
+  let command: Vec<String> = match original.get("command").and_then(|x| x.as_array()) {
+    Some(command) if !command.is_empty() => command.iter().map(|x| x.as_str().unwrap_or_default().to_string()).collect(),
+    _ => return Err(LogError::new(ErrorKind::Usage, &format!("{} has no recorded command. Only uploads logged with `log <command>` can be reproduced", id)).into())
+  };
+  let parent_id = original.get("parent_id").and_then(|x| x.as_str()).unwrap_or("*").to_string();
+
+  let uploader = original.get("uploaded_by").and_then(|x| x.as_str()).unwrap_or("an unknown user");
+  say!("{} was uploaded by {} with the command:\n  {}", id, uploader, shown(&command));
+  if !yes && !confirm()? {
+    return Err(LogError::new(ErrorKind::Usage, "Not running the command. Pass '--yes' to run it without asking").into())
+  }
+
+  // defaults to a new directory named after the id
+  let dir = dir.unwrap_or(PathBuf::from(format!("{}_reproduced", id.replace(':', "_"))));
+  if dir.exists() && dir.read_dir()?.next().is_some() {
+    return Err(LogError::new(ErrorKind::Usage, &format!("{} already exists and is not empty. Pick another with '--dir'", dir.display())).into())
+  }
+
+  say!("Checking out {} into {}", id, dir.display());
+  user.client.checkout(id, &dir)?;
+
+  if !execute(&command, &dir)? {
+    say!("\n[WARNING] : Command did not finish successfully. Logging the directory anyway");
+  }
+
+  let mut metadata = user.metadata.clone();
+  metadata.insert("reproduces".to_string(), Value::from(id));
+
+  let options = UploadOptions {
+    collection: Some(collection.to_string()),
+    name: None,
+    force: false,
+    metadata,
+    hooks: true,
+    command: Some(command),
+    parent_id: Some(parent_id),
+    fork: true // the original is already a child of the same parent
+  };
+
+  say!("Attempting upload...");
+  let result = match user.client.upload(&dir, &options) {
+    Ok(result) => result,
+
+    // the tracked files came out the same, so there is nothing new to log
+    Err(err) if err.kind == ErrorKind::NoChanges => {
+      say!("Every tracked file matches {}, the run was reproduced exactly", id);
+      user.report.id = Some(id.to_string());
+      user.report.server_message = Some("Reproduced exactly".to_string());
+      return Ok(())
+    },
+    Err(err) => return Err(err.into())
+  };
+
+  say!("New id: {} (branch {})", result.id, result.branch_label);
+  for f in &result.changed_files {
+    say!("  differs: {}", f);
+  }
+  for f in &result.missing_inputs {
+    say!("[WARNING] : {} does not exist, it was not hashed or uploaded", f);
+  }
+
+  let reproduced = record(user, &collection, &result.id)?.unwrap_or_default();
+  let mut differences: Vec<Vec<String>> = Vec::new();
+  compare("watch", original.get("watch").unwrap_or(&Value::Null), reproduced.get("watch").unwrap_or(&Value::Null), &mut differences);
+
+  if report::json_mode() {
+    let differences: Vec<Value> = differences.iter().map(|d| serde_json::json!({"variable": d[0], "original": d[1], "reproduced": d[2]})).collect();
+    user.report.results = Some(serde_json::json!({"original": id, "differences": differences}));
+  } else if differences.is_empty() {
+    say!("\nAll watch variables and thermo values match {}", id);
+  } else {
+    let headers: Vec<String> = vec!["variable".to_string(), "original".to_string(), "reproduced".to_string()];
+    say!("\n{}", table::format_table(&headers, &differences));
+  }
+
+  user.report.id = Some(result.id);
+  user.report.parent_id = Some(result.parent_id);
+  user.report.upload_name = Some(result.upload_name);
+  user.report.changed_files = result.changed_files;
+  user.report.server_message = Some(result.server_message);
+
+  Ok(())
+}
+
+// Runs the command in dir with its output going straight to the terminal. It is run as it was
+// given, not through a shell. Returns whether it exited successfully.
+fn execute(command: &[String], dir: &Path) -> Result<bool, Box<dyn std::error::Error>> {
+
+  say!("\nExecuting {}\n", shown(command));
+  say!("Start of command output:\n");
+
+  // with --json stdout is kept for the result, so the command's output goes to stderr with the messages
+  let mut cmd = process::Command::new(&command[0]);
+  cmd.args(&command[1..]).current_dir(dir);
+  if report::json_mode() {
+    cmd.stdout(std::io::stderr());
+  }
+
+  let status = cmd.status()
+    .map_err(|e| format!("Problem running command {}: {}", command[0], e))?;
+
+  if status.success() {
+    say!("\nCommand executed successfully. Control returned to log.");
+  }
+  Ok(status.success())
+}
+
+// The arguments as they would be typed, quoted where needed, so what is asked about is exactly
+// what gets run. Control characters are escaped so they can't hide anything on the terminal
+fn shown(command: &[String]) -> String {
+  command.iter().map(|x| match x {
+    x if x.contains(char::is_control) => format!("{:?}", x),
+    x if x.is_empty() || x.contains(|c: char| c.is_whitespace() || "'\"\\$`;&|<>()*?~#".contains(c)) => shell_quote(x),
+    x => x.to_string()
+  }).collect::<Vec<String>>().join(" ")
+}
+
+// Asks on the terminal whether to run the command. There is nobody to ask with --json or without a terminal
+fn confirm() -> Result<bool, Box<dyn std::error::Error>> {
+
+  if report::json_mode() || !unistd::isatty(0).unwrap_or(false) {
+    return Ok(false)
+  }
+
+  say!("Run it? [y/N] ");
+  let mut answer = String::new();
+  std::io::stdin().read_line(&mut answer)?;
+  Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+// command, uploader, parent and watch values of one upload
+fn record(user: &User, collection: &str, id: &str) -> Result<Option<Map<String, Value>>, Box<dyn std::error::Error>> {
+  let results = user.client.query(collection, &format!("id == \"{}\"", id), "command,uploaded_by,parent_id,watch")?;
+  Ok(results.into_iter().find_map(|x| match x {
+    Value::Object(o) => Some(o),
+    _ => None
+  }))
+}
+
+// Adds a row for every value that differs between the two runs. Thermo columns are long lists of
+// numbers, so those get summed up as how many values differ and by how much at most.
+fn compare(name: &str, original: &Value, reproduced: &Value, differences: &mut Vec<Vec<String>>) {
+
+  if original == reproduced {
+    return
+  }
+
+  match (original, reproduced) {
+    (Value::Object(a), Value::Object(b)) => {
+      let mut keys: Vec<&String> = a.keys().chain(b.keys()).collect();
+      keys.sort();
+      keys.dedup();
+      for k in keys {
+        compare(&format!("{}.{}", name, k), a.get(k).unwrap_or(&Value::Null), b.get(k).unwrap_or(&Value::Null), differences);
+      }
+    },
+    (Value::Array(a), Value::Array(b)) if a.len() == b.len() && a.iter().chain(b.iter()).all(|x| x.is_number()) => {
+      let pairs: Vec<(f64, f64)> = a.iter().zip(b.iter()).map(|(x, y)| (x.as_f64().unwrap(), y.as_f64().unwrap())).filter(|(x, y)| x != y).collect();
+      let max_difference = pairs.iter().map(|(x, y)| (x - y).abs()).fold(0.0, f64::max);
+      differences.push(vec![name.to_string(), format!("{} values", a.len()), format!("{} differ, by at most {}", pairs.len(), max_difference)]);
+    },
+    (Value::Array(a), Value::Array(b)) if a.len() != b.len() => {
+      differences.push(vec![name.to_string(), format!("{} values", a.len()), format!("{} values", b.len())]);
+    },
+    _ => differences.push(vec![name.to_string(), table::cell_string(Some(original)), table::cell_string(Some(reproduced))])
+  }
+}
@@ -0,0 +1,361 @@
+use std::{env, fs, process};
+use std::collections::BTreeSet;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+use log::snapshot::{WRAPPER_FILE, JOB_RECORD_FILE};
+
+use crate::{User, log_directory, take_option};
+
+// Batch scheduler support. `log submit job.sh` wraps a job script so that the run directory is
+// logged automatically once the job finishes, and records what the scheduler gave the job.
+// sbatch, squeue and qsub are found through PATH, so stub versions can be dropped in for testing.
+
+#[derive(PartialEq, Clone, Copy)]
+pub enum Scheduler {
+  Slurm,
+  Pbs
+}
+
+impl Scheduler {
+
+  fn from_name(name: &str) -> Result<Scheduler, Box<dyn std::error::Error>> {
+    match name {
+      "slurm" => Ok(Scheduler::Slurm),
+      "pbs" => Ok(Scheduler::Pbs),
+      _ => Err(format!("Unknown scheduler '{}'. Use slurm or pbs", name).into())
+    }
+  }
+
+  fn name(&self) -> &'static str {
+    match self {
+      Scheduler::Slurm => "slurm",
+      Scheduler::Pbs => "pbs"
+    }
+  }
+
+  fn submit_command(&self) -> &'static str {
+    match self {
+      Scheduler::Slurm => "sbatch",
+      Scheduler::Pbs => "qsub"
+    }
+  }
+
+  fn directive(&self) -> &'static str {
+    match self {
+      Scheduler::Slurm => "#SBATCH",
+      Scheduler::Pbs => "#PBS"
+    }
+  }
+
+  // picks whichever submit command is available
+  fn detect() -> Option<Scheduler> {
+    [Scheduler::Slurm, Scheduler::Pbs].into_iter().find(|s| in_path(s.submit_command()))
+  }
+
+  // inside of a running job we can tell from the environment
+  fn from_env() -> Option<Scheduler> {
+    if env::var("SLURM_JOB_ID").is_ok() {
+      Some(Scheduler::Slurm)
+    } else if env::var("PBS_JOBID").is_ok() {
+      Some(Scheduler::Pbs)
+    } else {
+      None
+    }
+  }
+}
+
+fn in_path(exe: &str) -> bool {
+  match env::var_os("PATH") {
+    Some(paths) => env::split_paths(&paths).any(|p| p.join(exe).is_file()),
+    None => false
+  }
+}
+
+// Quotes a string for use in the generated shell script
+pub fn shell_quote(s: &str) -> String {
+  format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+// The job script run from the directory it was submitted in, followed by `log job-finish`. The
+// job script gets the interpreter from its #! line, sh without one. A job cancelled or killed at
+// its time limit gets SIGTERM first, so that is trapped to still log the directory
+fn wrapper_script(scheduler: Scheduler, script_contents: &str, script: &Path, run_dir: &Path, exe: &Path, collection_name: &str, filename: &str) -> String {
+
+  let mut finish_cmd = vec![shell_quote(exe.to_str().unwrap()), "job-finish".to_string(), "--status".to_string(), "$status".to_string()];
+  if !collection_name.is_empty() {
+    finish_cmd.push("--coll".to_string());
+    finish_cmd.push(shell_quote(collection_name));
+  }
+  if !filename.is_empty() {
+    finish_cmd.push("--name".to_string());
+    finish_cmd.push(shell_quote(filename));
+  }
+
+  let interpreter = match script_contents.lines().next().and_then(|x| x.strip_prefix("#!")) {
+    Some(line) if !line.trim().is_empty() => line.split_whitespace().map(shell_quote).collect::<Vec<String>>().join(" "),
+    _ => "sh".to_string()
+  };
+
+  let mut wrapper = String::from("#!/bin/sh\n");
+
+  // the scheduler reads resource requests from the top of the script, so these are copied into the wrapper
+  for d in script_contents.lines().filter(|x| x.trim_start().starts_with(scheduler.directive())) {
+    wrapper.push_str(d);
+    wrapper.push('\n');
+  }
+  wrapper.push_str("\n# generated by log submit. Runs the job script and then logs the directory\n");
+  wrapper.push_str(&format!("cd {}\n", shell_quote(run_dir.to_str().unwrap())));
+  wrapper.push_str(&format!("finish() {{\n  {}\n}}\n", finish_cmd.join(" ")));
+  wrapper.push_str("status=0\n");
+  wrapper.push_str("trap finish EXIT\n");
+  wrapper.push_str("trap 'status=143; kill $job 2>/dev/null; exit 143' TERM INT\n");
+
+  // in the background, since sh only runs a trap once the command it is waiting on is done
+  wrapper.push_str(&format!("{} {} \"$@\" &\n", interpreter, shell_quote(script.to_str().unwrap())));
+  wrapper.push_str("job=$!\n");
+  wrapper.push_str("wait $job\n");
+  wrapper.push_str("status=$?\n");
+  wrapper.push_str("exit $status\n");
+  wrapper
+}
+
+pub fn submit(mut args: Vec<String>) -> Result<(), Box<dyn std::error::Error>> {
+
+  let scheduler = match take_option(&mut args, "--scheduler") {
+    Some(name) => Scheduler::from_name(&name)?,
+    None => Scheduler::detect().ok_or("Could not find sbatch or qsub. Pick a scheduler with '--scheduler'")?
+  };
+
+  let collection_name = take_option(&mut args, "--coll").unwrap_or_default();
+  let filename = take_option(&mut args, "--name").unwrap_or_default();
+
+  if args.is_empty() {
+    return Err("No job script given. Usage: log submit [--coll <collection>] [--name <name>] <job script> [scheduler options]".into())
+  }
+
+  let script = PathBuf::from(args.remove(0)).canonicalize().map_err(|e| format!("Could not find job script: {}", e))?;
+  let run_dir = env::current_dir()?;
+
+  // same check as a normal upload, so that we fail now instead of at the end of the job
+  if !run_dir.join("REV").exists() && collection_name.is_empty() {
+    return Err("No collection name specified. Don't know where to put this.\nPlease specify a collection name with the '--coll' option.".into())
+  }
+
+  let script_contents = fs::read_to_string(&script)?;
+  let wrapper = wrapper_script(scheduler, &script_contents, &script, &run_dir, &env::current_exe()?, &collection_name, &filename);
+
+  let wrapper_path = run_dir.join(WRAPPER_FILE);
+  fs::write(&wrapper_path, wrapper)?;
+  fs::set_permissions(&wrapper_path, fs::Permissions::from_mode(0o755))?;
+
+  let mut cmd = process::Command::new(scheduler.submit_command());
+  if scheduler == Scheduler::Slurm {
+    cmd.arg("--parsable");
+  }
+  cmd.args(&args); // anything left over goes straight to the scheduler
+  cmd.arg(&wrapper_path);
+  cmd.current_dir(&run_dir);
+
+  say!("Submitting {} with {}", script.display(), scheduler.submit_command());
+  let output = cmd.output().map_err(|e| format!("Could not run {}: {}", scheduler.submit_command(), e))?;
+  if !output.status.success() {
+    return Err(format!("{} failed: {}", scheduler.submit_command(), String::from_utf8_lossy(&output.stderr).trim()).into())
+  }
+
+  // sbatch --parsable gives "id" or "id;cluster", qsub gives "id.server"
+  let job_id = String::from_utf8_lossy(&output.stdout).trim().split(';').next().unwrap_or("").to_string();
+  if job_id.is_empty() {
+    return Err(format!("{} did not return a job id", scheduler.submit_command()).into())
+  }
+
+  let mut record = fs::OpenOptions::new().create(true).append(true).open(run_dir.join(JOB_RECORD_FILE))?;
+  std::io::Write::write_all(&mut record, format!("{} : {} {}\n", job_id, scheduler.name(), script.display()).as_bytes())?;
+
+  say!("Submitted job {}. The directory will be logged when the job finishes", job_id);
+
+  Ok(())
+}
+
+// Prints the scheduler state of every job submitted from this directory
+pub fn status() -> Result<(), Box<dyn std::error::Error>> {
+
+  let records = fs::read_to_string(JOB_RECORD_FILE).map_err(|_| "No jobs have been submitted from this directory")?;
+
+  for l in records.lines() {
+    let (job_id, info) = match l.split_once(" : ") {
+      Some(v) => v,
+      None => continue
+    };
+    let scheduler = Scheduler::from_name(info.split_whitespace().next().unwrap_or(""))?;
+
+    let output = match scheduler {
+      Scheduler::Slurm => process::Command::new("squeue").args(["-h", "-j", job_id, "-o", "%T"]).output(),
+      Scheduler::Pbs => process::Command::new("qstat").arg(job_id).output()
+    };
+
+    // finished jobs drop out of the queue
+    let state = match output {
+      Ok(o) if o.status.success() && !String::from_utf8_lossy(&o.stdout).trim().is_empty() => {
+        match scheduler {
+          Scheduler::Slurm => String::from_utf8_lossy(&o.stdout).trim().to_string(),
+          Scheduler::Pbs => String::from_utf8_lossy(&o.stdout).lines().last().unwrap_or("").split_whitespace().nth(4).unwrap_or("UNKNOWN").to_string()
+        }
+      },
+      _ => "FINISHED".to_string()
+    };
+
+    say!("{}  {}  {}", job_id, state, info);
+  }
+
+  Ok(())
+}
+
+fn env_value(name: &str) -> serde_json::Value {
+  match env::var(name) {
+    Ok(v) if !v.is_empty() => serde_json::Value::from(v),
+    _ => serde_json::Value::Null
+  }
+}
+
+// Everything the scheduler tells the job about itself
+fn job_info(scheduler: Scheduler, exit_status: &str) -> serde_json::Map<String, serde_json::Value> {
+
+  let mut info = serde_json::Map::new();
+  info.insert("scheduler".to_string(), serde_json::Value::from(scheduler.name()));
+  info.insert("exit_status".to_string(), serde_json::Value::from(exit_status.parse::<i64>().unwrap_or(-1)));
+
+  let names: [(&str, &str); 10] = match scheduler {
+    Scheduler::Slurm => [
+      ("job_id", "SLURM_JOB_ID"),
+      ("job_name", "SLURM_JOB_NAME"),
+      ("partition", "SLURM_JOB_PARTITION"),
+      ("node_list", "SLURM_JOB_NODELIST"),
+      ("num_nodes", "SLURM_JOB_NUM_NODES"),
+      ("ntasks", "SLURM_NTASKS"),
+      ("cpus_per_task", "SLURM_CPUS_PER_TASK"),
+      ("memory", "SLURM_MEM_PER_NODE"),
+      ("gpus", "SLURM_GPUS"),
+      ("account", "SLURM_JOB_ACCOUNT")
+    ],
+    Scheduler::Pbs => [
+      ("job_id", "PBS_JOBID"),
+      ("job_name", "PBS_JOBNAME"),
+      ("partition", "PBS_QUEUE"),
+      ("node_list", "PBS_NODEFILE"),
+      ("num_nodes", "PBS_NUM_NODES"),
+      ("ntasks", "PBS_NP"),
+      ("cpus_per_task", "PBS_NUM_PPN"),
+      ("memory", "PBS_MEM"),
+      ("gpus", "PBS_NGPUS"),
+      ("account", "PBS_ACCOUNT")
+    ]
+  };
+
+  for (key, var) in names {
+    info.insert(key.to_string(), env_value(var));
+  }
+
+  // PBS only gives a file with one line per core, and the lines of a node aren't always together
+  if scheduler == Scheduler::Pbs {
+    if let Ok(nodefile) = env::var("PBS_NODEFILE") {
+      let nodes: BTreeSet<String> = fs::read_to_string(nodefile).unwrap_or_default().lines().map(|x| x.trim().to_string()).filter(|x| !x.is_empty()).collect();
+      info.insert("node_list".to_string(), serde_json::Value::from(nodes.into_iter().collect::<Vec<String>>().join(",")));
+    }
+  }
+
+  // time limit is not in the environment, so asking squeue for it
+  if scheduler == Scheduler::Slurm {
+    if let Ok(job_id) = env::var("SLURM_JOB_ID") {
+      if let Ok(o) = process::Command::new("squeue").args(["-h", "-j", &job_id, "-o", "%l"]).output() {
+        let limit = String::from_utf8_lossy(&o.stdout).trim().to_string();
+        if o.status.success() && !limit.is_empty() {
+          info.insert("time_limit".to_string(), serde_json::Value::from(limit));
+        }
+      }
+    }
+  }
+
+  info
+}
+
+// Called by the wrapper script at the end of the job
+pub fn finish(user: &mut User, mut args: Vec<String>) -> Result<(), Box<dyn std::error::Error>> {
+
+  let exit_status = take_option(&mut args, "--status").unwrap_or_default();
+  let collection_name = take_option(&mut args, "--coll").unwrap_or_default();
+  let filename = take_option(&mut args, "--name").unwrap_or_default();
+
+  let scheduler = Scheduler::from_env().ok_or("Not running inside of a Slurm or PBS job")?;
+  say!("\nJob finished with status {}. Logging {}", exit_status, env::current_dir()?.display());
+
+  user.metadata.insert("job".to_string(), serde_json::Value::Object(job_info(scheduler, &exit_status)));
+
+  // jobs run long after REV was made, so never force here. A missing parent needs a person to look at it.
+  log_directory(user, vec!["-c".to_string(), ".".to_string()], collection_name, filename, false, None)
+}
+
+#[cfg(test)]
+mod tests {
+
+  use super::*;
+  use std::thread;
+  use std::time::{Duration, Instant};
+  use nix::sys::signal::{kill, Signal};
+  use nix::unistd::Pid;
+
+  // A run directory, with a space in its path, holding a stand-in for log that notes what it was run with
+  fn run_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("log wrapper {} {}", name, process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let exe = dir.join("fake log");
+    fs::write(&exe, format!("#!/bin/sh\necho \"$@\" > {}\n", shell_quote(dir.join("finished").to_str().unwrap()))).unwrap();
+    fs::set_permissions(&exe, fs::Permissions::from_mode(0o755)).unwrap();
+    dir
+  }
+
+  fn write_wrapper(dir: &Path, script_contents: &str) -> PathBuf {
+    let script = dir.join("job.sh");
+    fs::write(&script, script_contents).unwrap();
+    let path = dir.join(WRAPPER_FILE);
+    fs::write(&path, wrapper_script(Scheduler::Slurm, script_contents, &script, dir, &dir.join("fake log"), "crack", "it's run1")).unwrap();
+    path
+  }
+
+  #[test]
+  fn runs_job_script_with_its_interpreter() {
+    let dir = run_dir("interpreter");
+
+    // [[ only works in bash, so this exits with 3 if the script went through sh
+    let wrapper = write_wrapper(&dir, "#!/bin/bash\n#SBATCH -N 2\n[[ -n $BASH_VERSION ]] || exit 3\nexit 4\n");
+    assert!(fs::read_to_string(&wrapper).unwrap().starts_with("#!/bin/sh\n#SBATCH -N 2\n"));
+
+    let status = process::Command::new("sh").arg(&wrapper).status().unwrap();
+    assert_eq!(status.code(), Some(4));
+    assert_eq!(fs::read_to_string(dir.join("finished")).unwrap(), "job-finish --status 4 --coll crack --name it's run1\n");
+
+    fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn logs_cancelled_job() {
+    let dir = run_dir("cancel");
+    let wrapper = write_wrapper(&dir, "touch started\nexec sleep 30\n");
+
+    let mut job = process::Command::new("sh").arg(&wrapper).spawn().unwrap();
+    let start = Instant::now();
+    while !dir.join("started").exists() {
+      assert!(start.elapsed() < Duration::from_secs(10), "job script never started");
+      thread::sleep(Duration::from_millis(20));
+    }
+
+    // what scancel and the time limit send first
+    kill(Pid::from_raw(job.id() as i32), Signal::SIGTERM).unwrap();
+    assert_eq!(job.wait().unwrap().code(), Some(143));
+    assert_eq!(fs::read_to_string(dir.join("finished")).unwrap(), "job-finish --status 143 --coll crack --name it's run1\n");
+
+    fs::remove_dir_all(&dir).unwrap();
+  }
+
+}
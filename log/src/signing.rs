@@ -0,0 +1,78 @@
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use chacha20poly1305::aead::{OsRng, rand_core::RngCore};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use sha2::{Sha256, Digest};
+
+use crate::error::{ErrorKind, LogError, Result};
+
+// Every upload is signed with the user's ed25519 key, which is made the first time it's needed.
+// What gets signed is the hash of the REV file plus the hash of the archive that is sent, so the
+// server (and anyone running `log verify` later) can tell the upload came from this key unchanged.
+
+pub fn default_key_path() -> PathBuf {
+  let mut path = home::home_dir().unwrap_or_default();
+  path.push(".log/signing_key");
+  path
+}
+
+// Reads the hex encoded secret key at path, or makes a new one there that only the user can read
+pub fn load_or_create(path: &Path) -> Result<SigningKey> {
+
+  if path.exists() {
+    let secret: [u8; 32] = hex::decode(fs::read_to_string(path)?.trim()).ok().and_then(|x| x.try_into().ok())
+      .ok_or_else(|| LogError::new(ErrorKind::Usage, &format!("{} is not a signing key", path.display())))?;
+    return Ok(SigningKey::from_bytes(&secret))
+  }
+
+  let mut secret = [0u8; 32];
+  OsRng.fill_bytes(&mut secret);
+  let key = SigningKey::from_bytes(&secret);
+
+  if let Some(parent) = path.parent() {
+    fs::create_dir_all(parent)?;
+  }
+  let mut file = fs::OpenOptions::new().write(true).create_new(true).mode(0o600).open(path)?;
+  file.write_all(hex::encode(secret).as_bytes())?;
+
+  Ok(key)
+}
+
+// has to match what the server checks
+pub fn signed_message(rev: &[u8], archive_hash: &str) -> Vec<u8> {
+  format!("{}\n{}", hex::encode(Sha256::digest(rev)), archive_hash).into_bytes()
+}
+
+pub fn fingerprint(public_key: &[u8]) -> String {
+  hex::encode(Sha256::digest(public_key))[..16].to_string()
+}
+
+// Returns the signature and public key, hex encoded for the headers
+pub fn sign(key: &SigningKey, rev: &[u8], archive: &[u8]) -> (String, String) {
+  let signature = key.sign(&signed_message(rev, &hex::encode(Sha256::digest(archive))));
+  (hex::encode(signature.to_bytes()), hex::encode(key.verifying_key().to_bytes()))
+}
+
+// has to match what the server checks when a key is added
+pub fn key_claim(username: &str, public_key_hex: &str) -> Vec<u8> {
+  format!("signing key of {}\n{}", username, public_key_hex).into_bytes()
+}
+
+// Signs the claim that new_key belongs to username with key. Returns the signature and the
+// public half of key, hex encoded for the headers
+pub fn sign_claim(key: &SigningKey, username: &str, new_key: &str) -> (String, String) {
+  (hex::encode(key.sign(&key_claim(username, new_key)).to_bytes()), hex::encode(key.verifying_key().to_bytes()))
+}
+
+pub fn verify(public_key_hex: &str, signature_hex: &str, message: &[u8]) -> bool {
+
+  let public_key: Option<[u8; 32]> = hex::decode(public_key_hex).ok().and_then(|x| x.try_into().ok());
+  let signature: Option<[u8; 64]> = hex::decode(signature_hex).ok().and_then(|x| x.try_into().ok());
+
+  match (public_key.and_then(|x| VerifyingKey::from_bytes(&x).ok()), signature) {
+    (Some(key), Some(signature)) => key.verify(message, &Signature::from_bytes(&signature)).is_ok(),
+    _ => false
+  }
+}
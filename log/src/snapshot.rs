@@ -0,0 +1,334 @@
+use std::collections::HashMap;
+use std::{fs, io};
+use std::io::{Write, Read};
+use std::path::{Path, PathBuf};
+use sha2::{Sha256, Digest};
+use tar::Builder;
+use nix::unistd;
+
+use utils::utils;
+
+use crate::error::{ErrorKind, LogError, Result};
+use crate::codec::{self, Codec};
+use crate::progress::Progress;
+use crate::inputs::{self, ExternalFile, EXTERNAL_DIR};
+use crate::watch;
+
+// name of the encrypted full archive inside the upload of an encrypted collection
+pub const ENCRYPTED_ARCHIVE: &str = ".log_encrypted";
+
+// watch values of an encrypted upload, read out by the client since the server can't
+pub const WATCH_VALUES_FILE: &str = ".log_watch_values";
+
+// everything an encrypted upload has in plaintext
+pub const INDEX_FILES: [&str; 3] = ["REV", WATCH_VALUES_FILE, ENCRYPTED_ARCHIVE];
+
+// job script wrapper and job records written by `log submit`
+pub const WRAPPER_FILE: &str = ".log_job.sh";
+pub const JOB_RECORD_FILE: &str = ".log_jobs";
+
+// <file>.latest copies written by a conflicted update, one path per line
+pub const LATEST_COPIES_FILE: &str = ".log/latest_copies";
+
+// Files log keeps in a directory for itself: the project's .log config and hooks, job files
+// and the <file>.latest copies left by a conflicted update. Updates never delete these
+pub fn own_file(path: &str, latest_copies: &[String]) -> bool {
+  path.split('/').any(|x| x == ".log") || path == WRAPPER_FILE || path == JOB_RECORD_FILE || latest_copies.iter().any(|x| x == path)
+}
+
+// The .latest copies in dir that log wrote. Ones that have been deleted since are left out
+pub fn latest_copies(dir: &Path) -> Vec<String> {
+  fs::read_to_string(dir.join(LATEST_COPIES_FILE)).unwrap_or_default().lines()
+    .filter(|x| !x.is_empty() && dir.join(x).is_file())
+    .map(|x| x.to_string())
+    .collect()
+}
+
+// Adds paths to the list of .latest copies in dir
+pub fn record_latest_copies(dir: &Path, paths: &[String]) -> io::Result<()> {
+  let mut copies = latest_copies(dir);
+  copies.extend(paths.iter().cloned());
+  copies.sort();
+  copies.dedup();
+
+  fs::create_dir_all(dir.join(LATEST_COPIES_FILE).parent().unwrap())?;
+  fs::write(dir.join(LATEST_COPIES_FILE), copies.join("\n") + "\n")
+}
+
+// The state of a directory compared to its REV file.
+// Only files matching one of the tracked_files patterns are hashed, along with the files outside
+// of the directory that the inputs read (see the inputs module). The hashes of all of them
+// are rolled into one id, which is what ties an upload to its parent.
+
+pub const HASH_TRUNCATE_LENGTH: usize = 16;
+
+pub struct Snapshot {
+  pub dir: PathBuf,
+  pub collection: String,
+  pub current: HashMap<String, String>, // hashes of the tracked files as they are now, plus the "id"
+  pub record: HashMap<String, String>, // contents of the REV file, empty if there isn't one
+  pub external: Vec<ExternalFile> // files outside of dir that the inputs reference
+}
+
+impl Snapshot {
+
+  // Hashes the tracked files in dir. If no collection is given it is taken from the REV file.
+  pub fn take(dir: &Path, tracked_files: &str, collection: Option<&str>) -> Result<Snapshot> {
+
+    let dir = dir.canonicalize().map_err(|e| LogError::new(ErrorKind::Usage, &format!("Could not find {}: {}", dir.display(), e)))?;
+
+    let mut record = HashMap::new();
+    if dir.join("REV").exists() {
+      utils::read_file_into_hash(dir.join("REV").to_str().unwrap(), None, &mut record).map_err(|e| LogError::other(&format!("Could not read REV file: {}", e)))?;
+    }
+
+    // allows user to change collection if they want
+    let collection = match collection.filter(|x| !x.is_empty()) {
+      Some(c) => c.to_string(),
+      None => match record.get("id") {
+        Some(id) => id.split(':').next().unwrap().to_string(),
+        None => return Err(LogError::new(ErrorKind::Usage, "No collection name specified. Don't know where to put this.\nPlease specify a collection name with the '--coll' option."))
+      }
+    };
+
+    let external = inputs::external_files(&dir)?;
+    let mut snapshot = Snapshot { dir, collection, current: HashMap::new(), record, external };
+    snapshot.hash_files(tracked_files)?;
+
+    Ok(snapshot)
+  }
+
+  // collection of the id in dir's REV file, without hashing anything
+  pub fn rev_collection(dir: &Path) -> Option<String> {
+    if !dir.join("REV").exists() {
+      return None
+    }
+    let mut record = HashMap::new();
+    utils::read_file_into_hash(dir.join("REV").to_str()?, None, &mut record).ok()?;
+    record.get("id").map(|id| id.split(':').next().unwrap().to_string())
+  }
+
+  // reads all file names into a sorted list so the final hash will be deterministic
+  pub fn find_all_files(dir: &Path, file_list: &mut Vec<PathBuf>) -> io::Result<()> {
+
+    let mut filenames: Vec<std::ffi::OsString> = fs::read_dir(dir)?.map(|x| x.unwrap().file_name()).collect();
+    filenames.sort();
+
+    for f in filenames {
+      let sub_dir = dir.join(&f);
+      if sub_dir.is_dir() {
+        Snapshot::find_all_files(&sub_dir, file_list)?;
+      } else {
+        file_list.push(sub_dir);
+      }
+    }
+
+    Ok(())
+  }
+
+  fn hash_files(&mut self, tracked_files: &str) -> Result<()> {
+
+    let mut file_list: Vec<PathBuf> = Vec::new();
+    Snapshot::find_all_files(&self.dir, &mut file_list)?;
+    file_list.sort();
+
+    // the copies of external files are hashed below, from wherever they are read
+    file_list.retain(|f| !f.starts_with(self.dir.join(EXTERNAL_DIR)));
+
+    // patterns have always been matched against "./<path>"
+    let tracked: Vec<&PathBuf> = file_list.iter().filter(|f| {
+      let dotted = format!("./{}", f.strip_prefix(&self.dir).unwrap().to_str().unwrap());
+      tracked_files.split(',').map(|x| x.trim()).any(|s| dotted.contains(s))
+    }).collect();
+
+    let total = tracked.iter().filter_map(|f| f.metadata().ok()).map(|x| x.len()).sum();
+    let mut progress = Progress::new("Hashing", Some(total));
+    let mut final_hasher = Sha256::new();
+
+    // gets hash of every file that should be tracked
+    for f in tracked {
+
+      let name = f.strip_prefix(&self.dir).unwrap().to_str().unwrap();
+
+      let mut file = fs::File::open(f)?;
+      let mut file_data: Vec<u8> = Vec::new();
+      file.read_to_end(&mut file_data)?;
+      let hash = Sha256::digest(&file_data);
+      final_hasher.update(hash);
+      self.current.insert(name.to_string(), hex::encode(hash)[..HASH_TRUNCATE_LENGTH].to_string());
+      progress.add(file_data.len() as u64, 1);
+    }
+
+    // after the tracked files, so directories without any keep the id they always had
+    for f in self.external.iter().filter(|x| x.path.is_some()) {
+      let file_data = fs::read(f.path.as_ref().unwrap())?;
+      let hash = Sha256::digest(&file_data);
+      final_hasher.update(hash);
+      self.current.insert(f.name.to_string(), hex::encode(hash)[..HASH_TRUNCATE_LENGTH].to_string());
+      progress.add(file_data.len() as u64, 1);
+    }
+    progress.finish();
+    let final_hash = final_hasher.finalize();
+
+    // combining hash with collection name to give full id.
+    let total_id = format!("{}:{}", self.collection, &hex::encode(final_hash)[..HASH_TRUNCATE_LENGTH]);
+    self.current.insert("id".to_string(), total_id);
+
+    Ok(())
+  }
+
+  // id of the directory as it is right now
+  pub fn id(&self) -> &str {
+    self.current.get("id").unwrap()
+  }
+
+  // id written in the REV file, if the directory was logged before
+  pub fn record_id(&self) -> Option<&str> {
+    self.record.get("id").map(|x| x.as_str())
+  }
+
+  pub fn parent_id(&self) -> Option<&str> {
+    self.record.get("parent_id").map(|x| x.as_str())
+  }
+
+  // true if the tracked files differ from REV, or there is no REV yet
+  pub fn needs_update(&self) -> bool {
+
+    if self.record.is_empty() {
+      return true
+    }
+
+    // if record doesn't exist or is different, need to update record
+    self.current.iter().any(|(k, v)| self.record.get(k) != Some(v))
+  }
+
+  // Files that were added, changed or removed since the REV file was written
+  pub fn changed_files(&self) -> Vec<String> {
+
+    let mut changed: Vec<String> = self.current.iter()
+      .filter(|(k, v)| *k != "id" && self.record.get(*k) != Some(*v))
+      .map(|(k, _)| k.to_string())
+      .collect();
+
+    for k in self.record.keys() {
+      if k != "id" && k != "parent_id" && !self.current.contains_key(k) {
+        changed.push(k.to_string());
+      }
+    }
+
+    changed.sort();
+    changed
+  }
+
+  // Puts all hashes into text file along with one "master" hash that sums up the whole directory
+  pub fn new_rev(&self, parent_id: Option<&str>) -> Vec<u8> {
+
+    let mut filenames: Vec<&String> = self.current.keys().collect();
+    filenames.sort(); // sorting these too so that the REV file is always the same
+
+    // want ids at top of file
+    let mut new_rev = format!("id : {}\nparent_id : {}\n", self.id(), parent_id.unwrap_or("*"));
+
+    for f in filenames {
+      if f == "id" { continue; } // don't need to write id twice
+      new_rev.push_str(&format!("{} : {}\n", f, self.current.get(f).unwrap()));
+    }
+
+    new_rev.into_bytes()
+  }
+
+  pub fn write_rev(&self, rev: &[u8]) -> io::Result<()> {
+    let mut rev_file = fs::File::create(self.dir.join("REV"))?;
+    rev_file.write_all(rev)?;
+    rev_file.flush()
+  }
+
+  // Builds the compressed tar that gets uploaded. The hash of a compressed tar changes with file modification
+  // times, which is why the id comes from the individual file hashes instead.
+  // If a new REV is given it goes into the archive in place of the one on disk. This allows us
+  // to change the local REV file only if we succeeded in uploading the data to the server.
+  pub fn archive(&self, rev: Option<&[u8]>, compression: (Codec, Option<i32>)) -> io::Result<Vec<u8>> {
+
+    let mut all_files: Vec<PathBuf> = fs::read_dir(&self.dir)?.map(|x| x.unwrap().path()).collect();
+    all_files.sort();
+    // the project's .log directory holds config and hooks for this machine, which aren't part of a revision
+    all_files.retain(|f| f.file_name().unwrap() == "REV" || (f.file_name().unwrap() != EXTERNAL_DIR && f.file_name().unwrap() != ".log"));
+
+    // bytes and files under each entry, for the progress
+    let sizes: Vec<(u64, u64)> = all_files.iter().map(|f| {
+      let mut files: Vec<PathBuf> = Vec::new();
+      match f.is_dir() {
+        true => { let _ = Snapshot::find_all_files(f, &mut files); },
+        false => files.push(f.to_path_buf())
+      };
+      (files.iter().filter_map(|x| x.metadata().ok()).map(|x| x.len()).sum(), files.len() as u64)
+    }).collect();
+    let mut progress = Progress::new("Archiving", Some(sizes.iter().map(|x| x.0).sum()));
+
+    let mut archive = Builder::new(Vec::new());
+
+    let mut rev_written = false;
+    for (f, (bytes, files)) in all_files.iter().zip(sizes) {
+
+      let filename = f.file_name().unwrap();
+
+      if let (Some(rev), true) = (rev, filename == "REV") {
+        append_bytes(&mut archive, "REV", rev)?;
+        rev_written = true;
+      } else if f.is_dir() {
+        archive.append_dir_all(filename, f)?;
+      } else {
+        archive.append_file(filename, &mut fs::File::open(f)?)?;
+      }
+
+      progress.add(bytes, files);
+    }
+    progress.finish();
+
+    // external files go under EXTERNAL_DIR from wherever they were found, which can be the copy already there
+    for f in self.external.iter().filter(|x| x.path.is_some()) {
+      archive.append_path_with_name(f.path.as_ref().unwrap(), &f.name)?;
+    }
+
+    // first upload of a directory, nothing on disk yet
+    if let (Some(rev), false) = (rev, rev_written) {
+      append_bytes(&mut archive, "REV", rev)?;
+    }
+
+    let archive_result = archive.into_inner()?;
+    codec::compress(compression.0, compression.1, &archive_result)
+  }
+
+  // For encrypted collections. Nothing of the tracked files goes in plaintext: only the id and
+  // parent_id lines of REV and the watch values read out on this machine, next to the encrypted
+  // full archive. Those are the INDEX_FILES
+  pub fn index_archive(&self, rev: &[u8], compression: (Codec, Option<i32>), encrypted: &[u8]) -> io::Result<Vec<u8>> {
+    let mut archive = Builder::new(Vec::new());
+    append_bytes(&mut archive, "REV", &index_rev(rev))?;
+    append_bytes(&mut archive, WATCH_VALUES_FILE, watch::watch_values(&self.dir)?.as_bytes())?;
+    append_bytes(&mut archive, ENCRYPTED_ARCHIVE, encrypted)?;
+    codec::compress(compression.0, compression.1, &archive.into_inner()?)
+  }
+}
+
+// The REV an encrypted upload shows the server, without the names and hashes of the files
+pub fn index_rev(rev: &[u8]) -> Vec<u8> {
+  String::from_utf8_lossy(rev).lines()
+    .filter(|x| matches!(x.split_once(':').map(|(k, _)| k.trim()), Some("id") | Some("parent_id")))
+    .map(|x| format!("{}\n", x))
+    .collect::<String>()
+    .into_bytes()
+}
+
+fn append_bytes(archive: &mut Builder<Vec<u8>>, name: &str, data: &[u8]) -> io::Result<()> {
+  let uid = unistd::Uid::current().as_raw();
+  let mut header = tar::Header::new_gnu();
+  header.set_size(data.len() as u64);
+  header.set_cksum();
+  header.set_entry_type(tar::EntryType::Regular);
+  header.set_uid(uid as u64);
+  header.set_gid(uid as u64);
+  header.set_mode(0o666);
+  header.set_mtime(chrono::Utc::now().timestamp() as u64);
+  archive.append_data(&mut header, name, data)
+}
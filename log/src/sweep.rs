@@ -0,0 +1,311 @@
+use std::{env, fs, io, process, thread};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crate::{User, log_directory, take_option};
+
+// Parameter sweeps. `log sweep --set timestep=0.001,0.002 --set temp=300,600 -- lmp -in in.crack`
+// copies the current (already logged) directory once per combination of values, rewrites the
+// matching variable lines in the in.* files, runs the command in every copy and then logs each
+// copy as a child of the base revision.
+
+pub struct Sweep {
+  axes: Vec<(String, Vec<String>)>,
+  jobs: usize,
+  cmd: Vec<String>
+}
+
+struct SweepRun {
+  name: String,
+  dir: PathBuf,
+  values: Vec<(String, String)>,
+  cmd: Vec<String>
+}
+
+pub fn parse_args(args: Vec<String>) -> Result<Sweep, Box<dyn std::error::Error>> {
+
+  // everything after "--" is the command to run
+  let split = args.iter().position(|x| x == "--").ok_or("No command given. Put the command to run after '--', like: log sweep --set timestep=0.001,0.002 -- lmp -in in.crack")?;
+  let mut options = args[..split].to_vec();
+  let cmd = args[split+1..].to_vec();
+
+  if cmd.is_empty() {
+    return Err("No command given after '--'".into())
+  }
+
+  let mut axes: Vec<(String, Vec<String>)> = Vec::new();
+  while let Some(set) = take_option(&mut options, "--set") {
+    let (name, values) = set.split_once('=').ok_or(format!("Could not read '--set {}'. Should look like name=value1,value2", set))?;
+    let values: Vec<String> = values.split(',').map(|x| x.trim().to_string()).filter(|x| !x.is_empty()).collect();
+    if name.is_empty() || values.is_empty() {
+      return Err(format!("Could not read '--set {}'. Should look like name=value1,value2", set).into())
+    }
+    axes.push((name.trim().to_string(), values));
+  }
+
+  if axes.is_empty() {
+    return Err("Nothing to sweep over. Give at least one '--set name=value1,value2'".into())
+  }
+
+  let jobs = match take_option(&mut options, "--jobs") {
+    Some(j) => j.parse::<usize>().map_err(|_| format!("'--jobs {}' is not a number", j))?.max(1),
+    None => 1
+  };
+
+  if !options.is_empty() {
+    return Err(format!("Unknown sweep options: {}", options.join(" ")).into())
+  }
+
+  Ok(Sweep { axes, jobs, cmd })
+}
+
+// Every combination of the axis values, in the order they were given
+fn combinations(axes: &[(String, Vec<String>)]) -> Vec<Vec<(String, String)>> {
+  let mut combos: Vec<Vec<(String, String)>> = vec![Vec::new()];
+  for (name, values) in axes {
+    let mut next = Vec::with_capacity(combos.len() * values.len());
+    for c in &combos {
+      for v in values {
+        let mut new_combo = c.clone();
+        new_combo.push((name.to_string(), v.to_string()));
+        next.push(new_combo);
+      }
+    }
+    combos = next;
+  }
+  combos
+}
+
+fn copy_dir(from: &Path, to: &Path) -> io::Result<()> {
+  fs::create_dir(to)?;
+  for entry in fs::read_dir(from)? {
+    let entry = entry?;
+    let target = to.join(entry.file_name());
+    if entry.path().is_dir() {
+      copy_dir(&entry.path(), &target)?;
+    } else {
+      fs::copy(entry.path(), target)?;
+    }
+  }
+  Ok(())
+}
+
+// variable styles that hold a single value the sweep can swap out
+const SETTABLE_STYLES: [&str; 4] = ["equal", "string", "index", "internal"];
+
+// Rewrites the value of "variable <name> <style> ..." lines. Values that were quoted stay quoted,
+// so "variable name string 'a b'" keeps working. Comments at the end of a line are kept.
+// Returns whether anything in the file was changed.
+fn set_value(path: &Path, name: &str, value: &str) -> Result<bool, Box<dyn std::error::Error>> {
+
+  let contents = fs::read_to_string(path)?;
+  let mut found = false;
+  let mut new_contents = String::with_capacity(contents.len());
+
+  for line in contents.lines() {
+
+    let (code, comment) = match line.find('#') {
+      Some(i) => line.split_at(i),
+      None => (line, "")
+    };
+    let words: Vec<&str> = code.split_whitespace().collect();
+
+    let new_line = if words.len() >= 4 && words[0] == "variable" && words[1] == name {
+      let style = words[2];
+      if !SETTABLE_STYLES.contains(&style) {
+        return Err(format!("'{}' in {} is a {} variable, only {} variables can be swept", name, path.display(), style, SETTABLE_STYLES.join(", ")).into())
+      }
+      let quote = words[3].chars().next().filter(|c| *c == '"' || *c == '\'');
+      let value = match quote {
+        Some(q) => format!("{}{}{}", q, value, q),
+        None if value.contains(char::is_whitespace) => format!("\"{}\"", value),
+        None => value.to_string()
+      };
+      let indent = &code[..code.len() - code.trim_start().len()];
+      Some(format!("{}variable {} {} {}", indent, name, style, value))
+    } else {
+      None
+    };
+
+    match new_line {
+      Some(l) => {
+        found = true;
+        new_contents.push_str(&l);
+        if !comment.is_empty() {
+          new_contents.push(' ');
+          new_contents.push_str(comment);
+        }
+      },
+      None => new_contents.push_str(line)
+    };
+    new_contents.push('\n');
+  }
+
+  if found {
+    fs::write(path, new_contents)?;
+  }
+
+  Ok(found)
+}
+
+fn run_jobs(runs: &[SweepRun], jobs: usize) -> Vec<(String, bool)> {
+
+  let queue = Arc::new(Mutex::new(
+    runs.iter().rev().map(|r| (r.name.to_string(), r.dir.to_owned(), r.cmd.to_owned())).collect::<Vec<(String, PathBuf, Vec<String>)>>()
+  ));
+  let results: Arc<Mutex<Vec<(String, bool)>>> = Arc::new(Mutex::new(Vec::new()));
+
+  let workers: Vec<thread::JoinHandle<()>> = (0..jobs).map(|_| {
+    let queue = Arc::clone(&queue);
+    let results = Arc::clone(&results);
+
+    thread::spawn(move || loop {
+
+      let next = queue.lock().unwrap().pop();
+      let (name, dir, cmd) = match next {
+        Some(n) => n,
+        None => break
+      };
+
+      say!("Starting {}", name);
+
+      // output of every run goes into its own directory so parallel runs don't mix
+      let success = match fs::File::create(dir.join("sweep.out")) {
+        Ok(out) => {
+          let err_out = out.try_clone().unwrap();
+          // run as given, the values of a sweep never go through a shell
+          match process::Command::new(&cmd[0]).args(&cmd[1..]).current_dir(&dir).stdout(out).stderr(err_out).status() {
+            Ok(status) => status.success(),
+            Err(err) => {
+              say!("Problem running command in {}: {:?}", name, err);
+              false
+            }
+          }
+        },
+        Err(err) => {
+          say!("Could not create output file in {}: {:?}", name, err);
+          false
+        }
+      };
+
+      say!("Finished {} ({})", name, if success { "success" } else { "failed" });
+      results.lock().unwrap().push((name, success));
+    })
+  }).collect();
+
+  for w in workers {
+    w.join().unwrap();
+  }
+
+  let results = results.lock().unwrap().to_vec();
+  results
+}
+
+pub fn run(user: &mut User, sweep: Sweep) -> Result<(), Box<dyn std::error::Error>> {
+
+  // the base directory has to match a logged revision so every run has a real parent
+  let base_dir = env::current_dir()?;
+  if !base_dir.join("REV").exists() {
+    return Err("This directory has not been logged yet. Log it before starting a sweep".into())
+  }
+  let status = user.client.status(&base_dir, None)?;
+  if status.needs_update || status.upload_name.is_none() {
+    return Err("This directory has changes that have not been logged yet. Log it before starting a sweep".into())
+  }
+
+  let base_id = status.record_id.unwrap();
+  let base_name = base_dir.file_name().unwrap().to_str().unwrap().to_string();
+  let sweep_root = base_dir.parent().ok_or("Cannot sweep from the root directory")?.to_path_buf();
+
+  let input_files: Vec<std::ffi::OsString> = fs::read_dir(&base_dir)?
+    .map(|x| x.unwrap().file_name())
+    .filter(|x| x.to_str().unwrap_or("").starts_with("in."))
+    .collect();
+
+  if input_files.is_empty() {
+    return Err("No in.* files found to change".into())
+  }
+
+  // creating one sibling directory per combination
+  let mut runs: Vec<SweepRun> = Vec::new();
+  for values in combinations(&sweep.axes) {
+
+    let suffix: Vec<String> = values.iter().map(|(k, v)| format!("{}-{}", k, v.replace('/', "_"))).collect();
+    let name = format!("{}_{}", base_name, suffix.join("_"));
+    let dir = sweep_root.join(&name);
+
+    if dir.exists() {
+      return Err(format!("{} already exists, not overwriting it", dir.display()).into())
+    }
+
+    copy_dir(&base_dir, &dir)?;
+
+    for (k, v) in &values {
+      let mut found = false;
+      for f in &input_files {
+        found |= set_value(&dir.join(f), k, v)?;
+      }
+      if !found {
+        return Err(format!("Could not find '{}' in any of the in.* files", k).into())
+      }
+    }
+
+    // any absolute paths into the base directory need to point to the copy instead
+    let cmd: Vec<String> = sweep.cmd.iter().map(|x| x.replace(base_dir.to_str().unwrap(), dir.to_str().unwrap())).collect();
+
+    say!("Created {}", dir.display());
+    runs.push(SweepRun { name, dir, values, cmd });
+  }
+
+  say!("\nRunning {} jobs, {} at a time\n", runs.len(), sweep.jobs);
+  let results = run_jobs(&runs, sweep.jobs);
+
+  // logging one at a time since logging moves the working directory around
+  let mut axes = serde_json::Map::new();
+  for (k, v) in &sweep.axes {
+    axes.insert(k.to_string(), serde_json::Value::from(v.to_owned()));
+  }
+
+  // every run is a child of the base revision
+  user.fork = true;
+  let mut failures: Vec<String> = Vec::new();
+  for r in &runs {
+
+    say!("\nLogging {}", r.name);
+
+    let success = results.iter().any(|(n, s)| n == &r.name && *s);
+    if !success {
+      say!("Command failed, see {}. Not logging this run", r.dir.join("sweep.out").display());
+      failures.push(r.name.to_string());
+      continue;
+    }
+
+    let mut values = serde_json::Map::new();
+    for (k, v) in &r.values {
+      values.insert(k.to_string(), serde_json::Value::from(v.to_string()));
+    }
+
+    let mut sweep_info = serde_json::Map::new();
+    sweep_info.insert("base_id".to_string(), serde_json::Value::from(base_id.to_string()));
+    sweep_info.insert("axes".to_string(), serde_json::Value::Object(axes.clone()));
+    sweep_info.insert("values".to_string(), serde_json::Value::Object(values));
+    user.metadata.insert("sweep".to_string(), serde_json::Value::Object(sweep_info));
+
+    env::set_current_dir(&r.dir)?;
+    if let Err(err) = log_directory(user, r.cmd.clone(), String::new(), r.name.to_string(), false, None) {
+      say!("\n{}", err);
+      failures.push(r.name.to_string());
+    }
+  }
+
+  user.metadata.clear();
+  user.fork = false;
+  env::set_current_dir(&base_dir)?;
+
+  say!("\nSweep done. {} of {} runs logged", runs.len() - failures.len(), runs.len());
+  if !failures.is_empty() {
+    return Err(format!("These runs were not logged: {}", failures.join(", ")).into())
+  }
+
+  Ok(())
+}
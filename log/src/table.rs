@@ -0,0 +1,50 @@
+use serde_json::Value;
+
+// Helpers for printing server results as a plain text table
+
+
+// Follows a dotted path like "watch.timestep" down into a json object
+pub fn lookup<'a>(value: &'a Value, path: &str) -> Option<&'a Value> {
+  let mut curr = value;
+  for p in path.split('.') {
+    curr = curr.get(p)?;
+  }
+  Some(curr)
+}
+
+// Turns a json value into something readable in a single cell
+pub fn cell_string(value: Option<&Value>) -> String {
+  match value {
+    None | Some(Value::Null) => "-".to_string(),
+    Some(Value::String(s)) => s.to_string(),
+    Some(Value::Array(a)) => a.iter().map(|x| cell_string(Some(x))).collect::<Vec<String>>().join(","),
+    Some(Value::Object(o)) => {
+      // dates come back from the server as {"$date": ...}
+      match o.get("$date") {
+        Some(d) => cell_string(Some(d)),
+        None => Value::Object(o.clone()).to_string()
+      }
+    },
+    Some(other) => other.to_string()
+  }
+}
+
+pub fn format_table(headers: &[String], rows: &[Vec<String>]) -> String {
+
+  let mut widths: Vec<usize> = headers.iter().map(|x| x.len()).collect();
+  for row in rows {
+    for (i, cell) in row.iter().enumerate() {
+      widths[i] = widths[i].max(cell.len());
+    }
+  }
+
+  let format_row = |row: &[String]| -> String {
+    row.iter().enumerate().map(|(i, x)| format!("{:width$}", x, width = widths[i])).collect::<Vec<String>>().join("  ").trim_end().to_string()
+  };
+
+  let mut lines = vec![format_row(headers), widths.iter().map(|x| "-".repeat(*x)).collect::<Vec<String>>().join("  ")];
+  for row in rows {
+    lines.push(format_row(row));
+  }
+  lines.join("\n")
+}
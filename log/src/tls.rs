@@ -0,0 +1,175 @@
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::SystemTime;
+
+use rustls::client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier};
+use rustls::{Certificate, PrivateKey, RootCertStore, ServerName};
+use sha2::{Sha256, Digest};
+
+use crate::client::ClientConfig;
+use crate::error::{ErrorKind, LogError, Result};
+
+// How the client decides to trust log_server's certificate.
+// A certificate signed by a CA in the system trust store or in ca_cert is trusted like in a
+// browser. If pinned_fingerprint is set, only that exact certificate is accepted, CA or not.
+// With ca_cert set, a certificate that doesn't check out against it (or the system CAs) is refused.
+// Anything else (usually a self-signed server) is trusted on first use like ssh: the fingerprint
+// is shown, and once accepted it goes into ~/.log/known_servers. A different certificate showing
+// up later for the same server is refused until that line is removed.
+// With client_cert and client_key set the client proves who it is with a certificate as well,
+// for servers that take certificates in place of API keys.
+
+// Asked whether to trust a server nobody vouches for, with the server and fingerprint.
+// Without one, unknown servers are refused.
+pub type TrustPrompt = fn(&str, &str) -> bool;
+
+static PROMPT: OnceLock<TrustPrompt> = OnceLock::new();
+
+pub fn set_prompt(prompt: TrustPrompt) {
+  let _ = PROMPT.set(prompt);
+}
+
+pub fn known_servers_path() -> PathBuf {
+  let mut path = home::home_dir().unwrap_or_default();
+  path.push(".log/known_servers");
+  path
+}
+
+// sha256 of the certificate, written the same way as `openssl x509 -fingerprint -sha256`
+pub fn fingerprint(cert: &[u8]) -> String {
+  Sha256::digest(cert).iter().map(|x| format!("{:02X}", x)).collect::<Vec<String>>().join(":")
+}
+
+// so fingerprints copied with or without colons, in either case, compare equal
+fn normalize(fingerprint: &str) -> String {
+  fingerprint.chars().filter(|x| x.is_ascii_hexdigit()).collect::<String>().to_lowercase()
+}
+
+fn known_fingerprint(server: &str) -> Option<String> {
+  let contents = fs::read_to_string(known_servers_path()).ok()?;
+  contents.lines().filter_map(|x| x.split_once(" : ")).find(|(s, _)| s.trim() == server).map(|(_, f)| f.trim().to_string())
+}
+
+fn remember(server: &str, fingerprint: &str) -> std::io::Result<()> {
+  let path = known_servers_path();
+  if let Some(parent) = path.parent() {
+    fs::create_dir_all(parent)?;
+  }
+  let mut file = fs::OpenOptions::new().create(true).append(true).open(path)?;
+  writeln!(file, "{} : {}", server, fingerprint)
+}
+
+// option is the config option the file came from, for the error messages
+fn read_certs(path: &Path, option: &str) -> Result<Vec<Vec<u8>>> {
+  let pem = fs::read(path).map_err(|e| LogError::new(ErrorKind::Usage, &format!("Could not read {} {}: {}", option, path.display(), e)))?;
+  let certs = rustls_pemfile::certs(&mut pem.as_slice()).map_err(|e| LogError::new(ErrorKind::Usage, &format!("Could not read {} {}: {}", option, path.display(), e)))?;
+  match certs.is_empty() {
+    true => Err(LogError::new(ErrorKind::Usage, &format!("No certificates in {} {}", option, path.display()))),
+    false => Ok(certs)
+  }
+}
+
+// first private key in a pem file, pkcs8, rsa or ec
+fn read_key(path: &Path) -> Result<Vec<u8>> {
+  let pem = fs::read(path).map_err(|e| LogError::new(ErrorKind::Usage, &format!("Could not read client_key {}: {}", path.display(), e)))?;
+  let items = rustls_pemfile::read_all(&mut pem.as_slice()).map_err(|e| LogError::new(ErrorKind::Usage, &format!("Could not read client_key {}: {}", path.display(), e)))?;
+  items.into_iter().find_map(|x| match x {
+    rustls_pemfile::Item::PKCS8Key(key) | rustls_pemfile::Item::RSAKey(key) | rustls_pemfile::Item::ECKey(key) => Some(key),
+    _ => None
+  }).ok_or_else(|| LogError::new(ErrorKind::Usage, &format!("No private key in client_key {}", path.display())))
+}
+
+struct ServerVerifier {
+  server: String, // host:port, how known_servers is keyed
+  webpki: WebPkiVerifier,
+  ca_cert: bool, // the server has to check out against a CA, no trusting on first use
+  pinned: Option<String>,
+  problem: Arc<Mutex<Option<String>>> // why the certificate was refused, for a better error than rustls gives
+}
+
+impl ServerVerifier {
+  fn refuse(&self, problem: String) -> std::result::Result<ServerCertVerified, rustls::Error> {
+    *self.problem.lock().unwrap() = Some(problem.clone());
+    Err(rustls::Error::General(problem))
+  }
+}
+
+impl ServerCertVerifier for ServerVerifier {
+
+  fn verify_server_cert(&self, end_entity: &Certificate, intermediates: &[Certificate], server_name: &ServerName, scts: &mut dyn Iterator<Item = &[u8]>, ocsp_response: &[u8], now: SystemTime) -> std::result::Result<ServerCertVerified, rustls::Error> {
+
+    let seen = fingerprint(&end_entity.0);
+
+    if let Some(pinned) = &self.pinned {
+      return match normalize(pinned) == normalize(&seen) {
+        true => Ok(ServerCertVerified::assertion()),
+        false => self.refuse(format!("The certificate of {} does not match pinned_fingerprint.\n  pinned:    {}\n  presented: {}\nIf the server got a new certificate on purpose, update pinned_fingerprint in the config", self.server, pinned, seen))
+      }
+    }
+
+    // checks the name and expiry as well as the signature
+    let err = match self.webpki.verify_server_cert(end_entity, intermediates, server_name, scts, ocsp_response, now) {
+      Ok(verified) => return Ok(verified),
+      Err(err) => err
+    };
+    if self.ca_cert {
+      return self.refuse(format!("The certificate of {} could not be verified with ca_cert: {}
+  fingerprint: {}", self.server, err, seen))
+    }
+
+    match known_fingerprint(&self.server) {
+      Some(known) if normalize(&known) == normalize(&seen) => Ok(ServerCertVerified::assertion()),
+      Some(known) => self.refuse(format!("The certificate of {} has changed since it was first trusted.\n  trusted:   {}\n  presented: {}\nThis could mean someone is intercepting the connection. If the server got a new certificate on purpose, remove its line from {}", self.server, known, seen, known_servers_path().display())),
+      None => {
+        let accepted = PROMPT.get().map(|prompt| prompt(&self.server, &seen)).unwrap_or(false);
+        if !accepted {
+          return self.refuse(format!("The certificate of {} is not signed by a trusted CA and has not been trusted before.\n  fingerprint: {}\nSet ca_cert or pinned_fingerprint in the config, or run log from a terminal to trust it", self.server, seen))
+        }
+        remember(&self.server, &seen).map_err(|e| rustls::Error::General(format!("Could not save {}: {}", known_servers_path().display(), e)))?;
+        Ok(ServerCertVerified::assertion())
+      }
+    }
+  }
+}
+
+// TLS settings for talking to config.server, and where the reason for a refused certificate ends up
+pub fn client_tls(config: &ClientConfig) -> Result<(rustls::ClientConfig, Arc<Mutex<Option<String>>>)> {
+
+  let mut roots = RootCertStore::empty();
+  if let Ok(certs) = rustls_native_certs::load_native_certs() {
+    for cert in certs {
+      let _ = roots.add(&Certificate(cert.0));
+    }
+  }
+  if let Some(ca_cert) = &config.ca_cert {
+    for cert in read_certs(ca_cert, "ca_cert")? {
+      roots.add(&Certificate(cert)).map_err(|e| LogError::new(ErrorKind::Usage, &format!("Bad certificate in ca_cert {}: {}", ca_cert.display(), e)))?;
+    }
+  }
+
+  let problem = Arc::new(Mutex::new(None));
+  let verifier = ServerVerifier {
+    server: config.server.to_string(),
+    webpki: WebPkiVerifier::new(roots, None),
+    ca_cert: config.ca_cert.is_some(),
+    pinned: config.pinned_fingerprint.to_owned(),
+    problem: problem.clone()
+  };
+
+  let builder = rustls::ClientConfig::builder()
+    .with_safe_defaults()
+    .with_custom_certificate_verifier(Arc::new(verifier));
+
+  let tls = match (&config.client_cert, &config.client_key) {
+    (Some(cert), Some(key)) => {
+      let certs = read_certs(cert, "client_cert")?.into_iter().map(Certificate).collect();
+      builder.with_client_auth_cert(certs, PrivateKey(read_key(key)?)).map_err(|e| LogError::new(ErrorKind::Usage, &format!("Could not use client_cert {}: {}", cert.display(), e)))?
+    },
+    (None, None) => builder.with_no_client_auth(),
+    _ => return Err(LogError::new(ErrorKind::Usage, "client_cert and client_key have to be set together"))
+  };
+
+  Ok((tls, problem))
+}
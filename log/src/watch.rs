@@ -0,0 +1,110 @@
+use std::{fs, io};
+use std::path::Path;
+use serde_json::Value;
+
+// Watch values read on this machine, for encrypted collections where the server never sees the
+// files. Same rules as the server: a variable is what follows its name on a line of the file, the
+// last line it is on wins, and every block of thermo output after a "Step" header becomes
+// thermo_data_0, thermo_data_1 and so on. Dumps are left out.
+
+// Returns the values as a json object. The text is built by hand so thermo columns stay in the
+// order LAMMPS printed them, which a serde_json Map would sort.
+pub fn watch_values(dir: &Path) -> io::Result<String> {
+
+  let mut values: Vec<(String, String)> = Vec::new();
+  let mut set = |name: &str, value: String| match values.iter_mut().find(|(k, _)| k == name) {
+    Some(entry) => entry.1 = value,
+    None => values.push((name.to_string(), value))
+  };
+
+  let mut watch_files: Vec<String> = fs::read_dir(dir)?.filter_map(|x| x.ok()).map(|x| x.file_name().to_string_lossy().to_string()).filter(|x| x.contains("watch")).collect();
+  watch_files.sort();
+
+  for watch_file in watch_files {
+    let schema: Value = serde_json::from_str(&fs::read_to_string(dir.join(&watch_file))?)?;
+
+    for (file, watched) in schema.as_object().into_iter().flatten() {
+      if file.contains("dump") {
+        continue;
+      }
+      let contents = match fs::read(dir.join(file)) {
+        Ok(contents) => String::from_utf8_lossy(&contents).to_string(),
+        Err(_) => continue
+      };
+
+      for (name, variable) in watched.get("variables").and_then(|x| x.as_object()).into_iter().flatten() {
+        match variable.get("type").and_then(|x| x.as_str()).unwrap_or("") {
+          "thermo_log" => {
+            for (i, block) in thermo_blocks(&contents).into_iter().enumerate() {
+              let columns: Vec<(String, String)> = block.into_iter().map(|(k, v)| (k, Value::from(v).to_string())).collect();
+              set(&format!("thermo_data_{}", i), object(&columns));
+            }
+          },
+          var_type => {
+            for line in contents.lines() {
+              let line: Vec<&str> = line.split_whitespace().collect();
+              let rest = match line.iter().position(|x| x == name) {
+                Some(v) if v + 1 < line.len() => &line[v + 1..],
+                _ => continue
+              };
+              let value = match var_type {
+                "int" => rest[0].parse::<i64>().ok().map(Value::from),
+                "float" => rest[0].parse::<f64>().ok().map(Value::from),
+                "long_string" => Some(Value::from(rest.join(" "))),
+                "string" => Some(Value::from(rest[0])),
+                "keywords" => {
+                  set("keywords", Value::from(rest.to_vec()).to_string());
+                  continue;
+                },
+                _ => None
+              };
+              if let Some(value) = value {
+                set(name, value.to_string());
+              }
+            }
+          }
+        };
+      }
+    }
+  }
+
+  Ok(object(&values))
+}
+
+// Columns of each block of thermo output. A block ends at the first line that doesn't start
+// with a number, and one still open at the end of the file is dropped like on the server.
+fn thermo_blocks(contents: &str) -> Vec<Vec<(String, Vec<f64>)>> {
+
+  let mut blocks = Vec::new();
+  let mut block: Option<Vec<(String, Vec<f64>)>> = None;
+
+  for l in contents.lines() {
+    let line: Vec<&str> = l.split_whitespace().collect();
+
+    if let Some(columns) = block.as_mut() {
+      match line.first().map(|x| x.parse::<f64>()) {
+        Some(Ok(_)) => {
+          for (i, s) in line.iter().enumerate().take(columns.len()) {
+            if let Ok(v) = s.parse::<f64>() {
+              columns[i].1.push(v);
+            }
+          }
+          continue;
+        },
+        _ => blocks.push(block.take().unwrap())
+      };
+    }
+
+    if l.contains("Step") {
+      block = Some(line.iter().map(|x| (x.to_string(), Vec::new())).collect());
+    }
+  }
+
+  blocks
+}
+
+// json object from already encoded values, keeping their order
+fn object(entries: &[(String, String)]) -> String {
+  let entries: Vec<String> = entries.iter().map(|(k, v)| format!("{}:{}", Value::from(k.as_str()), v)).collect();
+  format!("{{{}}}", entries.join(","))
+}
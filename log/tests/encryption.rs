@@ -0,0 +1,82 @@
+use std::{env, fs};
+use std::io::Read;
+
+use log::codec::{self, Codec};
+use log::crypt;
+use log::snapshot::{Snapshot, ENCRYPTED_ARCHIVE, INDEX_FILES};
+
+const WATCH: &str = r#"{
+  "log.lammps": {
+    "upload": 1,
+    "variables": {
+      "timestep": {"type": "float"},
+      "thermo_data": {"type": "thermo_log"}
+    }
+  }
+}"#;
+
+const LOG_LAMMPS: &str = "LAMMPS (secret build)
+timestep 0.003
+   Step          Temp          E_pair
+         0   1.0           -3.25
+       100   1.5           -3.5
+Loop time of 0.5 on 4 procs for 100 steps
+";
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+  haystack.windows(needle.len()).any(|x| x == needle)
+}
+
+// The plaintext part of an encrypted upload is all the server gets to read. It may not hold
+// the bytes of any tracked file, the watch file included, and REV may not name them
+#[test]
+fn encrypted_upload_sends_no_tracked_file_in_plaintext() {
+
+  let dir = env::temp_dir().join(format!("log_encryption_test_{}", std::process::id()));
+  let _ = fs::remove_dir_all(&dir);
+  fs::create_dir_all(&dir).unwrap();
+  fs::write(dir.join("in.crack"), "# confidential potential settings\nvariable strain equal 0.0125\nrun 100\n").unwrap();
+  fs::write(dir.join("log.lammps"), LOG_LAMMPS).unwrap();
+  fs::write(dir.join("watch"), WATCH).unwrap();
+  fs::write(dir.join("data.crack"), [0u8, 159, 146, 150, 7, 7, 7, 255, 1, 2, 3, 4]).unwrap();
+
+  let snapshot = Snapshot::take(&dir, "in., log., data., watch", Some("crack")).unwrap();
+  let rev = snapshot.new_rev(None);
+  let full = snapshot.archive(Some(&rev), (Codec::None, None)).unwrap();
+  let upload = snapshot.index_archive(&rev, (Codec::None, None), &crypt::encrypt(b"test key", &full).unwrap()).unwrap();
+
+  let tar = codec::decompress(&upload).unwrap();
+  let mut entries: Vec<(String, Vec<u8>)> = Vec::new();
+  for entry in tar::Archive::new(tar.as_slice()).entries().unwrap() {
+    let mut entry = entry.unwrap();
+    let mut contents = Vec::new();
+    entry.read_to_end(&mut contents).unwrap();
+    entries.push((entry.path().unwrap().to_string_lossy().to_string(), contents));
+  }
+
+  let names: Vec<&str> = entries.iter().map(|x| x.0.as_str()).collect();
+  assert_eq!(names, INDEX_FILES);
+
+  let tracked: Vec<&String> = snapshot.current.keys().filter(|x| *x != "id").collect();
+  assert_eq!(tracked.len(), 4);
+  let plaintext: Vec<u8> = entries.iter().filter(|x| x.0 != ENCRYPTED_ARCHIVE).flat_map(|x| x.1.clone()).collect();
+  for name in tracked {
+    assert!(!contains(&upload, &fs::read(dir.join(name)).unwrap()), "{} is in the upload unencrypted", name);
+    assert!(!contains(&plaintext, name.as_bytes()), "{} is named in the plaintext", name);
+  }
+
+  // REV keeps only the ids, and the watch values were read out here
+  let rev_sent = String::from_utf8(entries[0].1.clone()).unwrap();
+  assert_eq!(rev_sent.lines().count(), 2);
+  assert!(rev_sent.starts_with(&format!("id : {}\nparent_id : *\n", snapshot.id())));
+
+  let values: serde_json::Value = serde_json::from_slice(&entries[1].1).unwrap();
+  assert_eq!(values["timestep"], 0.003);
+  assert_eq!(values["thermo_data_0"]["Step"], serde_json::json!([0.0, 100.0]));
+  assert_eq!(values["thermo_data_0"]["E_pair"], serde_json::json!([-3.25, -3.5]));
+
+  // and everything is still there for whoever has the key
+  assert_eq!(crypt::decrypt(b"test key", &entries[2].1).unwrap(), full);
+
+  fs::remove_dir_all(&dir).unwrap();
+}
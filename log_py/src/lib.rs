@@ -0,0 +1,297 @@
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+use pyo3::prelude::*;
+use pyo3::create_exception;
+use pyo3::exceptions::PyException;
+use pyo3::types::{PyBytes, PyDict, PyList};
+use numpy::PyArray1;
+use serde_json::{Map, Value};
+
+use log::{ClientConfig, ErrorKind, LoggerClient, UploadOptions};
+use log::client::read_key;
+use log::error::LogError as RustLogError;
+
+// Python module built on the log client library. Analysts get uploads, history, searches and
+// thermo data through the same API key auth that `log` uses, so nobody needs database credentials.
+//
+//   import rust_logger
+//   client = rust_logger.Client()
+//   df = client.thermo_frame("crack:5d1c3e0a9b2f7a41")
+
+// environment variable and file the API key is looked for in when it isn't passed in
+const KEY_ENV: &str = "LOG_API_KEY";
+const KEY_FILE: &str = ".log/api_key";
+
+create_exception!(rust_logger, LogError, PyException);
+create_exception!(rust_logger, UsageError, LogError);
+create_exception!(rust_logger, NoChangesError, LogError);
+create_exception!(rust_logger, ParentMissingError, LogError);
+create_exception!(rust_logger, AuthError, LogError);
+create_exception!(rust_logger, NetworkError, LogError);
+create_exception!(rust_logger, ServerRejectedError, LogError);
+create_exception!(rust_logger, UnverifiedError, LogError);
+create_exception!(rust_logger, HookFailedError, LogError);
+create_exception!(rust_logger, MismatchError, LogError);
+
+fn py_err(err: RustLogError) -> PyErr {
+  match err.kind {
+    ErrorKind::Usage => UsageError::new_err(err.message),
+    ErrorKind::NoChanges => NoChangesError::new_err(err.message),
+    ErrorKind::ParentMissing => ParentMissingError::new_err(err.message),
+    ErrorKind::Auth => AuthError::new_err(err.message),
+    ErrorKind::Network => NetworkError::new_err(err.message),
+    ErrorKind::ServerRejected => ServerRejectedError::new_err(err.message),
+    ErrorKind::Unverified => UnverifiedError::new_err(err.message),
+    ErrorKind::HookFailed => HookFailedError::new_err(err.message),
+    ErrorKind::Mismatch => MismatchError::new_err(err.message),
+    ErrorKind::Other => LogError::new_err(err.message)
+  }
+}
+
+// json goes through python's own json module, which gives plain dicts and lists
+fn to_py<'py>(py: Python<'py>, value: &Value) -> PyResult<Bound<'py, PyAny>> {
+  py.import("json")?.call_method1("loads", (value.to_string(),))
+}
+
+fn from_py(py: Python<'_>, value: &Bound<'_, PyAny>) -> PyResult<Value> {
+  let json_string: String = py.import("json")?.call_method1("dumps", (value,))?.extract()?;
+  serde_json::from_str(&json_string).map_err(|e| UsageError::new_err(format!("Could not convert to json: {}", e)))
+}
+
+// Key file uses the same "host : key" lines as the credential file `log` keeps in /etc.
+// Like ssh keys, it is ignored unless only its owner can read it.
+fn find_key(host: &str) -> PyResult<String> {
+
+  if let Ok(key) = std::env::var(KEY_ENV) {
+    if !key.is_empty() {
+      return Ok(key)
+    }
+  }
+
+  let key_file = home::home_dir().unwrap_or_default().join(KEY_FILE);
+  if key_file.exists() {
+    let mode = fs::metadata(&key_file)?.permissions().mode();
+    if mode & 0o077 != 0 {
+      return Err(AuthError::new_err(format!("{} can be read by other users. Run 'chmod 600 {}' first", key_file.display(), key_file.display())))
+    }
+    if let Some(key) = read_key(&key_file, host).map_err(py_err)? {
+      return Ok(key)
+    }
+  }
+
+  Err(AuthError::new_err(format!("No API key for {}. Pass api_key=, set {} or add a '{} : <key>' line to {}", host, KEY_ENV, host, key_file.display())))
+}
+
+#[pyclass]
+struct Client {
+  client: LoggerClient
+}
+
+#[pymethods]
+impl Client {
+
+  // Reads ~/.log/config unless a server and username are given directly
+  #[new]
+  #[pyo3(signature = (config_path=None, server=None, username=None, api_key=None, tracked_files=None, passphrase=None))]
+  fn new(config_path: Option<PathBuf>, server: Option<String>, username: Option<String>, api_key: Option<String>, tracked_files: Option<String>, passphrase: Option<String>) -> PyResult<Client> {
+
+    let mut config = match (&config_path, &server, &username) {
+      (None, Some(server), Some(username)) => ClientConfig {
+        server: server.to_string(),
+        username: username.to_string(),
+        tracked_files: String::new(),
+        inline_limit: None,
+        codec: log::Codec::default(),
+        compression_level: None,
+        encrypt_collections: Vec::new(),
+        encryption_key: None,
+        signing_key: log::signing::default_key_path(),
+        ca_cert: None,
+        pinned_fingerprint: None,
+        client_cert: None,
+        client_key: None,
+        collection: None,
+        hooks: None,
+        hooks_off: false,
+        sources: std::collections::HashMap::new()
+      },
+      _ => ClientConfig::from_file(&config_path.unwrap_or(ClientConfig::default_path())).map_err(py_err)?
+    };
+
+    if let Some(s) = server {
+      config.server = s;
+    }
+    if let Some(u) = username {
+      config.username = u;
+    }
+    if let Some(t) = tracked_files {
+      config.tracked_files = t;
+    }
+
+    // a client certificate in the config can stand in for the key
+    let key = match api_key {
+      Some(k) => k,
+      None if config.client_cert.is_some() => find_key(config.host()).unwrap_or_default(),
+      None => find_key(config.host())?
+    };
+
+    // for encrypted collections when the config has no encryption_key file
+    let mut client = LoggerClient::new(config, &key);
+    if let Some(p) = passphrase {
+      client.set_passphrase(&p);
+    }
+
+    Ok(Client { client })
+  }
+
+  fn __repr__(&self) -> String {
+    format!("Client(server='{}', username='{}')", self.client.config().server, self.client.config().username)
+  }
+
+  // How a directory compares to its REV file and the server
+  #[pyo3(signature = (path=".", collection=None))]
+  fn status<'py>(&self, py: Python<'py>, path: &str, collection: Option<String>) -> PyResult<Bound<'py, PyDict>> {
+
+    let status = py.detach(|| self.client.status(Path::new(path), collection.as_deref())).map_err(py_err)?;
+
+    let result = PyDict::new(py);
+    result.set_item("dir", status.dir.display().to_string())?;
+    result.set_item("collection", status.collection)?;
+    result.set_item("id", status.id)?;
+    result.set_item("record_id", status.record_id)?;
+    result.set_item("parent_id", status.parent_id)?;
+    result.set_item("upload_name", status.upload_name)?;
+    result.set_item("needs_update", status.needs_update)?;
+    result.set_item("changed_files", status.changed_files)?;
+    Ok(result)
+  }
+
+  // Uploads a directory if it changed since its REV file was written. Raises NoChangesError if not.
+  #[pyo3(signature = (path=".", collection=None, name=None, force=false, metadata=None))]
+  fn upload<'py>(&self, py: Python<'py>, path: &str, collection: Option<String>, name: Option<String>, force: bool, metadata: Option<Bound<'py, PyDict>>) -> PyResult<Bound<'py, PyDict>> {
+
+    let metadata: Map<String, Value> = match metadata {
+      Some(m) => match from_py(py, m.as_any())? {
+        Value::Object(obj) => obj,
+        _ => Map::new()
+      },
+      None => Map::new()
+    };
+
+    // .log/hooks are for the command line, scripts can do their own checks before calling this
+    let options = UploadOptions { collection, name, force, metadata, hooks: false, command: None, parent_id: None, fork: false };
+    let upload = py.detach(|| self.client.upload(Path::new(path), &options)).map_err(py_err)?;
+
+    let result = PyDict::new(py);
+    result.set_item("id", upload.id)?;
+    result.set_item("parent_id", upload.parent_id)?;
+    result.set_item("upload_name", upload.upload_name)?;
+    result.set_item("changed_files", upload.changed_files)?;
+    result.set_item("server_message", upload.server_message)?;
+    Ok(result)
+  }
+
+  // Downloads an upload by id into path. Returns the name it was uploaded under.
+  fn checkout(&self, py: Python<'_>, id: &str, path: &str) -> PyResult<String> {
+    py.detach(|| self.client.checkout(id, Path::new(path))).map_err(py_err)
+  }
+
+  // The contents of one file in an upload, including files the server stores by reference
+  fn fetch<'py>(&self, py: Python<'py>, id: &str, path: &str) -> PyResult<Bound<'py, PyBytes>> {
+    let contents = py.detach(|| self.client.fetch(id, path)).map_err(py_err)?;
+    Ok(PyBytes::new(py, &contents))
+  }
+
+  // The upload and all of its parents, newest first
+  fn history<'py>(&self, py: Python<'py>, id: &str) -> PyResult<Bound<'py, PyList>> {
+
+    let entries = py.detach(|| self.client.history(id)).map_err(py_err)?;
+
+    let result = PyList::empty(py);
+    for e in entries {
+      let entry = PyDict::new(py);
+      entry.set_item("id", e.id)?;
+      entry.set_item("parent_id", e.parent_id)?;
+      entry.set_item("upload_name", e.upload_name)?;
+      entry.set_item("upload_time", e.upload_time)?;
+      result.append(entry)?;
+    }
+    Ok(result)
+  }
+
+  // Same query language as `log query`, e.g. "watch.timestep < 0.002 and keywords has sim"
+  #[pyo3(signature = (collection, query="", fields=""))]
+  fn query<'py>(&self, py: Python<'py>, collection: &str, query: &str, fields: &str) -> PyResult<Bound<'py, PyAny>> {
+    let results = py.detach(|| self.client.query(collection, query, fields)).map_err(py_err)?;
+    to_py(py, &Value::Array(results))
+  }
+
+  // Returns a pandas DataFrame, or writes a csv, parquet or jsonl file if a path is given
+  #[pyo3(signature = (collection, query="", fields="", path=None, format=None))]
+  fn export<'py>(&self, py: Python<'py>, collection: &str, query: &str, fields: &str, path: Option<PathBuf>, format: Option<String>) -> PyResult<Bound<'py, PyAny>> {
+
+    if let Some(path) = path {
+      // format defaults to the file extension
+      let format = format.or(path.extension().map(|x| x.to_string_lossy().to_string())).unwrap_or("csv".to_string());
+      let num_rows = py.detach(|| self.client.export(collection, query, fields, &format, &path)).map_err(py_err)?;
+      return Ok(num_rows.into_pyobject(py)?.into_any())
+    }
+
+    let rows = py.detach(|| self.client.export_rows(collection, query, fields)).map_err(py_err)?;
+    let rows = to_py(py, &Value::Array(rows.into_iter().map(Value::Object).collect()))?;
+    py.import("pandas")?.getattr("DataFrame")?.call_method1("from_records", (rows,))
+  }
+
+  // One thermo run of an upload as a dict of numpy arrays, keyed by thermo column
+  #[pyo3(signature = (id, run=0))]
+  fn thermo<'py>(&self, py: Python<'py>, id: &str, run: usize) -> PyResult<Bound<'py, PyDict>> {
+
+    let collection = id.split(':').next().unwrap();
+    let field = format!("watch.thermo_data_{}", run);
+    let filter = format!("id == \"{}\"", id.replace('"', ""));
+
+    let results = py.detach(|| self.client.query(collection, &filter, &field)).map_err(py_err)?;
+    let upload = results.first().ok_or_else(|| LogError::new_err(format!("No upload with id {}", id)))?;
+    let columns = log::table::lookup(upload, &field).and_then(|x| x.as_object()).ok_or_else(|| LogError::new_err(format!("{} has no thermo run {}", id, run)))?;
+
+    // Step first, the rest in the order they come back
+    let mut names: Vec<&String> = columns.keys().collect();
+    names.sort_by_key(|x| x.as_str() != "Step");
+
+    let result = PyDict::new(py);
+    for name in names {
+      let values: Vec<f64> = match columns.get(name) {
+        Some(Value::Array(a)) => a.iter().map(|x| x.as_f64().unwrap_or(f64::NAN)).collect(),
+        _ => continue
+      };
+      result.set_item(name, PyArray1::from_vec(py, values))?;
+    }
+    Ok(result)
+  }
+
+  // Same as thermo() but as a pandas DataFrame
+  #[pyo3(signature = (id, run=0))]
+  fn thermo_frame<'py>(&self, py: Python<'py>, id: &str, run: usize) -> PyResult<Bound<'py, PyAny>> {
+    let columns = self.thermo(py, id, run)?;
+    py.import("pandas")?.getattr("DataFrame")?.call1((columns,))
+  }
+}
+
+#[pymodule]
+fn rust_logger(m: &Bound<'_, PyModule>) -> PyResult<()> {
+  let py = m.py();
+  m.add_class::<Client>()?;
+  m.add("LogError", py.get_type::<LogError>())?;
+  m.add("UsageError", py.get_type::<UsageError>())?;
+  m.add("NoChangesError", py.get_type::<NoChangesError>())?;
+  m.add("ParentMissingError", py.get_type::<ParentMissingError>())?;
+  m.add("AuthError", py.get_type::<AuthError>())?;
+  m.add("NetworkError", py.get_type::<NetworkError>())?;
+  m.add("ServerRejectedError", py.get_type::<ServerRejectedError>())?;
+  m.add("UnverifiedError", py.get_type::<UnverifiedError>())?;
+  m.add("HookFailedError", py.get_type::<HookFailedError>())?;
+  m.add("MismatchError", py.get_type::<MismatchError>())?;
+  Ok(())
+}
@@ -0,0 +1,252 @@
+use std::{fs::File, io, io::BufRead, io::BufReader, io::Read, io::Write};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use mongodb::bson::{Bson, Document, doc};
+use sha2::{Sha256, Digest};
+use tar::Archive;
+
+// Files too big to put inside of a Mongo document are left in the stored archive.
+// The document gets a small reference in their place, and the file is read back out of the
+// archive whenever someone asks for it.
+
+// defaults used when the server config doesn't set inline_limit / inline_total_limit
+pub const DEFAULT_INLINE_LIMIT: u64 = 4 * 1024 * 1024;
+pub const DEFAULT_INLINE_TOTAL_LIMIT: u64 = 12 * 1024 * 1024; // Mongo documents can't be more than 16 MB
+
+// Uploads from collections the client encrypts hold the real archive under this name.
+// Next to it in plaintext are only the ids from REV and the watch values the client read out.
+pub const ENCRYPTED_ARCHIVE: &str = ".log_encrypted";
+pub const WATCH_VALUES_FILE: &str = ".log_watch_values";
+pub const INDEX_FILES: [&str; 3] = ["REV", WATCH_VALUES_FILE, ENCRYPTED_ARCHIVE];
+
+// Uploads are gzip, zstd or plain tar, whatever the client's codec setting is. The codec is
+// stored with the upload, but archives are read by looking at their first bytes so that uploads
+// from before the codec was recorded still work.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+  Gzip,
+  Zstd,
+  None
+}
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+impl Codec {
+
+  // from the codec header, older clients don't send one and always use gzip
+  pub fn from_name(name: &str) -> Option<Codec> {
+    match name {
+      "" | "gzip" => Some(Codec::Gzip),
+      "zstd" => Some(Codec::Zstd),
+      "none" => Some(Codec::None),
+      _ => None
+    }
+  }
+
+  pub fn name(&self) -> &'static str {
+    match self {
+      Codec::Gzip => "gzip",
+      Codec::Zstd => "zstd",
+      Codec::None => "none"
+    }
+  }
+
+  // used for upload_path and download filenames
+  pub fn extension(&self) -> &'static str {
+    match self {
+      Codec::Gzip => ".tar.gz",
+      Codec::Zstd => ".tar.zst",
+      Codec::None => ".tar"
+    }
+  }
+
+  pub fn detect(data: &[u8]) -> Codec {
+    if data.starts_with(GZIP_MAGIC) {
+      Codec::Gzip
+    } else if data.starts_with(ZSTD_MAGIC) {
+      Codec::Zstd
+    } else {
+      Codec::None
+    }
+  }
+}
+
+// Decompresses an archive of any codec as it is read
+pub fn decoder<'a, R: Read + 'a>(compressed: R) -> io::Result<Box<dyn Read + 'a>> {
+
+  let mut reader = BufReader::new(compressed);
+  let codec = Codec::detect(reader.fill_buf()?);
+
+  Ok(match codec {
+    Codec::Gzip => Box::new(GzDecoder::new(reader)),
+    Codec::Zstd => Box::new(zstd::Decoder::with_buffer(reader)?),
+    Codec::None => Box::new(reader)
+  })
+}
+
+fn compress(codec: Codec, data: &[u8]) -> io::Result<Vec<u8>> {
+  match codec {
+    Codec::Gzip => {
+      let mut encoder = GzEncoder::new(Vec::new(), Compression::fast());
+      encoder.write_all(data)?;
+      encoder.finish()
+    },
+    Codec::Zstd => zstd::encode_all(data, 0),
+    Codec::None => Ok(data.to_vec())
+  }
+}
+
+pub fn reference_doc(archive_path: &str, contents: &[u8]) -> Document {
+  doc! {
+    "reference": true,
+    "archive_path": archive_path,
+    "size": contents.len() as i64,
+    "sha256": hex::encode(Sha256::digest(contents))
+  }
+}
+
+pub fn is_reference(value: &Bson) -> bool {
+  match value.as_document() {
+    Some(d) => d.get_bool("reference").unwrap_or(false) && d.get_str("archive_path").is_ok(),
+    None => false
+  }
+}
+
+// Size of a file stored inline, as text or binary
+pub fn inline_size(value: &Bson) -> Option<u64> {
+  match value {
+    Bson::String(s) => Some(s.len() as u64),
+    Bson::Binary(b) => Some(b.bytes.len() as u64),
+    _ => None
+  }
+}
+
+fn inline_bytes(value: &Bson) -> Option<&[u8]> {
+  match value {
+    Bson::String(s) => Some(s.as_bytes()),
+    Bson::Binary(b) => Some(&b.bytes),
+    _ => None
+  }
+}
+
+// sha256 of a file as it is stored in the document, inline or by reference
+pub fn stored_hash(value: &Bson) -> Option<String> {
+  match is_reference(value) {
+    true => value.as_document().unwrap().get_str("sha256").ok().map(|x| x.to_string()),
+    false => inline_bytes(value).map(|x| hex::encode(Sha256::digest(x)))
+  }
+}
+
+// Swaps files for references so that no single file is over limit and all of them together
+// stay under total_limit. The biggest files go first. REV and the watch file always stay.
+pub fn apply_inline_limits(files: &mut Document, limit: u64, total_limit: u64) {
+
+  let mut sizes: Vec<(String, u64)> = files.iter()
+    .filter(|(k, _)| *k != "REV" && !k.contains("watch"))
+    .filter_map(|(k, v)| inline_size(v).map(|s| (k.to_string(), s)))
+    .collect();
+  sizes.sort_by_key(|x| std::cmp::Reverse(x.1));
+
+  let mut total: u64 = files.iter().filter_map(|(_, v)| inline_size(v)).sum();
+
+  for (name, size) in sizes {
+    if size <= limit && total <= total_limit {
+      break;
+    }
+
+    let reference = reference_doc(&name, inline_bytes(files.get(&name).unwrap()).unwrap());
+    println!("Storing {} ({} bytes) by reference", name, size);
+    files.insert(name, reference);
+    total -= size;
+  }
+}
+
+// Reads a single file out of a stored upload
+pub fn read_file(upload_path: &str, archive_path: &str) -> io::Result<Vec<u8>> {
+  read_entry(File::open(upload_path)?, archive_path)
+}
+
+// sha256 of every file in a stored upload
+pub fn file_hashes(upload_path: &str) -> io::Result<Vec<(String, String)>> {
+
+  let mut archive = Archive::new(decoder(File::open(upload_path)?)?);
+  let mut hashes: Vec<(String, String)> = Vec::new();
+
+  for file in archive.entries()? {
+    let mut file = file?;
+    if !file.header().entry_type().is_file() {
+      continue;
+    }
+
+    let name = file.path()?.to_str().unwrap_or("").to_string();
+    let mut contents: Vec<u8> = Vec::new();
+    file.read_to_end(&mut contents)?;
+    hashes.push((name, hex::encode(Sha256::digest(&contents))));
+  }
+
+  Ok(hashes)
+}
+
+// Name and size of every file in a stored upload, in archive order
+pub fn list_files(upload_path: &str) -> io::Result<Vec<(String, u64)>> {
+
+  let mut archive = Archive::new(decoder(File::open(upload_path)?)?);
+  let mut files: Vec<(String, u64)> = Vec::new();
+
+  for file in archive.entries()? {
+    let file = file?;
+    if file.header().entry_type().is_file() {
+      files.push((file.path()?.to_str().unwrap_or("").to_string(), file.size()));
+    }
+  }
+
+  Ok(files)
+}
+
+// Same as read_file for an upload that is still in memory
+pub fn read_entry<R: Read>(compressed: R, archive_path: &str) -> io::Result<Vec<u8>> {
+
+  let mut archive = Archive::new(decoder(compressed)?);
+
+  for file in archive.entries()? {
+    let mut file = file?;
+    if file.path()?.to_str() == Some(archive_path) {
+      let mut contents: Vec<u8> = Vec::new();
+      file.read_to_end(&mut contents)?;
+      return Ok(contents)
+    }
+  }
+
+  Err(io::Error::new(io::ErrorKind::NotFound, format!("{} is not in the stored archive", archive_path)))
+}
+
+// Rewrites a stored upload with only the files in keep. Used to drop anything but the INDEX_FILES
+// from an encrypted upload.
+// The rewritten archive keeps the codec it was uploaded with.
+pub fn keep_only(upload_path: &str, keep: &[String]) -> io::Result<()> {
+
+  let mut magic = [0u8; 4];
+  let read = File::open(upload_path)?.read(&mut magic)?;
+  let codec = Codec::detect(&magic[..read]);
+
+  let mut builder = tar::Builder::new(Vec::new());
+  {
+    let mut archive = Archive::new(decoder(File::open(upload_path)?)?);
+    for file in archive.entries()? {
+      let mut file = file?;
+      let name = file.path()?.to_str().unwrap_or("").to_string();
+      if keep.contains(&name) {
+        let mut header = file.header().clone();
+        builder.append_data(&mut header, &name, &mut file)?;
+      }
+    }
+  }
+
+  let compressed = compress(codec, &builder.into_inner()?)?;
+
+  let mut outputfile = File::create(upload_path)?;
+  outputfile.write_all(&compressed)?;
+  outputfile.flush()
+}
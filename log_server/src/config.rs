@@ -11,13 +11,20 @@ pub struct Config {
 
 impl Config {
 
-  const ALLOWED_SERVER_OPTIONS: [&'static str; 6] = [
+  const ALLOWED_SERVER_OPTIONS: [&'static str; 13] = [
       "server_port",
       "cert_path", 
       "key_path", 
       "data_path", 
       "database",
-      "css"
+      "css",
+      "inline_limit", // files bigger than this many bytes are stored by reference
+      "inline_total_limit", // same but for all files of an upload together
+      "require_signatures", // "true" to refuse uploads that are not signed
+      "client_ca_path", // CA whose client certificates are accepted in place of API keys
+      "require_client_cert", // "true" to refuse connections without a client certificate
+      "client_keys_path", // file the server keeps the database keys of certificate users in
+      "fork_policy" // allow, warn, reject or reject_all uploads whose parent already has a child, like "warn, crack=reject"
   ];
 
   pub fn new() -> Config {
@@ -1,4 +1,4 @@
-use hyper::{Request, Body};
+use hyper::{Request, Body, HeaderMap};
 use mongodb::{bson::{Document}, Client, bson::doc, options::FindOptions};
 
 
@@ -9,50 +9,123 @@ use mongodb::{bson::{Document}, Client, bson::doc, options::FindOptions};
 pub struct Connection {
   pub username: String,
   pub password: String,
+  pub client_cert: bool, // signed in with a client certificate, using the key the server keeps for them
   pub filename: String,
   pub collection: String,
   pub filehash: String,
+  pub fields: String,
+  pub metadata: String,
+  pub inline_limit: String,
+  pub codec: String,
+  pub path: String,
+  pub signature: String,
+  pub public_key: String,
+  pub target: String,
+  pub command: String,
+  pub hostname: String,
+  pub client_version: String,
+  pub uploaded_by: String,
+  pub fork: String,
+  pub fingerprint: String,
   pub err: Option<String>
 }
 
+// Header values can only be visible ascii. Anything else is recorded in err instead of panicking
+fn header(headers: &HeaderMap, name: &str, err: &mut Option<String>) -> Option<String> {
+  match headers.get(name)?.to_str() {
+    Ok(k) => Some(k.to_string()),
+    Err(_) => {
+      *err = Some(format!("The {} header has characters that are not allowed in a header", name));
+      None
+    }
+  }
+}
+
+// Free text like metadata, the command line and the hostname is sent hex encoded, since it can have anything in it
+fn hex_header(headers: &HeaderMap, name: &str, err: &mut Option<String>) -> Option<String> {
+  let value = header(headers, name, err)?;
+  match hex::decode(&value).ok().and_then(|x| String::from_utf8(x).ok()) {
+    Some(value) => Some(value),
+    None => {
+      *err = Some(format!("The {} header has to be hex encoded utf-8", name));
+      None
+    }
+  }
+}
+
 impl Connection {
   pub fn get_conn_info(req: &Request<Body>) -> Connection {
 
     let headers = req.headers();
 
-    let username = match headers.get("username") {
-      Some(k) => String::from(k.to_str().unwrap()),
-      None => String::from("admin")
-    };
+    // set for a header that can't be read, the request gets a 400 with it
+    let mut err = None;
 
-    let password = match headers.get("password") {
-      Some(k) => String::from(k.to_str().unwrap()),
-      None => String::new()
-    };
+    let username = header(headers, "username", &mut err).unwrap_or(String::from("admin"));
+    let password = header(headers, "password", &mut err).unwrap_or_default();
+    let filename = header(headers, "filename", &mut err).unwrap_or_default();
+    let filehash = header(headers, "filehash", &mut err).unwrap_or_default();
+    let collection = header(headers, "collection", &mut err).unwrap_or_default();
+    let fields = header(headers, "fields", &mut err).unwrap_or_default();
 
-    let filename = match headers.get("filename") {
-      Some(k) => String::from(k.to_str().unwrap()),
-      None => String::new()
-    };
+    // json object with extra information about an upload, like sweep axes
+    let metadata = hex_header(headers, "metadata", &mut err).unwrap_or_default();
 
-    let filehash = match headers.get("filehash") {
-      Some(k) => String::from(k.to_str().unwrap()),
-      None => String::new()
-    };
+    // lowest size in bytes of files that the client wants stored by reference
+    let inline_limit = header(headers, "inline_limit", &mut err).unwrap_or_default();
 
-    let collection = match headers.get("collection") {
-      Some(k) => String::from(k.to_str().unwrap()),
-      None => String::new()
-    };
+    // what the uploaded archive is compressed with, gzip if not given
+    let codec = header(headers, "codec", &mut err).unwrap_or_default();
+
+    // path of a single file inside of an upload
+    let path = header(headers, "path", &mut err).unwrap_or_default();
+
+    // ed25519 signature of the upload, or of a signing key being added, and the key it was made with, both hex encoded
+    let signature = header(headers, "signature", &mut err).unwrap_or_default();
+    let public_key = header(headers, "public_key", &mut err).unwrap_or_default();
+
+    // json list of the program and arguments that produced an upload, for `log reproduce`
+    let command = hex_header(headers, "command", &mut err).unwrap_or_default();
+
+    // machine and version of log an upload came from
+    let hostname = hex_header(headers, "hostname", &mut err).unwrap_or_default();
+    let client_version = header(headers, "client_version", &mut err).unwrap_or_default();
+
+    // only list uploads made by this user
+    let uploaded_by = header(headers, "uploaded_by", &mut err).unwrap_or_default();
+
+    // "intended" when the client makes siblings on purpose, like the runs of a sweep
+    let fork = header(headers, "fork", &mut err).unwrap_or_default();
+
+    // user an administrator is adding, rotating the key of, revoking or changing the signing keys of
+    let target = header(headers, "target", &mut err).unwrap_or_default();
+
+    // signing key an administrator is removing
+    let fingerprint = header(headers, "fingerprint", &mut err).unwrap_or_default();
 
     
     Connection {
+      client_cert: false,
       username,
       password,
       filename,
       collection,
       filehash,
-      err: None
+      fields,
+      metadata,
+      inline_limit,
+      codec,
+      path,
+      signature,
+      public_key,
+      target,
+      command,
+      hostname,
+      client_version,
+      uploaded_by,
+      fork,
+      fingerprint,
+      err
     }
     
     
@@ -65,8 +138,13 @@ impl Connection {
       true => Some(doc! {field.unwrap(): value.unwrap() }),
       false => None
     };
-    
-   
+
+    Connection::filter_db_query(client, filter, db, collection, return_fields, sort_field).await
+  }
+
+  // Same as simple_db_query but takes a full filter document, like the ones built by the filter module
+  pub async fn filter_db_query(client: &Client, filter: Option<Document>, db: &str, collection: &str, return_fields: Option<Document>, sort_field: Option<Document>) -> mongodb::Cursor<Document> {
+
     let db = client.database(db).collection::<Document>(collection);    
 
     let find_options = FindOptions::builder();
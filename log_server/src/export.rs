@@ -0,0 +1,92 @@
+use mongodb::bson::{Bson, Document};
+use serde_json::{Map, Value};
+
+// Builds the flat rows sent back by the /export endpoint.
+// Scalar fields give one row per upload. As soon as a thermo series is asked for
+// (anything under watch.thermo_data_N) we switch to one row per thermo step, with the
+// scalar fields repeated on every row so the output can go straight into a dataframe.
+
+
+pub fn is_series(field: &str) -> bool {
+  field.split('.').any(|x| x.starts_with("thermo_data_"))
+}
+
+// Follows a dotted path like "watch.timestep" down through nested documents
+fn lookup<'a>(doc: &'a Document, path: &str) -> Option<&'a Bson> {
+  let mut parts = path.split('.');
+  let mut curr = doc.get(parts.next()?)?;
+  for p in parts {
+    curr = curr.as_document()?.get(p)?;
+  }
+  Some(curr)
+}
+
+fn to_json(value: Option<&Bson>) -> Value {
+  match value {
+    Some(v) => v.clone().into_relaxed_extjson(),
+    None => Value::Null
+  }
+}
+
+// With no fields requested we export every plain watch value.
+// Thermo data and parsed dump files are documents so they get skipped.
+pub fn default_fields(doc: &Document) -> Vec<String> {
+  match doc.get_document("watch") {
+    Ok(watch) => watch.iter().filter(|(_, v)| v.as_document().is_none()).map(|(k, _)| format!("watch.{}", k)).collect(),
+    Err(_) => Vec::new()
+  }
+}
+
+pub fn rows(doc: &Document, fields: &[String]) -> Vec<Map<String, Value>> {
+
+  let fields: Vec<String> = match fields.is_empty() {
+    true => default_fields(doc),
+    false => fields.to_vec()
+  };
+
+  let mut base = Map::new();
+  for f in ["id", "upload_name", "upload_time"] {
+    base.insert(f.to_string(), to_json(doc.get(f)));
+  }
+
+  let (series, scalars): (Vec<&String>, Vec<&String>) = fields.iter().partition(|x| is_series(x));
+
+  for f in scalars {
+    base.insert(f.to_string(), to_json(lookup(doc, f)));
+  }
+
+  if series.is_empty() {
+    return vec![base]
+  }
+
+  // a series field is either a single column like watch.thermo_data_0.Temp
+  // or a whole thermo run like watch.thermo_data_0, which expands to all of its columns
+  let mut columns: Vec<(String, &bson::Array)> = Vec::new();
+  for f in series {
+    match lookup(doc, f) {
+      Some(Bson::Array(a)) => columns.push((f.to_string(), a)),
+      Some(Bson::Document(d)) => {
+        for (k, v) in d {
+          if let Bson::Array(a) = v {
+            columns.push((format!("{}.{}", f, k), a));
+          }
+        }
+      },
+      _ => ()
+    };
+  }
+
+  let num_steps = columns.iter().map(|(_, a)| a.len()).max().unwrap_or(0);
+  let mut all_rows = Vec::with_capacity(num_steps);
+
+  for step in 0..num_steps {
+    let mut row = base.clone();
+    row.insert("step_index".to_string(), Value::from(step));
+    for (name, values) in &columns {
+      row.insert(name.to_string(), to_json(values.get(step)));
+    }
+    all_rows.push(row);
+  }
+
+  all_rows
+}
@@ -0,0 +1,329 @@
+use mongodb::bson::{Document, Bson, doc};
+use chrono::TimeZone;
+
+// The filter module turns the small query language used by `log query` into a MongoDB filter.
+// Only whitelisted fields and a fixed set of operators are accepted, so nothing a client sends
+// can ever become a raw Mongo operator like $where.
+//
+// expression := condition (("and" | "or") condition)*     "and" binds tighter than "or"
+// condition  := "not" condition | "(" expression ")" | field operator value
+// operator   := < | <= | > | >= | == | != | has
+//
+// Example: watch.timestep < 0.002 and keywords has sim
+
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+  Word(String),
+  Quoted(String),
+  Op(String),
+  Open,
+  Close
+}
+
+const OPERATORS: [&str; 7] = ["<=", ">=", "==", "!=", "<", ">", "has"];
+
+// top level fields that can be searched on besides anything inside of the watch document
+const TOP_LEVEL_FIELDS: [&str; 10] = ["id", "parent_id", "upload_name", "upload_time", "keywords", "command", "uploaded_by", "upload_host", "client_version", "branch_label"];
+
+pub fn parse(query: &str) -> Result<Document, String> {
+
+  let tokens = tokenize(query)?;
+  if tokens.is_empty() {
+    return Ok(Document::new())
+  }
+
+  let mut pos = 0;
+  let filter = parse_or(&tokens, &mut pos)?;
+
+  if pos != tokens.len() {
+    return Err(format!("Unexpected '{}' in query", token_string(&tokens[pos])))
+  }
+
+  Ok(filter)
+}
+
+// Checks that a field name can be used in a query or projection.
+// "keywords" is a shortcut since that is where the watch keywords end up.
+pub fn check_field(field: &str) -> Result<String, String> {
+
+  let valid_chars = field.chars().all(|c| c.is_alphanumeric() || c == '_' || c == '.');
+  if field.is_empty() || !valid_chars || field.starts_with('.') || field.ends_with('.') || field.contains("..") {
+    return Err(format!("Invalid field name '{}'", field))
+  }
+
+  if field == "keywords" {
+    return Ok("watch.keywords".to_string())
+  }
+
+  // all of watch at once is only useful as a returned field, like for comparing two runs
+  if TOP_LEVEL_FIELDS.contains(&field) || field == "watch" || field.starts_with("watch.") {
+    Ok(field.to_string())
+  } else {
+    Err(format!("Cannot query on '{}'. Fields must be one of {:?} or start with 'watch.'", field, TOP_LEVEL_FIELDS))
+  }
+}
+
+fn token_string(token: &Token) -> String {
+  match token {
+    Token::Word(s) | Token::Op(s) => s.to_string(),
+    Token::Quoted(s) => format!("\"{}\"", s),
+    Token::Open => "(".to_string(),
+    Token::Close => ")".to_string()
+  }
+}
+
+fn tokenize(query: &str) -> Result<Vec<Token>, String> {
+
+  let mut tokens: Vec<Token> = Vec::new();
+  let mut chars = query.chars().peekable();
+
+  while let Some(c) = chars.next() {
+
+    if c.is_whitespace() { continue; }
+
+    match c {
+      '(' => tokens.push(Token::Open),
+      ')' => tokens.push(Token::Close),
+
+      '"' | '\'' => {
+        let mut s = String::new();
+        let mut closed = false;
+        for q in chars.by_ref() {
+          if q == c {
+            closed = true;
+            break;
+          }
+          s.push(q);
+        }
+        if !closed {
+          return Err("Unterminated string in query".to_string())
+        }
+        tokens.push(Token::Quoted(s));
+      },
+
+      '<' | '>' | '=' | '!' => {
+        let mut op = c.to_string();
+        if chars.peek() == Some(&'=') {
+          op.push(chars.next().unwrap());
+        }
+        if !OPERATORS.contains(&op.as_str()) {
+          return Err(format!("Unknown operator '{}'", op))
+        }
+        tokens.push(Token::Op(op));
+      },
+
+      _ => {
+        let mut word = c.to_string();
+        while let Some(&n) = chars.peek() {
+          if n.is_whitespace() || "()<>=!\"'".contains(n) { break; }
+          word.push(n);
+          chars.next();
+        }
+
+        if word == "has" {
+          tokens.push(Token::Op(word));
+        } else {
+          tokens.push(Token::Word(word));
+        }
+      }
+    };
+  }
+
+  Ok(tokens)
+}
+
+fn parse_or(tokens: &[Token], pos: &mut usize) -> Result<Document, String> {
+
+  let mut terms = vec![parse_and(tokens, pos)?];
+  while *pos < tokens.len() && tokens[*pos] == Token::Word("or".to_string()) {
+    *pos += 1;
+    terms.push(parse_and(tokens, pos)?);
+  }
+
+  match terms.len() {
+    1 => Ok(terms.remove(0)),
+    _ => Ok(doc! {"$or": terms})
+  }
+}
+
+fn parse_and(tokens: &[Token], pos: &mut usize) -> Result<Document, String> {
+
+  let mut terms = vec![parse_condition(tokens, pos)?];
+  while *pos < tokens.len() && tokens[*pos] == Token::Word("and".to_string()) {
+    *pos += 1;
+    terms.push(parse_condition(tokens, pos)?);
+  }
+
+  match terms.len() {
+    1 => Ok(terms.remove(0)),
+    _ => Ok(doc! {"$and": terms})
+  }
+}
+
+fn parse_condition(tokens: &[Token], pos: &mut usize) -> Result<Document, String> {
+
+  let token = match tokens.get(*pos) {
+    Some(t) => t,
+    None => return Err("Query ended unexpectedly".to_string())
+  };
+
+  if *token == Token::Open {
+    *pos += 1;
+    let inner = parse_or(tokens, pos)?;
+    if tokens.get(*pos) != Some(&Token::Close) {
+      return Err("Missing ')' in query".to_string())
+    }
+    *pos += 1;
+    return Ok(inner)
+  }
+
+  if *token == Token::Word("not".to_string()) {
+    *pos += 1;
+    let inner = parse_condition(tokens, pos)?;
+    return Ok(doc! {"$nor": [inner]})
+  }
+
+  // everything else must be a full "field op value" triple
+  let field = match token {
+    Token::Word(w) => check_field(w)?,
+    other => return Err(format!("Expected a field name but found '{}'", token_string(other)))
+  };
+
+  let op = match tokens.get(*pos + 1) {
+    Some(Token::Op(op)) => op.as_str(),
+    Some(other) => return Err(format!("Expected an operator after '{}' but found '{}'", field, token_string(other))),
+    None => return Err(format!("Missing operator after '{}'", field))
+  };
+
+  let value = match tokens.get(*pos + 2) {
+    Some(Token::Word(w)) => parse_value(&field, w, false)?,
+    Some(Token::Quoted(q)) => parse_value(&field, q, true)?,
+    Some(other) => return Err(format!("Expected a value after '{}' but found '{}'", op, token_string(other))),
+    None => return Err(format!("Missing value after '{} {}'", field, op))
+  };
+
+  *pos += 3;
+
+  let condition = match op {
+    "<" => doc! {field: {"$lt": value}},
+    "<=" => doc! {field: {"$lte": value}},
+    ">" => doc! {field: {"$gt": value}},
+    ">=" => doc! {field: {"$gte": value}},
+    "!=" => doc! {field: {"$ne": value}},
+    // equality on an array field in Mongo already means "contains"
+    _ => doc! {field: value}
+  };
+
+  Ok(condition)
+}
+
+// Values are numbers whenever they parse as one, unless they were quoted.
+// upload_time is stored as a date so it needs to be compared against one.
+fn parse_value(field: &str, value: &str, quoted: bool) -> Result<Bson, String> {
+
+  if field == "upload_time" {
+    let date = match chrono::DateTime::parse_from_rfc3339(value) {
+      Ok(d) => d.with_timezone(&chrono::Utc),
+      Err(_) => match chrono::NaiveDate::parse_from_str(value, "%Y-%m-%d") {
+        Ok(d) => chrono::Utc.from_utc_datetime(&d.and_hms_opt(0, 0, 0).unwrap()),
+        Err(_) => return Err(format!("Could not read '{}' as a date. Use YYYY-MM-DD or RFC 3339", value))
+      }
+    };
+    return Ok(Bson::DateTime(bson::DateTime::from_chrono(date)))
+  }
+
+  if !quoted {
+    if let Ok(i) = value.parse::<i64>() {
+      return Ok(Bson::Int64(i))
+    }
+    if let Ok(f) = value.parse::<f64>() {
+      return Ok(Bson::Double(f))
+    }
+  }
+
+  Ok(Bson::String(value.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn and_binds_tighter_than_or() {
+    let filter = parse("watch.a < 1 or watch.b > 2 and watch.c == 3").unwrap();
+    assert_eq!(filter, doc! {"$or": [
+      {"watch.a": {"$lt": 1i64}},
+      {"$and": [{"watch.b": {"$gt": 2i64}}, {"watch.c": 3i64}]}
+    ]});
+  }
+
+  #[test]
+  fn parentheses_and_not() {
+    let filter = parse("(watch.a < 1 or watch.b > 2) and not upload_name == run1").unwrap();
+    assert_eq!(filter, doc! {"$and": [
+      {"$or": [{"watch.a": {"$lt": 1i64}}, {"watch.b": {"$gt": 2i64}}]},
+      {"$nor": [{"upload_name": "run1"}]}
+    ]});
+  }
+
+  #[test]
+  fn quoted_values_stay_strings() {
+    assert_eq!(parse("watch.x == \"0.5\"").unwrap(), doc! {"watch.x": "0.5"});
+    assert_eq!(parse("watch.x == 0.5").unwrap(), doc! {"watch.x": 0.5});
+    assert_eq!(parse("command == 'lmp -in in.crack'").unwrap(), doc! {"command": "lmp -in in.crack"});
+    assert_eq!(parse("branch_label != \"main and or\"").unwrap(), doc! {"branch_label": {"$ne": "main and or"}});
+  }
+
+  #[test]
+  fn keywords_shortcut_and_dates() {
+    assert_eq!(parse("keywords has sim").unwrap(), doc! {"watch.keywords": "sim"});
+    let date = chrono::Utc.with_ymd_and_hms(2024, 3, 1, 0, 0, 0).unwrap();
+    assert_eq!(parse("upload_time >= 2024-03-01").unwrap(), doc! {"upload_time": {"$gte": bson::DateTime::from_chrono(date)}});
+  }
+
+  #[test]
+  fn only_whitelisted_fields() {
+    assert!(parse("$where == 1").is_err());
+    assert!(parse("watch.$where == 1").is_err());
+    assert!(parse("password == x").is_err());
+    assert!(parse("upload_path == x").is_err());
+    assert!(parse("watch..a == 1").is_err());
+    assert!(parse(".watch == 1").is_err());
+    assert!(check_field("watch").is_ok());
+    assert!(check_field("_id").is_err());
+  }
+
+  #[test]
+  fn operators_in_values_are_not_operators() {
+    // a value can look like a Mongo operator but only ever ends up as a plain value
+    assert_eq!(parse("upload_name == '$where'").unwrap(), doc! {"upload_name": "$where"});
+    assert_eq!(parse("upload_name == $gt").unwrap(), doc! {"upload_name": "$gt"});
+  }
+
+  #[test]
+  fn malformed_queries_are_errors() {
+    for query in [
+      "watch.a <",
+      "watch.a",
+      "watch.a 1",
+      "< 1",
+      "watch.a < 1 and",
+      "watch.a < 1 or or watch.b > 2",
+      "(watch.a < 1",
+      "watch.a < 1)",
+      "not",
+      "watch.a == \"open",
+      "watch.a =< 1",
+      "watch.a = 1",
+      "watch.a ! 1",
+      "watch.a < (",
+      "upload_time > yesterday",
+      "()",
+      "((((",
+    ] {
+      assert!(parse(query).is_err(), "'{}' should not parse", query);
+    }
+    assert_eq!(parse("   ").unwrap(), Document::new());
+  }
+}
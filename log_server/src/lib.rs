@@ -1,4 +1,8 @@
 pub mod processor;
 pub mod connection;
 pub mod config;
+pub mod filter;
+pub mod export;
+pub mod archive;
+pub mod provenance;
 extern crate utils;
\ No newline at end of file
@@ -18,6 +18,7 @@ use std::pin::Pin;
 use std::sync::Arc;
 use std::vec::Vec;
 use std::{fs, io, io::Write as iowritetrait, sync};
+use std::os::unix::fs::OpenOptionsExt;
 use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 use tokio_rustls::rustls::ServerConfig;
 use rand::{thread_rng, Rng};
@@ -32,6 +33,11 @@ use std::fmt::Write;
 use log_server::processor::*;
 use log_server::connection::*;
 use log_server::config::*;
+use log_server::filter;
+use log_server::export;
+use log_server::archive;
+use log_server::provenance;
+use sha2::{Sha256, Digest};
 
 
 
@@ -109,10 +115,28 @@ async fn run_server() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let raw_uid = unistd::Uid::current().as_raw();
     unistd::seteuid(unistd::Uid::from_raw(raw_uid)).expect("Error setting initial user id");
 
-    // Do not use client certificate authentication.
+    // Client certificates signed by client_ca_path can be used in place of API keys.
+    // Without require_client_cert, clients without one still log in with their key.
+    let client_auth = match CONFIG.get("client_ca_path") {
+      Some(ca_path) => {
+        if CONFIG.get("client_keys_path").is_none() {
+          return Err(error("client_ca_path needs client_keys_path to be set too".into()).into())
+        }
+        let mut roots = rustls::RootCertStore::empty();
+        for cert in load_certs(ca_path)? {
+          roots.add(&cert).map_err(|e| error(format!("bad certificate in {}: {}", ca_path, e)))?;
+        }
+        match CONFIG.get("require_client_cert").map(|x| x == "true").unwrap_or(false) {
+          true => rustls::server::AllowAnyAuthenticatedClient::new(roots),
+          false => rustls::server::AllowAnyAnonymousOrAuthenticatedClient::new(roots)
+        }
+      },
+      None => rustls::server::NoClientAuth::new()
+    };
+
     let mut cfg = rustls::ServerConfig::builder()
       .with_safe_defaults()
-      .with_no_client_auth()
+      .with_client_cert_verifier(client_auth)
       .with_single_cert(certs, key)
       .map_err(|e| error(format!("{}", e)))?;
     // Configure ALPN to accept HTTP/2, HTTP/1.1 in that order.
@@ -122,7 +146,10 @@ async fn run_server() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
 
   // Create a TCP listener via tokio.
   let incoming = AddrIncoming::bind(&addr)?;
-  let service = make_service_fn(|_| async { Ok::<_, io::Error>(service_fn(echo)) });
+  let service = make_service_fn(|conn: &TlsStream| {
+    let peer = conn.peer.clone();
+    async move { Ok::<_, io::Error>(service_fn(move |req| echo(req, peer.lock().unwrap().clone()))) }
+  });
   let server = Server::builder(TlsAcceptor::new(tls_cfg, incoming)).serve(service);
 
   // Run the future, keep going until an error occurs.
@@ -141,6 +168,7 @@ enum State {
 // TlsStream implements AsyncRead/AsyncWrite handshaking tokio_rustls::Accept first
 pub struct TlsStream {
   state: State,
+  peer: Arc<sync::Mutex<Option<String>>>, // common name of the client certificate, known once the handshake is done
 }
 
 impl TlsStream {
@@ -148,8 +176,23 @@ impl TlsStream {
     let accept = tokio_rustls::TlsAcceptor::from(config).accept(stream);
     TlsStream {
       state: State::Handshaking(accept),
+      peer: Arc::new(sync::Mutex::new(None)),
     }
   }
+
+  fn handshake_done(&self, stream: &tokio_rustls::server::TlsStream<AddrStream>) {
+    let name = stream.get_ref().1.peer_certificates()
+      .and_then(|certs| certs.first())
+      .and_then(|cert| common_name(&cert.0));
+    *self.peer.lock().unwrap() = name;
+  }
+}
+
+// CN of a certificate, which is the username for requests made with a client certificate
+fn common_name(der: &[u8]) -> Option<String> {
+  let (_, cert) = x509_parser::parse_x509_certificate(der).ok()?;
+  let name = cert.subject().iter_common_name().next()?.as_str().ok()?.to_string();
+  Some(name)
 }
 
 impl AsyncRead for TlsStream {
@@ -162,6 +205,7 @@ impl AsyncRead for TlsStream {
     match pin.state {
       State::Handshaking(ref mut accept) => match ready!(Pin::new(accept).poll(cx)) {
         Ok(mut stream) => {
+          pin.handshake_done(&stream);
           let result = Pin::new(&mut stream).poll_read(cx, buf);
           pin.state = State::Streaming(stream);
           result
@@ -183,6 +227,7 @@ impl AsyncWrite for TlsStream {
     match pin.state {
       State::Handshaking(ref mut accept) => match ready!(Pin::new(accept).poll(cx)) {
         Ok(mut stream) => {
+          pin.handshake_done(&stream);
           let result = Pin::new(&mut stream).poll_write(cx, buf);
           pin.state = State::Streaming(stream);
           result
@@ -263,12 +308,36 @@ async fn get_db_conn(username: &str, password: &str, database: &str) -> std::res
 
 }
 
-async fn echo(req: Request<Body>) -> Result<Response<Body>,  hyper::Error> {
+async fn echo(req: Request<Body>, peer: Option<String>) -> Result<Response<Body>,  hyper::Error> {
   
   let mut response = Response::new(Body::empty());
 
   // getting connection info from request headers
   let mut conn = Connection::get_conn_info(&req);
+
+  // a header that couldn't be read is the client's mistake, not a reason to take the server down
+  if let Some(err) = &conn.err {
+    println!("Bad request: {}", err);
+    *response.status_mut() = StatusCode::BAD_REQUEST;
+    *response.body_mut() = Body::from(err.to_string());
+    return Ok(response)
+  }
+
+  // a client certificate from client_ca_path says who this is when there is no API key (or
+  // administrator password) in the request. They log into the database as their own user, with
+  // the key the server was given for them by `log key add`. A name nobody added, or that was
+  // revoked since, gets nowhere.
+  let mut cert_error = None;
+  if let (Some(name), true) = (peer, conn.password.is_empty()) {
+    match client_keys().remove(&name) {
+      Some(key) => {
+        conn.username = name;
+        conn.password = key;
+        conn.client_cert = true;
+      },
+      None => cert_error = Some(format!("No user is registered for the client certificate of {}. An administrator has to add them with 'log key add {}'", name, name))
+    }
+  }
   
   let mut error = String::new();
 
@@ -279,6 +348,11 @@ async fn echo(req: Request<Body>) -> Result<Response<Body>,  hyper::Error> {
   // normal API for everything else
   if let Result::Err(err) = match (req.method(), uri_path.as_str()) {
 
+    // certificate nobody was registered for
+    _ if cert_error.is_some() => {
+      Err(set_response_error(cert_error.as_ref().unwrap()))
+    },
+
     // home page in browser
     (&Method::GET, "") => {
       home_page(&mut response).await 
@@ -317,6 +391,46 @@ async fn echo(req: Request<Body>) -> Result<Response<Body>,  hyper::Error> {
       get_latest(&mut response, &mut conn).await
     },
 
+    // downloads the archive of one specific upload
+    (&Method::POST, "checkout") => {
+      checkout(&mut response, &mut conn).await
+    },
+
+    // a single file out of an upload, for files stored by reference
+    (&Method::POST, "file") => {
+      fetch_file(&mut response, &mut conn).await
+    },
+
+    // collections, the uploads in a collection or the files in an upload as json, for `log ls`
+    (&Method::POST, "list") => {
+      list(&mut response, &mut conn).await
+    },
+
+    // chain of parents for an upload, newest first
+    (&Method::POST, "history") => {
+      history(&mut response, &mut conn).await
+    },
+
+    // hashes of everything the server has for one upload, for `log verify` on a directory
+    (&Method::POST, "integrity") => {
+      integrity(&mut response, &mut conn).await
+    },
+
+    // same chain with REV files and signatures so the client can check them
+    (&Method::POST, "verify") => {
+      verify(&mut response, &mut conn).await
+    },
+
+    // structured searches over watch variables from `log query`
+    (&Method::POST, "search") => {
+      search(&mut response, &mut conn, req).await
+    },
+
+    // streams watch values and thermo data as json lines for `log export`
+    (&Method::POST, "export") => {
+      export_data(&mut response, &mut conn, req).await
+    },
+
     // method for cleaning up files left on disk but deleted from database
     (&Method::POST, "cleanup") => {
       cleanup(&mut response, &mut conn).await
@@ -327,6 +441,46 @@ async fn echo(req: Request<Body>) -> Result<Response<Body>,  hyper::Error> {
       register(&mut response, &mut conn).await
     },
 
+    // user for a client certificate, whose key the server keeps. Needs the administrator password
+    (&Method::POST, "add_user") => {
+      add_cert_user(&mut conn).await
+    },
+
+    // new key for a user, their own or any with the administrator password
+    (&Method::POST, "rotate") => {
+      rotate(&mut response, &mut conn).await
+    },
+
+    // makes the caller's key stop working
+    (&Method::POST, "logout") => {
+      logout(&mut conn).await
+    },
+
+    // registered users, needs the administrator password
+    (&Method::POST, "users") => {
+      list_users(&mut response, &mut conn).await
+    },
+
+    // deletes a user, needs the administrator password
+    (&Method::POST, "revoke") => {
+      revoke(&mut conn).await
+    },
+
+    // another signing key for the caller, signed with one they have
+    (&Method::POST, "add_signing_key") => {
+      add_signing_key(&mut response, &mut conn, req).await
+    },
+
+    // makes a key the only signing key of a user, needs the administrator password
+    (&Method::POST, "replace_signing_key") => {
+      replace_signing_key(&mut conn).await
+    },
+
+    // removes one or all signing keys of a user, needs the administrator password
+    (&Method::POST, "remove_signing_key") => {
+      remove_signing_key(&mut response, &mut conn).await
+    },
+
     // Catch-all 404.
     _ => {
       Err(set_response_error("Bruh, there's no page here."))
@@ -358,6 +512,7 @@ async fn echo(req: Request<Body>) -> Result<Response<Body>,  hyper::Error> {
 enum Webpage {
   Home,
   Query,
+  Uploads, // the query page of a collection, with a filter for who uploaded
   ErrorPage,
   LoginRedirect,
 }
@@ -376,7 +531,8 @@ async fn return_file(response: &mut hyper::Response<Body>, req: Request<Body>) -
 
 }
 
-fn build_html(page: Webpage, list: Option<Vec<String>>, pagename: Option<&str>) -> Result<Buffer, Box<dyn std::error::Error>> {
+// list is made up of the link and the text shown for it
+fn build_html(page: Webpage, list: Option<Vec<(String, String)>>, pagename: Option<&str>) -> Result<Buffer, Box<dyn std::error::Error>> {
 
   let mut buf = Buffer::new();
   writeln!(buf, "<!-- My website -->").unwrap();
@@ -408,11 +564,17 @@ fn build_html(page: Webpage, list: Option<Vec<String>>, pagename: Option<&str>)
     form.input().attr("type='submit' value='Enter'");
   }
 
-  if page == Webpage::Query {
+  if page == Webpage::Uploads {
+    let mut form = body.form().attr("method='get'");
+    form.input().attr("type='text' id='user' name='user' placeholder='uploaded by'");
+    form.input().attr("type='submit' value='Filter'");
+  }
+
+  if page == Webpage::Query || page == Webpage::Uploads {
 
     let mut htmllist = body.ul();
 
-    for collection_name in list.unwrap() {
+    for (collection_name, text) in list.unwrap() {
       let mut file_string = String::from(pagename.unwrap());
       file_string.push_str("/");
       file_string.push_str(&collection_name);
@@ -421,7 +583,7 @@ fn build_html(page: Webpage, list: Option<Vec<String>>, pagename: Option<&str>)
         htmllist.li().a().attr(
             &format!("href='{}'", file_string)
         ),
-        "{}", collection_name,
+        "{}", text,
       ).unwrap()
     }
     
@@ -515,7 +677,7 @@ async fn download(response: &mut hyper::Response<Body>, conn: &mut Connection, r
   let download_id = uri_path[2];
   let coll = download_id.split_once(":").unwrap().0;
 
-  let mut cursor = Connection::simple_db_query(&client, Some("id"), Some(download_id), CONFIG.get("database").unwrap(), coll, Some(doc! {"upload_path": 1, "upload_name": 1}), None).await;
+  let mut cursor = Connection::simple_db_query(&client, Some("id"), Some(download_id), CONFIG.get("database").unwrap(), coll, Some(doc! {"upload_path": 1, "upload_name": 1, "codec": 1}), None).await;
   let res = cursor.next().await.unwrap().unwrap();
 
   let mut file = fs::File::open(res.get_str("upload_path").unwrap())?;
@@ -525,7 +687,10 @@ async fn download(response: &mut hyper::Response<Body>, conn: &mut Connection, r
   let headers = response.headers_mut();
   let mut disp_string = String::from("attachment; filename=\"");
   disp_string.push_str(res.get_str("upload_name").unwrap());
-  disp_string.push_str(".tar.gz\"");
+  // uploads from before the codec was stored are all gzip
+  let codec = archive::Codec::from_name(res.get_str("codec").unwrap_or("")).unwrap_or(archive::Codec::Gzip);
+  disp_string.push_str(codec.extension());
+  disp_string.push('"');
   headers.insert(hyper::header::CONTENT_DISPOSITION, hyper::header::HeaderValue::from_str(&disp_string).unwrap());
 
   *response.body_mut() = Body::from(data);
@@ -545,20 +710,39 @@ async fn query(response: &mut hyper::Response<Body>, conn: &mut Connection, req:
   // lengths of 2 and 3 can be handled by the generic html builder. 
   // Any more than that uses a specific html code
 
-  let res = match uri_path.len() {
+  let (page, res) = match uri_path.len() {
 
     // root query endpoint, show collections
     2 => {
       let db = client.database(CONFIG.get("database").ok_or("DB not found")?);
-      Some(db.list_collection_names(None).await.unwrap())
+      let res = db.list_collection_names(None).await.unwrap().into_iter().map(|x| (x.to_string(), x)).collect();
+      (Webpage::Query, Some(res))
     },
 
-    // Have picked a collection, now show uploads within collection
+    // Have picked a collection, now show uploads within collection.
+    // ?user=<name> only shows what that user uploaded
     3 => {
 
-      let cursor = Connection::simple_db_query(&client, None, None, CONFIG.get("database").unwrap(), uri_path[2], Some(doc! { "upload_name": 1 }), None).await;
-      let res: Vec<String> = cursor.map(|x| x.unwrap().get_str("upload_name").unwrap().to_string()).collect().await;
-      Some(res)
+      let user = req.uri().query().unwrap_or("").split('&').find_map(|x| x.strip_prefix("user=")).map(|x| x.replace("%20", " ")).filter(|x| !x.is_empty());
+      let cursor = match &user {
+        Some(user) => Connection::simple_db_query(&client, Some("uploaded_by"), Some(user), CONFIG.get("database").unwrap(), uri_path[2], Some(doc! { "upload_name": 1, "uploaded_by": 1, "branch_label": 1 }), None).await,
+        None => Connection::simple_db_query(&client, None, None, CONFIG.get("database").unwrap(), uri_path[2], Some(doc! { "upload_name": 1, "uploaded_by": 1, "branch_label": 1 }), None).await
+      };
+      let res: Vec<(String, String)> = cursor.map(|x| {
+        let x = x.unwrap();
+        let name = x.get_str("upload_name").unwrap().to_string();
+        let mut text = match x.get_str("uploaded_by") {
+          Ok(by) => format!("{} (uploaded by {})", name, by),
+          Err(_) => name.to_string()
+        };
+
+        // forks stand out from the main line
+        if let Some(label) = x.get_str("branch_label").ok().filter(|x| *x != "main") {
+          text.push_str(&format!(" [branch {}]", label));
+        }
+        (name, text)
+      }).collect().await;
+      (Webpage::Uploads, Some(res))
 
     },
 
@@ -577,6 +761,23 @@ async fn query(response: &mut hyper::Response<Body>, conn: &mut Connection, req:
 
         let name = name.replace("%20", " "); // replaces the %20 with an actual space character
 
+        // big files are not in the database, so they are read out of the stored archive instead
+        if sub_doc.get(&name).map(archive::is_reference).unwrap_or(false) {
+
+          let archive_path = sub_doc.get_document(&name)?.get_str("archive_path")?;
+          let contents = archive::read_file(res.get_str("upload_path")?, archive_path)?;
+
+          let content_type = match (name.contains(".pdf"), std::str::from_utf8(&contents).is_ok()) {
+            (true, _) => "application/pdf; charset=binary",
+            (false, true) => "text/plain; charset=UTF-8",
+            (false, false) => "application/octet-stream; charset=binary"
+          };
+          response.headers_mut().insert(hyper::header::CONTENT_TYPE, hyper::header::HeaderValue::from_str(content_type).unwrap());
+          *response.body_mut() = Body::from(contents);
+          return Ok(())
+
+        }
+
         if let Ok(new_doc) = sub_doc.get_document(name) {
 
           sub_doc = new_doc;
@@ -704,6 +905,16 @@ async fn query(response: &mut hyper::Response<Body>, conn: &mut Connection, req:
 
         let val = sub_doc.get(&k).unwrap();
 
+        // files stored by reference look like documents but should link to the file itself
+        if archive::is_reference(val) {
+          let url_post_string = format!("{}/{}", uri_path.last().unwrap(), k);
+          writeln!(
+            htmllist.li().a().attr(&format!("href='{}'", url_post_string)),
+            "{} ({} bytes, fetched from archive)", k, val.as_document().unwrap().get_i64("size").unwrap_or(0)
+          ).unwrap();
+          continue;
+        }
+
         // if is a Document then we need to list sub docs as list 
         // don't like that a lot of code is copy/pasted between the 2 blocks.
         if val.element_type() == bson::spec::ElementType::EmbeddedDocument {
@@ -813,7 +1024,7 @@ async fn query(response: &mut hyper::Response<Body>, conn: &mut Connection, req:
     
   };
 
-  let buf = build_html(Webpage::Query, res, Some(uri_path.last().unwrap())).unwrap();
+  let buf = build_html(page, res, Some(uri_path.last().unwrap())).unwrap();
   *response.body_mut() = Body::from(buf.finish());
 
   Ok(())
@@ -823,6 +1034,17 @@ async fn upload(response: &mut hyper::Response<Body>, conn: &mut Connection, req
   // Connecting to database 
   let client = get_db_conn(&conn.username, &conn.password, CONFIG.get("database").unwrap()).await?;
 
+  // metadata is stored as its own document so it has to be a json object
+  if !conn.metadata.is_empty() && !serde_json::from_str::<serde_json::Value>(&conn.metadata).map(|x| x.is_object()).unwrap_or(false) {
+    return Err(set_response_error("Upload metadata must be a json object"))
+  }
+
+  // the command is run again by `log reproduce` without a shell, so it is kept as the list of arguments
+  let is_command = |x: serde_json::Value| x.as_array().map(|x| !x.is_empty() && x.iter().all(|x| x.is_string())).unwrap_or(false);
+  if !conn.command.is_empty() && !serde_json::from_str::<serde_json::Value>(&conn.command).map(is_command).unwrap_or(false) {
+    return Err(set_response_error("Upload command must be a json list of strings"))
+  }
+
   // checking if file already exists in database
   let num_entries = Connection::simple_db_query(&client, Some("id"), Some(&conn.filehash), CONFIG.get("database").unwrap(), &conn.collection, None, None).await.count().await;
   if num_entries > 0 {
@@ -832,6 +1054,58 @@ async fn upload(response: &mut hyper::Response<Body>, conn: &mut Connection, req
   // Await the full body to be concatenated into a single `Bytes`...
   let full_body = hyper::body::to_bytes(req.into_body()).await.unwrap();
 
+  let codec = match archive::Codec::from_name(&conn.codec) {
+    Some(c) => c,
+    None => return Err(set_response_error(&format!("Unknown codec {}. This server reads gzip, zstd and none", conn.codec)))
+  };
+  if archive::Codec::detect(&full_body) != codec {
+    return Err(set_response_error(&format!("Upload is not a {} archive", codec.name())))
+  }
+  conn.codec = codec.name().to_string();
+
+  let rev = archive::read_entry(full_body.as_ref(), "REV")?;
+
+  // signatures are checked before anything is stored
+  let provenance_doc = match conn.signature.is_empty() {
+    false => {
+      let archive_hash = hex::encode(Sha256::digest(&full_body));
+      let fingerprint = provenance::verify(&conn.public_key, &conn.signature, &provenance::signed_message(&rev, &archive_hash)).map_err(|e| set_response_error(&e))?;
+      provenance::check_key(&client, CONFIG.get("database").unwrap(), &conn.username, &conn.public_key, &fingerprint).await?;
+
+      Some(doc! {
+        "signed_by": &conn.username,
+        "signature": &conn.signature,
+        "public_key": &conn.public_key,
+        "key_fingerprint": fingerprint,
+        "archive_hash": archive_hash
+      })
+    },
+    true if CONFIG.get("require_signatures").map(|x| x == "true").unwrap_or(false) => return Err(set_response_error("This server only accepts signed uploads")),
+    true => None
+  };
+
+  // a parent with a child already means two machines uploaded from the same REV
+  let parent_id = String::from_utf8_lossy(&rev).lines()
+    .filter_map(|x| x.split_once(':'))
+    .find(|(k, _)| k.trim() == "parent_id")
+    .map(|(_, v)| v.trim().to_string())
+    .unwrap_or("*".to_string());
+  // a fork the client meant to make, like the runs of a sweep, is let through by everything but reject_all
+  let policy = fork_policy(&conn.collection);
+  let (branch_label, siblings) = next_branch_label(&client, CONFIG.get("database").unwrap(), &conn.collection, &parent_id).await?;
+  if !siblings.is_empty() {
+    let message = format!("{} already has a child ({}), so this upload starts branch {}", parent_id, siblings.join(", "), branch_label);
+    match policy.as_str() {
+      "allow" => {},
+      "reject_all" => return Err(set_response_error(&format!("{}. {} does not take forks, update to the newest upload first", message, conn.collection))),
+      _ if conn.fork == "intended" => {},
+      "reject" => return Err(set_response_error(&format!("{}. {} does not take forks, update to the newest upload first", message, conn.collection))),
+      _ => {
+        response.headers_mut().insert("fork_warning", hyper::header::HeaderValue::from_str(&message)?);
+      }
+    }
+  }
+
   
   // Starting thread here to return response immediately to user
   // tokio::spawn(async move {
@@ -842,7 +1116,7 @@ async fn upload(response: &mut hyper::Response<Body>, conn: &mut Connection, req
   } else {
     new_file_path.push_str(&conn.filehash);
   }
-  new_file_path.push_str(".tar.gz");
+  new_file_path.push_str(codec.extension());
 
   // don't want to overwrite files
   // if same name, append datetime
@@ -855,19 +1129,42 @@ async fn upload(response: &mut hyper::Response<Body>, conn: &mut Connection, req
     new_file_path.clear();
     new_file_path.push_str(CONFIG.get("data_path").unwrap());
     new_file_path.push_str(&conn.filename);
-    new_file_path.push_str(".tar.gz");
+    new_file_path.push_str(codec.extension());
   }
 
   let mut outputfile = fs::File::create(&new_file_path).expect("File creation failed");
   outputfile.write_all(&full_body).expect("File write failed");
   outputfile.flush()?;
   
+  create_collection(&client, CONFIG.get("database").unwrap(), &conn.collection).await?;
+
   // Leaving server code to process data into database
-  let processor = Processor::new(new_file_path, conn.clone(), client);
-  processor.process_data().await.unwrap();
+  let mut processor = Processor::new(new_file_path.clone(), conn.clone(), client);
+  if let Some(provenance_doc) = provenance_doc {
+    processor.set_provenance(provenance_doc);
+  }
+  processor.set_branch_label(&branch_label);
+  processor.set_reject_forks(policy == "reject_all" || (policy == "reject" && conn.fork != "intended"));
+  if let Err(err) = processor.process_data().await {
+    fs::remove_file(&new_file_path)?;
+    return match is_duplicate_key(&err) {
+      true => Err(set_response_error(&format!("{} got another child while this was uploading. {} does not take forks, update to the newest upload first", parent_id, conn.collection))),
+      false => Err(set_response_error(&format!("Could not store upload: {}", err)))
+    }
+  }
+
+  // another upload of the same parent got in first, so this one became a fork after all
+  if processor.branch_label() != branch_label && policy != "allow" && conn.fork != "intended" {
+    let message = format!("{} got another child while this was uploading, so this upload starts branch {}", parent_id, processor.branch_label());
+    response.headers_mut().insert("fork_warning", hyper::header::HeaderValue::from_str(&message)?);
+  }
 
   // });
   
+  // name can change above, so the client needs to know what it ended up as
+  response.headers_mut().insert("upload_name", hyper::header::HeaderValue::from_str(&conn.filename)?);
+  response.headers_mut().insert("branch_label", hyper::header::HeaderValue::from_str(processor.branch_label())?);
+
   let mut body_response = String::from("New file created: ");
   body_response.push_str(&conn.filename);
   *response.body_mut() = Body::from(body_response);
@@ -875,6 +1172,21 @@ async fn upload(response: &mut hyper::Response<Body>, conn: &mut Connection, req
   Ok(())
 }
 
+// fork_policy applies to every collection, followed by the collections that differ, like "warn, crack=reject".
+// Forks are warned about if it isn't set
+fn fork_policy(collection: &str) -> String {
+
+  let mut policy = "warn".to_string();
+  for entry in CONFIG.get("fork_policy").map(|x| x.as_str()).unwrap_or("").split(',').map(|x| x.trim()).filter(|x| !x.is_empty()) {
+    match entry.split_once('=') {
+      Some((c, p)) if c.trim() == collection => return p.trim().to_string(),
+      Some(_) => {},
+      None => policy = entry.to_string()
+    }
+  }
+  policy
+}
+
 async fn check(response: &mut hyper::Response<Body>, conn: &mut Connection) -> Result<(), Box<dyn std::error::Error>> {
   // Connecting to database 
   let client = get_db_conn(&conn.username, &conn.password, CONFIG.get("database").unwrap()).await?;
@@ -912,6 +1224,302 @@ async fn get_latest(response: &mut hyper::Response<Body>, conn: &mut Connection)
   Ok(())
 }
 
+async fn checkout(response: &mut hyper::Response<Body>, conn: &mut Connection) -> Result<(), Box<dyn std::error::Error>> {
+
+  // Connecting to database 
+  let client = get_db_conn(&conn.username, &conn.password, CONFIG.get("database").unwrap()).await?;
+
+  let coll = conn.filehash.split(':').next().unwrap(); // get collection name from id
+
+  let mut cursor = Connection::simple_db_query(&client, Some("id"), Some(&conn.filehash), CONFIG.get("database").unwrap(), coll, Some(doc! {"upload_path": 1, "upload_name": 1}), None).await;
+  let record = match cursor.try_next().await? {
+    Some(r) => r,
+    None => return Err(set_response_error(&format!("No upload with id {}", conn.filehash)))
+  };
+
+  let mut file = fs::File::open(record.get_str("upload_path")?)?;
+  let mut data: Vec<u8> = Vec::new();
+  file.read_to_end(&mut data)?;
+
+  response.headers_mut().insert("upload_name", hyper::header::HeaderValue::from_str(record.get_str("upload_name")?)?);
+  *response.body_mut() = Body::from(data);
+
+  Ok(())
+}
+
+async fn fetch_file(response: &mut hyper::Response<Body>, conn: &mut Connection) -> Result<(), Box<dyn std::error::Error>> {
+
+  // Connecting to database 
+  let client = get_db_conn(&conn.username, &conn.password, CONFIG.get("database").unwrap()).await?;
+
+  let coll = conn.filehash.split(':').next().unwrap(); // get collection name from id
+
+  let mut cursor = Connection::simple_db_query(&client, Some("id"), Some(&conn.filehash), CONFIG.get("database").unwrap(), coll, Some(doc! {"upload_path": 1}), None).await;
+  let record = match cursor.try_next().await? {
+    Some(r) => r,
+    None => return Err(set_response_error(&format!("No upload with id {}", conn.filehash)))
+  };
+
+  // works for any file in the upload, not just the ones stored by reference
+  let contents = archive::read_file(record.get_str("upload_path")?, &conn.path)?;
+  *response.body_mut() = Body::from(contents);
+
+  Ok(())
+}
+
+// JSON version of what the query pages show, for browsing from the terminal.
+// With neither a collection nor an id header it lists the collections, with a collection the
+// uploads in it, and with an id the files in that upload.
+async fn list(response: &mut hyper::Response<Body>, conn: &mut Connection) -> Result<(), Box<dyn std::error::Error>> {
+
+  // Connecting to database 
+  let client = get_db_conn(&conn.username, &conn.password, CONFIG.get("database").unwrap()).await?;
+  let database = CONFIG.get("database").unwrap();
+
+  let results: Vec<serde_json::Value> = if !conn.filehash.is_empty() {
+
+    let coll = conn.filehash.split(':').next().unwrap(); // get collection name from id
+
+    let mut cursor = Connection::simple_db_query(&client, Some("id"), Some(&conn.filehash), database, coll, Some(doc! {"upload_path": 1, "files": 1}), None).await;
+    let record = match cursor.try_next().await? {
+      Some(r) => r,
+      None => return Err(set_response_error(&format!("No upload with id {}", conn.filehash)))
+    };
+    let files = record.get_document("files").ok();
+
+    // everything in the archive, along with how the database holds it
+    archive::list_files(record.get_str("upload_path")?)?.into_iter().map(|(path, size)| {
+      let stored = match files.and_then(|x| x.get(&path)) {
+        Some(value) if archive::is_reference(value) => "reference",
+        Some(_) => "inline",
+        None => "archive"
+      };
+      serde_json::json!({"path": path, "size": size, "stored": stored})
+    }).collect()
+
+  } else if !conn.collection.is_empty() {
+
+    let cursor = Connection::simple_db_query(&client, None, None, database, &conn.collection, Some(doc! {"_id": 0, "id": 1, "parent_id": 1, "upload_name": 1, "upload_time": 1, "uploaded_by": 1, "branch_label": 1}), Some(doc! {"upload_time": 1})).await;
+    let records: Vec<Document> = cursor.try_collect().await?;
+
+    // uploads on the same branch share the first upload of their chain
+    let parents: std::collections::HashMap<&str, &str> = records.iter()
+      .filter_map(|x| Some((x.get_str("id").ok()?, x.get_str("parent_id").unwrap_or("*"))))
+      .collect();
+    let root = |id: &str| {
+      let mut curr = id;
+      let mut steps = 0;
+      while let Some(parent) = parents.get(curr).filter(|x| **x != "*" && parents.contains_key(**x)) {
+        curr = parent;
+        steps += 1;
+        if steps > parents.len() { break; } // a loop in the chain
+      }
+      curr.to_string()
+    };
+
+    // branches are worked out over everyone's uploads before filtering by user
+    records.iter().filter(|x| conn.uploaded_by.is_empty() || x.get_str("uploaded_by").map(|u| u == conn.uploaded_by).unwrap_or(false)).map(|x| {
+      let mut value = bson::Bson::Document(x.clone()).into_relaxed_extjson();
+      value["branch"] = serde_json::Value::from(root(x.get_str("id").unwrap_or("")));
+      value
+    }).collect()
+
+  } else {
+
+    let mut collections = client.database(database).list_collection_names(None).await?;
+    collections.retain(|x| x != provenance::SIGNING_KEYS);
+    collections.sort();
+    collections.into_iter().map(serde_json::Value::from).collect()
+
+  };
+
+  *response.body_mut() = Body::from(serde_json::to_string(&results)?);
+
+  Ok(())
+}
+
+// Follows parent ids from id until the root of the chain, or until a parent has been deleted
+async fn ancestry(client: &Client, id: &str, return_fields: Document) -> Result<Vec<Document>, Box<dyn std::error::Error>> {
+
+  let mut results: Vec<Document> = Vec::new();
+  let mut seen: Vec<String> = Vec::new();
+  let mut curr_id = id.to_owned();
+
+  while curr_id != "*" && !seen.contains(&curr_id) {
+
+    let coll = curr_id.split(':').next().unwrap().to_string();
+    let mut cursor = Connection::simple_db_query(client, Some("id"), Some(&curr_id), CONFIG.get("database").unwrap(), &coll, Some(return_fields.clone()), None).await;
+    let record = match cursor.try_next().await? {
+      Some(r) => r,
+      None if results.is_empty() => return Err(set_response_error(&format!("No upload with id {}", curr_id))),
+      None => break
+    };
+
+    seen.push(curr_id);
+    curr_id = record.get_str("parent_id").unwrap_or("*").to_string();
+    results.push(record);
+  }
+
+  Ok(results)
+}
+
+async fn integrity(response: &mut hyper::Response<Body>, conn: &mut Connection) -> Result<(), Box<dyn std::error::Error>> {
+
+  // Connecting to database 
+  let client = get_db_conn(&conn.username, &conn.password, CONFIG.get("database").unwrap()).await?;
+
+  let coll = conn.filehash.split(':').next().unwrap(); // get collection name from id
+
+  let mut cursor = Connection::simple_db_query(&client, Some("id"), Some(&conn.filehash), CONFIG.get("database").unwrap(), coll, Some(doc! {"upload_path": 1, "files": 1, "encrypted": 1}), None).await;
+  let record = match cursor.try_next().await? {
+    Some(r) => r,
+    None => return Err(set_response_error(&format!("No upload with id {}", conn.filehash)))
+  };
+
+  // what is in the stored archive, and what made it into the database
+  let mut archive_hashes = serde_json::Map::new();
+  for (name, hash) in archive::file_hashes(record.get_str("upload_path")?)? {
+    archive_hashes.insert(name, serde_json::Value::from(hash));
+  }
+
+  let mut file_hashes = serde_json::Map::new();
+  if let Ok(files) = record.get_document("files") {
+    for (name, value) in files {
+      if let Some(hash) = archive::stored_hash(value) {
+        file_hashes.insert(name.to_string(), serde_json::Value::from(hash));
+      }
+    }
+  }
+
+  let result = serde_json::json!({
+    "id": &conn.filehash,
+    "encrypted": record.get_bool("encrypted").unwrap_or(false),
+    "archive": archive_hashes,
+    "files": file_hashes
+  });
+  *response.body_mut() = Body::from(result.to_string());
+
+  Ok(())
+}
+
+async fn history(response: &mut hyper::Response<Body>, conn: &mut Connection) -> Result<(), Box<dyn std::error::Error>> {
+
+  // Connecting to database 
+  let client = get_db_conn(&conn.username, &conn.password, CONFIG.get("database").unwrap()).await?;
+
+  let records = ancestry(&client, &conn.filehash, doc! {"_id": 0, "id": 1, "parent_id": 1, "upload_name": 1, "upload_time": 1, "uploaded_by": 1, "branch_label": 1}).await?;
+  let results: Vec<serde_json::Value> = records.into_iter().map(|x| bson::Bson::Document(x).into_relaxed_extjson()).collect();
+
+  *response.body_mut() = Body::from(serde_json::to_string(&results)?);
+
+  Ok(())
+}
+
+async fn verify(response: &mut hyper::Response<Body>, conn: &mut Connection) -> Result<(), Box<dyn std::error::Error>> {
+
+  // Connecting to database 
+  let client = get_db_conn(&conn.username, &conn.password, CONFIG.get("database").unwrap()).await?;
+
+  let records = ancestry(&client, &conn.filehash, doc! {"_id": 0, "id": 1, "parent_id": 1, "upload_name": 1, "upload_time": 1, "files.REV": 1, "provenance": 1}).await?;
+
+  let mut results: Vec<serde_json::Value> = Vec::new();
+  for mut record in records {
+
+    // REV is all the client needs from the files
+    let rev = record.get_document("files").ok().and_then(|x| x.get_str("REV").ok()).unwrap_or("").to_string();
+    record.remove("files");
+    record.insert("rev", rev);
+
+    // the keys the signer had when this was uploaded, one of which it should be signed with
+    let signer = record.get_document("provenance").ok().and_then(|x| x.get_str("signed_by").ok()).map(|x| x.to_string());
+    if let Some(signer) = signer {
+      let keys = provenance::signing_keys(&client, CONFIG.get("database").unwrap(), &signer).await?.unwrap_or_default();
+      record.insert("registered_fingerprints", keys.fingerprints_at(record.get_datetime("upload_time").ok().copied()));
+    }
+
+    results.push(bson::Bson::Document(record).into_relaxed_extjson());
+  }
+
+  *response.body_mut() = Body::from(serde_json::to_string(&results)?);
+
+  Ok(())
+}
+
+async fn search(response: &mut hyper::Response<Body>, conn: &mut Connection, req: Request<Body>) -> Result<(), Box<dyn std::error::Error>> {
+
+  // Connecting to database 
+  let client = get_db_conn(&conn.username, &conn.password, CONFIG.get("database").unwrap()).await?;
+
+  if conn.collection.is_empty() {
+    return Err(set_response_error("No collection given to search in"))
+  }
+
+  // query string is sent in the body since it can contain pretty much anything
+  let request_body = hyper::body::to_bytes(req.into_body()).await?;
+  let query_filter = filter::parse(std::str::from_utf8(&request_body)?)?;
+
+  // always return enough to identify the upload, plus whatever fields were asked for
+  let mut return_fields = doc! {"_id": 0, "id": 1, "upload_name": 1, "upload_time": 1};
+  for f in conn.fields.split(',').map(|x| x.trim()).filter(|x| !x.is_empty()) {
+    return_fields.insert(filter::check_field(f)?, 1);
+  }
+
+  let cursor = Connection::filter_db_query(&client, Some(query_filter), CONFIG.get("database").unwrap(), &conn.collection, Some(return_fields), Some(doc! {"upload_time": 1})).await;
+  let results: Vec<Document> = cursor.try_collect().await?;
+
+  // relaxed extended json keeps numbers as plain json numbers which is much easier for the client
+  let results: Vec<serde_json::Value> = results.into_iter().map(|x| bson::Bson::Document(x).into_relaxed_extjson()).collect();
+  *response.body_mut() = Body::from(serde_json::to_string(&results)?);
+
+  Ok(())
+}
+
+async fn export_data(response: &mut hyper::Response<Body>, conn: &mut Connection, req: Request<Body>) -> Result<(), Box<dyn std::error::Error>> {
+
+  // Connecting to database 
+  let client = get_db_conn(&conn.username, &conn.password, CONFIG.get("database").unwrap()).await?;
+
+  if conn.collection.is_empty() {
+    return Err(set_response_error("No collection given to export"))
+  }
+
+  // can optionally narrow down the export with the same filters used by `log query`
+  let request_body = hyper::body::to_bytes(req.into_body()).await?;
+  let query_filter = filter::parse(std::str::from_utf8(&request_body)?)?;
+
+  let mut fields: Vec<String> = Vec::new();
+  for f in conn.fields.split(',').map(|x| x.trim()).filter(|x| !x.is_empty()) {
+    fields.push(filter::check_field(f)?);
+  }
+
+  let mut return_fields = doc! {"_id": 0, "id": 1, "upload_name": 1, "upload_time": 1};
+  if fields.is_empty() {
+    return_fields.insert("watch", 1);
+  }
+  for f in &fields {
+    return_fields.insert(f, 1);
+  }
+
+  let cursor = Connection::filter_db_query(&client, Some(query_filter), CONFIG.get("database").unwrap(), &conn.collection, Some(return_fields), Some(doc! {"upload_time": 1})).await;
+
+  // each upload is turned into its rows as it comes out of the cursor, so the whole
+  // collection never has to sit in memory at once
+  let row_stream = cursor.map(move |doc| {
+    doc.map(|d| {
+      let mut lines = String::new();
+      for row in export::rows(&d, &fields) {
+        lines.push_str(&serde_json::Value::Object(row).to_string());
+        lines.push('\n');
+      }
+      lines
+    })
+  });
+
+  *response.body_mut() = Body::wrap_stream(row_stream);
+
+  Ok(())
+}
+
 async fn cleanup(response: &mut hyper::Response<Body>, conn: &mut Connection) -> Result<(), Box<dyn std::error::Error>> {
 
   // Connecting to database      
@@ -955,27 +1563,277 @@ async fn register(response: &mut hyper::Response<Body>, conn: &mut Connection) -
   let client =  get_db_conn("admin", &conn.password, "admin").await?;
 
   // Creating new password for user
-  let new_key: String = thread_rng()
-    .sample_iter(&Alphanumeric)
-    .take(64)
-    .map(char::from)
-    .collect();
+  let new_key = random_key();
   
   // Adding password to response here, so it will be returned even if cannot make new user. But the password won't be valid
   // if the user is not added
   response.headers_mut().insert("key", hyper::header::HeaderValue::from_str(&new_key).unwrap());
 
+  create_key_role(&client).await?;
+
   client.database(CONFIG.get("database").unwrap())
   .run_command(doc! {
     "createUser": &conn.username,
     "pwd": new_key,
-    "roles": [{"role": "readWrite", "db": CONFIG.get("database").unwrap()}]
+    "roles": [{"role": "readWrite", "db": CONFIG.get("database").unwrap()}, {"role": KEY_ROLE, "db": CONFIG.get("database").unwrap()}]
   }, None)
   .await?;
 
   Ok(())
 }
 
+// Adds a user that signs in with a client certificate, named after its common name. The key is
+// written to client_keys_path instead of being sent back, so only the server can use it.
+async fn add_cert_user(conn: &mut Connection) -> Result<(), Box<dyn std::error::Error>> {
+
+  if CONFIG.get("client_keys_path").is_none() {
+    return Err(set_response_error("This server does not take client certificates"))
+  }
+  if conn.target.is_empty() {
+    return Err(set_response_error("No user given to add"))
+  }
+
+  let client = get_db_conn("admin", &conn.password, "admin").await?;
+  create_key_role(&client).await?;
+
+  let new_key = random_key();
+  client.database(CONFIG.get("database").unwrap())
+  .run_command(doc! {
+    "createUser": &conn.target,
+    "pwd": &new_key,
+    "roles": [{"role": "readWrite", "db": CONFIG.get("database").unwrap()}, {"role": KEY_ROLE, "db": CONFIG.get("database").unwrap()}]
+  }, None)
+  .await?;
+  set_client_key(&conn.target, Some(&new_key))?;
+
+  println!("Added certificate user {}", conn.target);
+  Ok(())
+}
+
+// Keys of the certificate users, from `name : key` lines in client_keys_path
+fn client_keys() -> std::collections::HashMap<String, String> {
+  let contents = CONFIG.get("client_keys_path").and_then(|x| fs::read_to_string(x).ok()).unwrap_or_default();
+  contents.lines()
+    .filter_map(|x| x.split_once(" : "))
+    .map(|(name, key)| (name.trim().to_string(), key.trim().to_string()))
+    .collect()
+}
+
+// Changes or with None removes the key of a certificate user. The file is replaced in one go and only the server can read it
+fn set_client_key(name: &str, key: Option<&str>) -> io::Result<()> {
+  let path = CONFIG.get("client_keys_path").unwrap();
+  let mut keys = client_keys();
+  match key {
+    Some(key) => keys.insert(name.to_string(), key.to_string()),
+    None => keys.remove(name)
+  };
+
+  let mut contents = String::new();
+  for (name, key) in keys {
+    contents.push_str(&format!("{} : {}\n", name, key));
+  }
+  let tmp_path = format!("{}.tmp", path);
+  let mut file = fs::OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(&tmp_path)?;
+  file.write_all(contents.as_bytes())?;
+  file.sync_all()?;
+  fs::rename(&tmp_path, path)
+}
+
+fn random_key() -> String {
+  thread_rng()
+    .sample_iter(&Alphanumeric)
+    .take(64)
+    .map(char::from)
+    .collect()
+}
+
+// Users only get readWrite on the database, which does not let them change their own password.
+// This role does, so a key can be rotated or logged out with nothing but the key itself.
+const KEY_ROLE: &str = "log_change_own_key";
+
+async fn create_key_role(client: &Client) -> Result<(), Box<dyn std::error::Error>> {
+
+  let database = client.database(CONFIG.get("database").unwrap());
+
+  let existing = database.run_command(doc! {"rolesInfo": KEY_ROLE}, None).await?;
+  if !existing.get_array("roles").map(|x| x.is_empty()).unwrap_or(true) {
+    return Ok(())
+  }
+
+  database.run_command(doc! {
+    "createRole": KEY_ROLE,
+    "privileges": [{"resource": {"db": CONFIG.get("database").unwrap(), "collection": ""}, "actions": ["changeOwnPassword"]}],
+    "roles": []
+  }, None)
+  .await?;
+
+  Ok(())
+}
+
+// Replaces a user's key with a new random one. Without a target header users change their own
+// key, signed in with the key being replaced. With one the password is the administrator's and the
+// target's key is changed, which also works for users registered before they could change their own.
+// The new key is only sent back once the database has taken it, so a failed rotation leaves the old key working.
+async fn rotate(response: &mut hyper::Response<Body>, conn: &mut Connection) -> Result<(), Box<dyn std::error::Error>> {
+
+  let new_key = random_key();
+
+  if conn.target.is_empty() {
+    set_own_key(conn, &new_key).await?;
+    println!("Rotated the key of {}", conn.username);
+  } else {
+    let client = get_db_conn("admin", &conn.password, "admin").await?;
+    create_key_role(&client).await?;
+
+    let database = client.database(CONFIG.get("database").unwrap());
+    database.run_command(doc! {"updateUser": &conn.target, "pwd": &new_key}, None).await?;
+    database.run_command(doc! {
+      "grantRolesToUser": &conn.target,
+      "roles": [{"role": KEY_ROLE, "db": CONFIG.get("database").unwrap()}]
+    }, None)
+    .await?;
+    println!("Rotated the key of {} for the administrator", conn.target);
+
+    // a certificate user has nowhere to save it, so the server does
+    if client_keys().contains_key(&conn.target) {
+      set_client_key(&conn.target, Some(&new_key))?;
+      println!("Kept the new key of certificate user {}", conn.target);
+      return Ok(())
+    }
+  }
+
+  response.headers_mut().insert("key", hyper::header::HeaderValue::from_str(&new_key).unwrap());
+
+  Ok(())
+}
+
+// Throws away the caller's key by changing it to one nobody is told
+async fn logout(conn: &mut Connection) -> Result<(), Box<dyn std::error::Error>> {
+  set_own_key(conn, &random_key()).await?;
+  println!("Logged out {}", conn.username);
+  Ok(())
+}
+
+async fn set_own_key(conn: &Connection, new_key: &str) -> Result<(), Box<dyn std::error::Error>> {
+
+  // the key of a certificate user is the server's, they never see it
+  if conn.client_cert {
+    return Err(set_response_error("Signed in with a client certificate, there is no key to change"))
+  }
+
+  let client = get_db_conn(&conn.username, &conn.password, CONFIG.get("database").unwrap()).await?;
+  client.database(CONFIG.get("database").unwrap())
+    .run_command(doc! {"updateUser": &conn.username, "pwd": new_key}, None)
+    .await
+    .map_err(|e| format!("Could not change the key of {}: {}. Users registered before key rotation was added need an administrator to rotate their key once", conn.username, e))?;
+
+  Ok(())
+}
+
+// Users that can sign in to the database, with their roles and the key they sign uploads with
+async fn list_users(response: &mut hyper::Response<Body>, conn: &mut Connection) -> Result<(), Box<dyn std::error::Error>> {
+
+  let client = get_db_conn("admin", &conn.password, "admin").await?;
+  let database = CONFIG.get("database").unwrap();
+
+  let info = client.database(database).run_command(doc! {"usersInfo": 1}, None).await?;
+
+  let mut users: Vec<serde_json::Value> = Vec::new();
+  for user in info.get_array("users")?.iter().filter_map(|x| x.as_document()) {
+    let name = user.get_str("user")?;
+    let roles: Vec<&str> = user.get_array("roles").map(|x| x.iter().filter_map(|r| r.as_document()?.get_str("role").ok()).collect()).unwrap_or_default();
+    let signing_keys = provenance::signing_keys(&client, database, name).await?.unwrap_or_default();
+    users.push(serde_json::json!({
+      "user": name,
+      "roles": roles,
+      "signing_keys": signing_keys.fingerprints()
+    }));
+  }
+
+  *response.body_mut() = Body::from(serde_json::to_string(&users)?);
+
+  Ok(())
+}
+
+// Removes a user so their key stops working. What they uploaded stays. Their signing keys are
+// removed too, so someone added later under the same name doesn't inherit them, but they are
+// kept as removed so the signatures on what they uploaded can still be checked.
+async fn revoke(conn: &mut Connection) -> Result<(), Box<dyn std::error::Error>> {
+
+  if conn.target.is_empty() {
+    return Err(set_response_error("No user given to revoke"))
+  }
+
+  let client = get_db_conn("admin", &conn.password, "admin").await?;
+  client.database(CONFIG.get("database").unwrap())
+    .run_command(doc! {"dropUser": &conn.target}, None)
+    .await?;
+  if client_keys().contains_key(&conn.target) {
+    set_client_key(&conn.target, None)?;
+  }
+  provenance::remove_keys(&client, CONFIG.get("database").unwrap(), &conn.target, None).await?;
+
+  println!("Revoked {}", conn.target);
+  Ok(())
+}
+
+// Adds the hex encoded key in the body as a signing key of the caller. The claim is signed with
+// a key they already have, which has to be the new one itself only when they have none
+async fn add_signing_key(response: &mut hyper::Response<Body>, conn: &mut Connection, req: Request<Body>) -> Result<(), Box<dyn std::error::Error>> {
+
+  let client = get_db_conn(&conn.username, &conn.password, CONFIG.get("database").unwrap()).await?;
+  let new_key = String::from_utf8(hyper::body::to_bytes(req.into_body()).await?.to_vec())?;
+  let signer = provenance::verify(&conn.public_key, &conn.signature, &provenance::key_claim(&conn.username, new_key.trim())).map_err(|e| set_response_error(&e))?;
+
+  let fingerprint = provenance::add_key(&client, CONFIG.get("database").unwrap(), &conn.username, &signer, new_key.trim()).await?;
+  *response.body_mut() = Body::from(fingerprint);
+  Ok(())
+}
+
+// For a user who lost their key or had it stolen. Every key they have is removed and public_key
+// becomes their only one.
+async fn replace_signing_key(conn: &mut Connection) -> Result<(), Box<dyn std::error::Error>> {
+
+  if conn.target.is_empty() {
+    return Err(set_response_error("No user given to replace the signing key of"))
+  }
+  let fingerprint = provenance::key_fingerprint(&conn.public_key).map_err(|e| set_response_error(&e))?;
+
+  let client = get_db_conn("admin", &conn.password, "admin").await?;
+  let database = CONFIG.get("database").unwrap();
+  provenance::remove_keys(&client, database, &conn.target, None).await?;
+
+  let mut keys = provenance::signing_keys(&client, database, &conn.target).await?.unwrap_or_default();
+  keys.removed.retain(|x| x.get_str("fingerprint").map(|x| x != fingerprint).unwrap_or(true));
+  keys.keys.push(provenance::key_doc(&conn.public_key, &fingerprint));
+  provenance::save_keys(&client, database, &conn.target, &keys).await?;
+
+  println!("Replaced the signing keys of {} with {}", conn.target, fingerprint);
+  Ok(())
+}
+
+// Removes the key with the fingerprint header from a user, or every key they have without it.
+// Their next signed upload registers a key again if they have none left.
+async fn remove_signing_key(response: &mut hyper::Response<Body>, conn: &mut Connection) -> Result<(), Box<dyn std::error::Error>> {
+
+  if conn.target.is_empty() {
+    return Err(set_response_error("No user given to remove signing keys from"))
+  }
+
+  let client = get_db_conn("admin", &conn.password, "admin").await?;
+  let fingerprint = Some(conn.fingerprint.as_str()).filter(|x| !x.is_empty());
+  let removed = provenance::remove_keys(&client, CONFIG.get("database").unwrap(), &conn.target, fingerprint).await?;
+  if removed.is_empty() {
+    return Err(set_response_error(&match fingerprint {
+      Some(fingerprint) => format!("{} has no signing key {}", conn.target, fingerprint),
+      None => format!("{} has no signing keys", conn.target)
+    }))
+  }
+
+  *response.body_mut() = Body::from(serde_json::to_string(&removed)?);
+  Ok(())
+}
+
 // Load public certificate from file.
 fn load_certs(filename: &str) -> io::Result<Vec<rustls::Certificate>> {
   // Open certificate file.
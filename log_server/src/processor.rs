@@ -1,13 +1,15 @@
 
 use futures_util::{TryStreamExt};
-use mongodb::{bson::{Document, Bson, Array, doc}, Client};
+use mongodb::{bson::{Document, Bson, Array, doc}, Client, IndexModel};
+use mongodb::error::{ErrorKind, WriteFailure};
+use mongodb::options::IndexOptions;
 use std::{fs::File, io::Read, io, collections::HashMap};
-use flate2::read::GzDecoder;
 use tar::Archive;
 use similar::{TextDiff};
 
 use crate::connection::*;
 use crate::config::*;
+use crate::archive;
 use utils::utils;
 use chrono;
 
@@ -15,32 +17,68 @@ pub struct Processor {
   file_path: String,
   conn: Connection,
   db_client: Client,
-  config: HashMap<String, String>
+  config: HashMap<String, String>,
+  provenance: Option<Document>, // signature details, already checked by the time they get here
+  branch_label: String,
+  reject_forks: bool
 }
 
 
 impl Processor {
 
   pub fn new(file_path: String, conn: Connection, db_client: Client) -> Processor {
-    Processor { file_path, conn, db_client, config: Config::new().config}
+    Processor { file_path, conn, db_client, config: Config::new().config, provenance: None, branch_label: "main".to_string(), reject_forks: false }
+  }
+
+  pub fn set_provenance(&mut self, provenance: Document) {
+    self.provenance = Some(provenance);
+  }
+
+  pub fn set_branch_label(&mut self, branch_label: &str) {
+    self.branch_label = branch_label.to_string();
+  }
+
+  // label the upload ended up with, which changes if another child of the parent got there first
+  pub fn branch_label(&self) -> &str {
+    &self.branch_label
+  }
+
+  // a collection with fork_policy reject can't take a fork that slipped in while processing
+  pub fn set_reject_forks(&mut self, reject_forks: bool) {
+    self.reject_forks = reject_forks;
   }
 
   
 
-  fn decompress_data(&self, doc: &mut Document, watch_values: &mut Document) -> io::Result<()> {
+  // Returns true if the upload is encrypted, in which case only the INDEX_FILES are kept
+  fn decompress_data(&self, doc: &mut Document, watch_values: &mut Document) -> io::Result<bool> {
     
-    let mut unzipper = GzDecoder::new(File::open(&self.file_path)?);
+    let mut unzipper = archive::decoder(File::open(&self.file_path)?)?;
     let mut uncompressed: Vec<u8> = Vec::new();
     unzipper.read_to_end(&mut uncompressed)?;
 
     let mut archive = Archive::new(uncompressed.as_slice());
     let mut watch_schema: serde_json::Value = serde_json::from_str(r#"{}"#).unwrap();
+    let mut encrypted = false;
+    let mut client_watch_values: Option<String> = None;
 
     // first, let's get REV and watch files
     for file in archive.entries()? {
       let mut file = file?;
 
       let filename = file.path()?.into_owned().to_str().unwrap().to_string();
+
+      if filename == archive::ENCRYPTED_ARCHIVE {
+        encrypted = true;
+        continue;
+      }
+
+      if filename == archive::WATCH_VALUES_FILE {
+        let mut buf = String::new();
+        file.read_to_string(&mut buf)?;
+        client_watch_values = Some(buf);
+        continue;
+      }
       
       if filename.contains("REV") || filename.contains("watch") {
 
@@ -57,6 +95,21 @@ impl Processor {
     }
     
 
+    // the client read the watch values itself, the files never leave its machine unencrypted.
+    // Anything else an older client sent in plaintext is dropped without being read
+    if encrypted {
+      if let Some(values) = client_watch_values {
+        *watch_values = serde_json::from_str(&values).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("Bad watch values: {}", e)))?;
+      }
+
+      let plaintext: Vec<String> = doc.keys().filter(|x| *x != "REV").map(|x| x.to_string()).collect();
+      for f in plaintext {
+        doc.remove(f);
+      }
+      archive::keep_only(&self.file_path, &archive::INDEX_FILES.map(|x| x.to_string()))?;
+      return Ok(true)
+    }
+
     // need get files that REV and watch need
     let watch_needed_files: Vec<&String> = watch_schema.as_object().unwrap().keys().collect();
     let mut rev_needed_files: HashMap<String, String> = HashMap::new();
@@ -325,9 +378,26 @@ impl Processor {
       }
 
     }
-    
 
-    Ok(())
+    // big files are left in the archive so the document stays under Mongo's size limit
+    let (limit, total_limit) = self.inline_limits();
+    archive::apply_inline_limits(doc, limit, total_limit);
+
+    Ok(false)
+  }
+
+  // The client can ask for a lower limit than the server's, never a higher one
+  fn inline_limits(&self) -> (u64, u64) {
+
+    let server_limit = self.config.get("inline_limit").and_then(|x| x.parse::<u64>().ok()).unwrap_or(archive::DEFAULT_INLINE_LIMIT);
+    let total_limit = self.config.get("inline_total_limit").and_then(|x| x.parse::<u64>().ok()).unwrap_or(archive::DEFAULT_INLINE_TOTAL_LIMIT);
+
+    let limit = match self.conn.inline_limit.parse::<u64>() {
+      Ok(client_limit) => client_limit.min(server_limit),
+      Err(_) => server_limit
+    };
+
+    (limit, total_limit)
   }
 
   async fn get_file_diffs(&self, diffs:&mut Document, file_doc: &Document, rev_file_hash: HashMap<String, String>, db_name: &str) -> std::result::Result<(), mongodb::error::Error> {
@@ -377,7 +447,8 @@ impl Processor {
       for file in modified_files {
         
         // cannot do diff on binary files. This will break when you try and convert them to strings
-        let new_file = match file_doc.get(file).unwrap().as_str() {
+        // encrypted uploads only have REV, everything else is missing
+        let new_file = match file_doc.get(file).and_then(|x| x.as_str()) {
           Some(s) => s,
           None => {
             diffs.insert(file, "");
@@ -386,8 +457,15 @@ impl Processor {
         };
         
         // this gets the file from the database
+        // files stored by reference or as binary are skipped just like new binary files
         let old_file = match parent.get("files").unwrap().as_document().unwrap().get(file) {
-          Some(f) => f.as_str().unwrap(),
+          Some(f) => match f.as_str() {
+            Some(s) => s,
+            None => {
+              diffs.insert(file, "");
+              continue;
+            }
+          },
           None => ""
         };
 
@@ -405,7 +483,7 @@ impl Processor {
     Ok(())
   }
 
-  pub async fn process_data(&self) -> std::result::Result<(), mongodb::error::Error> {
+  pub async fn process_data(&mut self) -> std::result::Result<(), mongodb::error::Error> {
     
     let mut parent_doc = Document::new();
     let db_name = self.config.get("database").unwrap();
@@ -414,13 +492,35 @@ impl Processor {
     // inserting general upload metadata
     parent_doc.insert("upload_name", &self.conn.filename);
     parent_doc.insert("upload_path", &self.file_path);
+    parent_doc.insert("codec", &self.conn.codec);
     parent_doc.insert("upload_time", chrono::offset::Utc::now());
+
+    // who uploaded, from where and with which client. Older clients don't send the last two
+    parent_doc.insert("uploaded_by", &self.conn.username);
+    if !self.conn.hostname.is_empty() {
+      parent_doc.insert("upload_host", &self.conn.hostname);
+    }
+    if !self.conn.client_version.is_empty() {
+      parent_doc.insert("client_version", &self.conn.client_version);
+    }
+
+    // list of the program and its arguments, already checked in upload()
+    if !self.conn.command.is_empty() {
+      let command: serde_json::Value = serde_json::from_str(&self.conn.command).unwrap();
+      parent_doc.insert("command", bson::to_bson(&command).unwrap());
+    }
+
+    // extra information sent by the client, already checked to be a json object in upload()
+    if !self.conn.metadata.is_empty() {
+      let metadata: serde_json::Value = serde_json::from_str(&self.conn.metadata).unwrap();
+      parent_doc.insert("metadata", bson::to_bson(&metadata).unwrap());
+    }
     
     // Decompressing file and getting tracked and REV files
     let mut file_doc = Document::new();
     let mut watch_values = Document::new();
 
-    self.decompress_data(&mut file_doc, &mut watch_values).expect("Decompression failed");
+    let encrypted = self.decompress_data(&mut file_doc, &mut watch_values).expect("Decompression failed");
     let mut rev_file_hash = HashMap::new();
     utils::read_file_into_hash(file_doc.get("REV").unwrap().as_str().unwrap(), None, &mut rev_file_hash)?;
     parent_doc.insert("id", rev_file_hash.get("id").unwrap());
@@ -433,14 +533,94 @@ impl Processor {
     parent_doc.insert("watch", watch_values);
     parent_doc.insert("files", file_doc);
     parent_doc.insert("diffs", diffs);
-    
+    if encrypted {
+      parent_doc.insert("encrypted", true);
+    }
+    if let Some(provenance) = &self.provenance {
+      parent_doc.insert("provenance", provenance.clone());
+    }
     
     let db = self.db_client.database(db_name);
     let collection = db.collection::<Document>(coll_name);
-    collection.insert_one(parent_doc, None).await?;
 
-    Ok(())
+    // Two uploads of the same parent can both be given the same label before either is stored.
+    // The index from create_collection makes the second insert fail, and it is labelled again with the one that got in.
+    let parent_id = parent_doc.get_str("parent_id").unwrap_or("*").to_string();
+    loop {
+      parent_doc.insert("branch_label", &self.branch_label);
+      match collection.insert_one(&parent_doc, None).await {
+        Ok(_) => return Ok(()),
+        Err(err) if is_duplicate_key(&err) && !self.reject_forks => {
+          let (label, _) = next_branch_label(&self.db_client, db_name, coll_name, &parent_id).await?;
+          if label == self.branch_label {
+            return Err(err)
+          }
+          self.branch_label = label;
+        },
+        Err(err) => return Err(err)
+      }
+    }
     
   }
 
-}
\ No newline at end of file
+}
+
+pub fn is_duplicate_key(err: &mongodb::error::Error) -> bool {
+  matches!(&*err.kind, ErrorKind::Write(WriteFailure::WriteError(e)) if e.code == 11000)
+}
+
+// Makes the collection for its first upload, along with the unique index on parent_id and
+// branch_label that keeps two children of a parent from getting the same label. A collection that
+// already exists is left alone, and the index is in place before anything can be uploaded on top
+// of its first upload. Collections made before labels can hold duplicates already, so they don't get it
+pub async fn create_collection(client: &Client, database: &str, collection: &str) -> std::result::Result<(), mongodb::error::Error> {
+
+  let db = client.database(database);
+  match db.create_collection(collection, None).await {
+    Ok(_) => {},
+    Err(err) if matches!(&*err.kind, ErrorKind::Command(e) if e.code == 48) => return Ok(()), // NamespaceExists
+    Err(err) => return Err(err)
+  }
+
+  let index = IndexOptions::builder()
+    .name("parent_branch".to_string())
+    .unique(true)
+    .partial_filter_expression(doc! {"parent_id": {"$gt": "*"}, "branch_label": {"$exists": true}})
+    .build();
+  db.collection::<Document>(collection).create_index(IndexModel::builder().keys(doc! {"parent_id": 1, "branch_label": 1}).options(index).build(), None).await?;
+  println!("Created collection {}", collection);
+
+  Ok(())
+}
+
+// Label for a new child of parent_id, and the children it already has with who made them.
+// The first child of a parent stays on the parent's branch and every later child starts a new one
+// named after it, so a second child of an upload on main is main.1 and a fork of that is main.1.1.
+// Labels of deleted children are given out again.
+pub async fn next_branch_label(client: &Client, database: &str, collection: &str, parent_id: &str) -> std::result::Result<(String, Vec<String>), mongodb::error::Error> {
+
+  if parent_id == "*" {
+    return Ok(("main".to_string(), Vec::new()))
+  }
+
+  // uploads from before labels were stored are all on main
+  let mut cursor = Connection::simple_db_query(client, Some("id"), Some(parent_id), database, collection, Some(doc! {"branch_label": 1}), None).await;
+  let parent_label = match cursor.try_next().await? {
+    Some(parent) => parent.get_str("branch_label").unwrap_or("main").to_string(),
+    None => "main".to_string()
+  };
+
+  let cursor = Connection::simple_db_query(client, Some("parent_id"), Some(parent_id), database, collection, Some(doc! {"id": 1, "uploaded_by": 1, "branch_label": 1}), Some(doc! {"upload_time": 1})).await;
+  let children: Vec<Document> = cursor.try_collect().await?;
+  let taken: Vec<&str> = children.iter().filter_map(|x| x.get_str("branch_label").ok()).collect();
+  let label = (0..).map(|n| match n {
+    0 => parent_label.to_string(),
+    n => format!("{}.{}", parent_label, n)
+  }).find(|x| !taken.contains(&x.as_str())).unwrap();
+
+  let children = children.iter().map(|x| match x.get_str("uploaded_by") {
+    Ok(by) => format!("{} by {}", x.get_str("id").unwrap_or(""), by),
+    Err(_) => x.get_str("id").unwrap_or("").to_string()
+  }).collect();
+  Ok((label, children))
+}
@@ -0,0 +1,240 @@
+use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+use futures_util::TryStreamExt;
+use mongodb::{bson::{self, Document, doc}, options::{IndexOptions, ReplaceOptions}, Client, IndexModel};
+use sha2::{Sha256, Digest};
+
+use crate::connection::Connection;
+use crate::processor::is_duplicate_key;
+
+// Signed uploads. The client signs the hash of the REV file together with the hash of the
+// archive it sends, so a signature ties one exact upload to one key.
+// The first key a user signs with is stored in SIGNING_KEYS, after that only the keys they add are accepted.
+
+pub const SIGNING_KEYS: &str = "signing_keys";
+
+// has to match what the client signs
+pub fn signed_message(rev: &[u8], archive_hash: &str) -> Vec<u8> {
+  format!("{}\n{}", hex::encode(Sha256::digest(rev)), archive_hash).into_bytes()
+}
+
+pub fn fingerprint(public_key: &[u8]) -> String {
+  hex::encode(Sha256::digest(public_key))[..16].to_string()
+}
+
+fn public_key(public_key_hex: &str) -> Result<VerifyingKey, String> {
+  let public_key: [u8; 32] = hex::decode(public_key_hex).ok().and_then(|x| x.try_into().ok()).ok_or("Public key is not a hex encoded ed25519 key")?;
+  VerifyingKey::from_bytes(&public_key).map_err(|_| "Public key is not a valid ed25519 key".to_string())
+}
+
+// Fingerprint of a hex encoded key, if it is a valid one
+pub fn key_fingerprint(public_key_hex: &str) -> Result<String, String> {
+  Ok(fingerprint(public_key(public_key_hex)?.as_bytes()))
+}
+
+// Returns the fingerprint of the key if the signature is good
+pub fn verify(public_key_hex: &str, signature_hex: &str, message: &[u8]) -> Result<String, String> {
+
+  let key = public_key(public_key_hex)?;
+  let signature: [u8; 64] = hex::decode(signature_hex).ok().and_then(|x| x.try_into().ok()).ok_or("Signature is not a hex encoded ed25519 signature")?;
+  key.verify(message, &Signature::from_bytes(&signature)).map_err(|_| "Signature does not match the upload")?;
+
+  Ok(fingerprint(key.as_bytes()))
+}
+
+// The keys of a user are kept in one document, {username, keys, removed_keys}, each key being
+// {public_key, fingerprint, added} and a removed one also having when it was removed. Users
+// registered before there could be several keys have public_key and fingerprint on the document itself.
+#[derive(Default)]
+pub struct SigningKeys {
+  pub keys: Vec<Document>,
+  pub removed: Vec<Document>
+}
+
+impl SigningKeys {
+  pub fn fingerprints(&self) -> Vec<String> {
+    self.keys.iter().filter_map(|x| x.get_str("fingerprint").ok().map(|x| x.to_string())).collect()
+  }
+
+  fn has(keys: &[Document], fingerprint: &str) -> bool {
+    keys.iter().any(|x| x.get_str("fingerprint").map(|x| x == fingerprint).unwrap_or(false))
+  }
+
+  // Keys that were registered when something was uploaded at time, so a removed key still
+  // checks out for what was signed with it before it was removed
+  pub fn fingerprints_at(&self, time: Option<bson::DateTime>) -> Vec<String> {
+    let removed_after = self.removed.iter().filter(|x| match (time, x.get_datetime("removed")) {
+      (Some(time), Ok(removed)) => *removed > time,
+      _ => false
+    });
+    self.keys.iter().chain(removed_after).filter_map(|x| x.get_str("fingerprint").ok().map(|x| x.to_string())).collect()
+  }
+}
+
+pub fn key_doc(public_key_hex: &str, fingerprint: &str) -> Document {
+  doc! {
+    "public_key": public_key_hex,
+    "fingerprint": fingerprint,
+    "added": chrono::offset::Utc::now()
+  }
+}
+
+// has to match what the client signs to add a key
+pub fn key_claim(username: &str, public_key_hex: &str) -> Vec<u8> {
+  format!("signing key of {}\n{}", username, public_key_hex).into_bytes()
+}
+
+// Keys registered for username, from every document a user has in case two first uploads
+// raced each other before the collection had its unique index
+pub async fn signing_keys(client: &Client, db: &str, username: &str) -> mongodb::error::Result<Option<SigningKeys>> {
+
+  let mut cursor = Connection::simple_db_query(client, Some("username"), Some(username), db, SIGNING_KEYS, None, None).await;
+  let mut found: Option<SigningKeys> = None;
+
+  while let Some(user_doc) = cursor.try_next().await? {
+    let keys = found.get_or_insert_with(SigningKeys::default);
+    match user_doc.get_array("keys") {
+      Ok(list) => {
+        keys.keys.extend(list.iter().filter_map(|x| x.as_document().cloned()));
+        keys.removed.extend(user_doc.get_array("removed_keys").into_iter().flatten().filter_map(|x| x.as_document().cloned()));
+      },
+      Err(_) => {
+        let mut key = key_doc(user_doc.get_str("public_key").unwrap_or(""), user_doc.get_str("fingerprint").unwrap_or(""));
+        if let Ok(added) = user_doc.get_datetime("added") {
+          key.insert("added", added);
+        }
+        keys.keys.push(key);
+      }
+    }
+  }
+
+  Ok(found)
+}
+
+// Writes the keys of username back as a single document. Older documents of the user have no
+// keys field, and their keys were read into keys already
+pub async fn save_keys(client: &Client, db: &str, username: &str, keys: &SigningKeys) -> mongodb::error::Result<()> {
+
+  let collection = client.database(db).collection::<Document>(SIGNING_KEYS);
+  let user_doc = doc! {"username": username, "keys": keys.keys.clone(), "removed_keys": keys.removed.clone()};
+  collection.replace_one(doc! {"username": username}, user_doc, ReplaceOptions::builder().upsert(true).build()).await?;
+  collection.delete_many(doc! {"username": username, "keys": {"$exists": false}}, None).await?;
+
+  Ok(())
+}
+
+// One document per username, so two uploads racing to register a first key can't both win.
+// Made when a user registers their first key. Servers that already have two documents for a
+// user can't get it until one is removed, registering still works without it
+async fn create_username_index(client: &Client, db: &str) {
+  let index = IndexModel::builder()
+    .keys(doc! {"username": 1})
+    .options(IndexOptions::builder().name("username".to_string()).unique(true).build())
+    .build();
+  if let Err(err) = client.database(db).collection::<Document>(SIGNING_KEYS).create_index(index, None).await {
+    println!("Warning: could not make the unique username index on {}: {}", SIGNING_KEYS, err);
+  }
+}
+
+// Trust on first use. The first key seen for a user is registered, after that only keys they
+// added with `log key add-signing` are accepted. A user whose keys were all removed registers again.
+pub async fn check_key(client: &Client, db: &str, username: &str, public_key_hex: &str, fingerprint: &str) -> Result<(), Box<dyn std::error::Error>> {
+
+  match signing_keys(client, db, username).await? {
+    Some(keys) if SigningKeys::has(&keys.keys, fingerprint) => Ok(()),
+    Some(keys) if SigningKeys::has(&keys.removed, fingerprint) => Err(format!("Upload is signed with key {} which was removed from {}", fingerprint, username).into()),
+    Some(keys) if !keys.keys.is_empty() => Err(format!("Upload is signed with key {} but the keys registered for {} are {}. Add it with log key add-signing", fingerprint, username, keys.fingerprints().join(", ")).into()),
+    Some(mut keys) => {
+      println!("Registering signing key {} for {}", fingerprint, username);
+      keys.keys.push(key_doc(public_key_hex, fingerprint));
+      save_keys(client, db, username, &keys).await?;
+      Ok(())
+    },
+    None => {
+      create_username_index(client, db).await;
+      let user_doc = doc! {"username": username, "keys": [key_doc(public_key_hex, fingerprint)], "removed_keys": []};
+      match client.database(db).collection::<Document>(SIGNING_KEYS).insert_one(user_doc, None).await {
+        Ok(_) => {
+          println!("Registering signing key {} for {}", fingerprint, username);
+          Ok(())
+        },
+        // another upload registered a key first, this one has to match it
+        Err(err) if is_duplicate_key(&err) => match signing_keys(client, db, username).await? {
+          Some(keys) if SigningKeys::has(&keys.keys, fingerprint) => Ok(()),
+          _ => Err(format!("Upload is signed with key {} but another key was just registered for {}", fingerprint, username).into())
+        },
+        Err(err) => Err(err.into())
+      }
+    }
+  }
+}
+
+// Adds a key for a user. The claim has to be signed with one of their registered keys, or by the
+// new key itself for a user who has none yet, so an API key alone isn't enough to add one.
+// A key an administrator removed stays removed
+pub async fn add_key(client: &Client, db: &str, username: &str, signer_fingerprint: &str, public_key_hex: &str) -> Result<String, Box<dyn std::error::Error>> {
+
+  let fingerprint = key_fingerprint(public_key_hex)?;
+  let mut keys = signing_keys(client, db, username).await?.unwrap_or_default();
+
+  if SigningKeys::has(&keys.removed, &fingerprint) {
+    return Err(format!("Key {} was removed from {}, only an administrator can put it back", fingerprint, username).into())
+  }
+  let first_key = keys.keys.is_empty() && signer_fingerprint == fingerprint;
+  if !first_key && !SigningKeys::has(&keys.keys, signer_fingerprint) {
+    return Err(format!("{} is not a signing key of {}. Add keys from a machine with one of {}, or ask an administrator to replace them", signer_fingerprint, username, keys.fingerprints().join(", ")).into())
+  }
+  if SigningKeys::has(&keys.keys, &fingerprint) {
+    return Ok(fingerprint)
+  }
+
+  if keys.keys.is_empty() && keys.removed.is_empty() {
+    create_username_index(client, db).await;
+  }
+  keys.keys.push(key_doc(public_key_hex, &fingerprint));
+  save_keys(client, db, username, &keys).await?;
+
+  println!("Added signing key {} for {}", fingerprint, username);
+  Ok(fingerprint)
+}
+
+// Removes the key with fingerprint from username, or all of their keys without one. Removed
+// keys are kept with the time they were removed so older uploads can still be checked
+pub async fn remove_keys(client: &Client, db: &str, username: &str, fingerprint: Option<&str>) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+
+  let mut keys = match signing_keys(client, db, username).await? {
+    Some(keys) => keys,
+    None => return Ok(Vec::new())
+  };
+
+  let (removed, kept): (Vec<Document>, Vec<Document>) = keys.keys.into_iter().partition(|x| fingerprint.map(|f| x.get_str("fingerprint").map(|x| x == f).unwrap_or(false)).unwrap_or(true));
+  if removed.is_empty() {
+    return Ok(Vec::new())
+  }
+
+  let now = bson::DateTime::now();
+  let fingerprints: Vec<String> = removed.iter().filter_map(|x| x.get_str("fingerprint").ok().map(|x| x.to_string())).collect();
+  keys.keys = kept;
+  keys.removed.extend(removed.into_iter().map(|mut x| { x.insert("removed", now); x }));
+  save_keys(client, db, username, &keys).await?;
+
+  println!("Removed signing keys {} from {}", fingerprints.join(", "), username);
+  Ok(fingerprints)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn removed_keys_only_count_for_uploads_made_before() {
+    let removed_at = bson::DateTime::from_millis(2_000);
+    let mut old_key = key_doc("aa", "old");
+    old_key.insert("removed", removed_at);
+    let keys = SigningKeys { keys: vec![key_doc("bb", "new")], removed: vec![old_key] };
+
+    assert_eq!(keys.fingerprints_at(Some(bson::DateTime::from_millis(1_000))), vec!["new", "old"]);
+    assert_eq!(keys.fingerprints_at(Some(bson::DateTime::from_millis(3_000))), vec!["new"]);
+    assert_eq!(keys.fingerprints_at(None), vec!["new"]);
+    assert_eq!(keys.fingerprints(), vec!["new"]);
+  }
+}
@@ -0,0 +1,67 @@
+// Helpers shared by log and log_server
+
+pub mod utils {
+
+  use std::collections::HashMap;
+  use std::{fs, io, path};
+
+  // Reads "key : value" lines into hash. Config files, REV files and the credential files all use
+  // this format. filename can also be the contents themselves, which is how the server reads the
+  // REV it pulled out of an archive. With options, only those keys are kept
+  pub fn read_file_into_hash(filename: &str, options: Option<&[&str]>, hash: &mut HashMap<String, String>) -> io::Result<()> {
+
+    let contents = match path::Path::new(filename).is_file() {
+      true => fs::read_to_string(filename)?,
+      false if filename.contains('\n') => filename.to_string(),
+      false => return Err(io::Error::new(io::ErrorKind::NotFound, format!("{} does not exist", filename)))
+    };
+
+    for line in contents.lines() {
+
+      // values like "localhost:1241" have colons of their own, so " : " is looked for first
+      let (key, value) = match line.split_once(" : ").or_else(|| line.split_once(':')) {
+        Some(v) => (v.0.trim(), v.1.trim()),
+        None => continue
+      };
+
+      if key.is_empty() || options.map(|x| !x.contains(&key)).unwrap_or(false) {
+        continue;
+      }
+
+      hash.insert(key.to_string(), value.to_string());
+    }
+
+    Ok(())
+  }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+  use std::collections::HashMap;
+  use super::utils::read_file_into_hash;
+
+  #[test]
+  fn reads_files_and_contents() {
+    let path = std::env::temp_dir().join(format!("utils_test_{}", std::process::id()));
+    std::fs::write(&path, "Username : tayg\nServer : localhost:1241\n\nnot a setting\n").unwrap();
+
+    let mut hash = HashMap::new();
+    read_file_into_hash(path.to_str().unwrap(), None, &mut hash).unwrap();
+    assert_eq!(hash.len(), 2);
+    assert_eq!(hash["Server"], "localhost:1241");
+
+    let mut hash = HashMap::new();
+    read_file_into_hash("in.crack : 3f9a0c1b2d4e5f60\nid : 5d1c3e0a9b2f7a41\n", None, &mut hash).unwrap();
+    assert_eq!(hash["in.crack"], "3f9a0c1b2d4e5f60");
+
+    let mut hash = HashMap::new();
+    read_file_into_hash(path.to_str().unwrap(), Some(&["Username"]), &mut hash).unwrap();
+    assert_eq!(hash.keys().collect::<Vec<_>>(), vec!["Username"]);
+
+    std::fs::remove_file(&path).unwrap();
+    assert!(read_file_into_hash(path.to_str().unwrap(), None, &mut hash).is_err());
+  }
+
+}